@@ -0,0 +1,383 @@
+mod plugin;
+pub mod xcursor;
+
+pub(crate) use self::plugin::MouseCursorPlugin;
+
+use crate::gl_util::TextureRenderer;
+use flutter_engine::FlutterEngineWeakRef;
+use log::{debug, error, warn};
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+pub use self::xcursor::CursorTheme;
+
+/// A cursor image, stored as premultiplied ARGB pixels (as used by XCursor and DRM).
+#[derive(Clone, Debug)]
+pub struct CursorImage {
+    width: u32,
+    height: u32,
+    hotspot: (u32, u32),
+    pixels: Vec<u32>,
+}
+
+impl CursorImage {
+    /// Creates an image from row-major premultiplied ARGB pixels. Returns `None` unless `pixels`
+    /// contains exactly `width * height` entries.
+    pub fn from_argb(
+        width: u32,
+        height: u32,
+        hotspot: (u32, u32),
+        pixels: Vec<u32>,
+    ) -> Option<Self> {
+        if pixels.len() != width.checked_mul(height)? as usize {
+            return None;
+        }
+
+        Some(Self {
+            width,
+            height,
+            hotspot,
+            pixels,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn hotspot(&self) -> (u32, u32) {
+        self.hotspot
+    }
+
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    /// Converts the image into tightly packed RGBA bytes.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.pixels.len() * 4);
+        for pixel in &self.pixels {
+            out.push((pixel >> 16) as u8);
+            out.push((pixel >> 8) as u8);
+            out.push(*pixel as u8);
+            out.push((pixel >> 24) as u8);
+        }
+        out
+    }
+}
+
+/// Cursor names to try for each of flutter's `SystemMouseCursors` kinds, CSS names first followed
+/// by the legacy X11 names.
+fn system_cursor_names(kind: &str) -> &'static [&'static str] {
+    match kind {
+        "click" => &["pointer", "hand2", "hand1"],
+        "forbidden" => &["not-allowed", "crossed_circle"],
+        "wait" => &["wait", "watch"],
+        "progress" => &["progress", "left_ptr_watch"],
+        "contextMenu" => &["context-menu", "left_ptr"],
+        "help" => &["help", "question_arrow"],
+        "text" => &["text", "xterm"],
+        "verticalText" => &["vertical-text", "xterm"],
+        "cell" => &["cell", "plus"],
+        "precise" => &["crosshair", "cross"],
+        "move" => &["move", "fleur"],
+        "grab" => &["grab", "openhand", "hand1"],
+        "grabbing" => &["grabbing", "closedhand", "fleur"],
+        "noDrop" => &["no-drop", "crossed_circle"],
+        "alias" => &["alias", "dnd-link"],
+        "copy" => &["copy", "dnd-copy"],
+        "allScroll" => &["all-scroll", "fleur"],
+        "resizeLeftRight" | "resizeColumn" => &["ew-resize", "col-resize", "sb_h_double_arrow"],
+        "resizeUpDown" | "resizeRow" => &["ns-resize", "row-resize", "sb_v_double_arrow"],
+        "resizeUpLeftDownRight" => &["nwse-resize", "bd_double_arrow"],
+        "resizeUpRightDownLeft" => &["nesw-resize", "fd_double_arrow"],
+        "resizeUp" => &["n-resize", "top_side"],
+        "resizeDown" => &["s-resize", "bottom_side"],
+        "resizeLeft" => &["w-resize", "left_side"],
+        "resizeRight" => &["e-resize", "right_side"],
+        "resizeUpLeft" => &["nw-resize", "top_left_corner"],
+        "resizeUpRight" => &["ne-resize", "top_right_corner"],
+        "resizeDownLeft" => &["sw-resize", "bottom_left_corner"],
+        "resizeDownRight" => &["se-resize", "bottom_right_corner"],
+        "zoomIn" => &["zoom-in"],
+        "zoomOut" => &["zoom-out"],
+        _ => &["default", "left_ptr"],
+    }
+}
+
+/// A hardware cursor plane, able to display the cursor independently of the rendered frame.
+pub(crate) trait CursorPlane {
+    /// Shows the given image, or hides the cursor when `None`.
+    fn set_image(&mut self, image: Option<&CursorImage>) -> Result<(), ()>;
+
+    /// Moves the cursor hotspot to the given position in output pixels.
+    fn move_to(&mut self, x: i32, y: i32) -> Result<(), ()>;
+}
+
+/// Cursor state shared with an output's render thread, composited into each frame.
+#[derive(Default)]
+pub(crate) struct SoftwareCursor {
+    image: Option<Arc<CursorImage>>,
+    generation: u64,
+    position: (f64, f64),
+    visible: bool,
+}
+
+/// Render thread side of a `SoftwareCursor`.
+pub(crate) struct SoftwareCursorRenderer {
+    cursor: Arc<Mutex<SoftwareCursor>>,
+    renderer: Option<TextureRenderer>,
+    generation: u64,
+    failed: bool,
+}
+
+impl SoftwareCursorRenderer {
+    pub fn new(cursor: Arc<Mutex<SoftwareCursor>>) -> Self {
+        Self {
+            cursor,
+            renderer: None,
+            generation: 0,
+            failed: false,
+        }
+    }
+
+    /// Draws the cursor on top of the current frame. Must be called with the output's context
+    /// current, before swapping buffers.
+    pub unsafe fn draw(&mut self, framebuffer: (u32, u32)) {
+        let cursor = self.cursor.lock();
+        let image = match cursor.image.as_ref() {
+            Some(image) if cursor.visible => image,
+            _ => return,
+        };
+
+        if self.renderer.is_none() && !self.failed {
+            match TextureRenderer::new() {
+                Ok(renderer) => self.renderer = Some(renderer),
+                Err(err) => {
+                    error!("Failed to set up software cursor: {}", err);
+                    self.failed = true;
+                }
+            }
+        }
+
+        let renderer = match self.renderer.as_mut() {
+            Some(renderer) => renderer,
+            None => return,
+        };
+
+        if self.generation != cursor.generation {
+            renderer.upload(image.width, image.height, &image.to_rgba());
+            self.generation = cursor.generation;
+        }
+
+        let (x, y) = cursor.position;
+        renderer.draw(
+            framebuffer,
+            (x - image.hotspot.0 as f64, y - image.hotspot.1 as f64),
+        );
+    }
+}
+
+struct CursorOutput {
+    engine: FlutterEngineWeakRef,
+    software: Arc<Mutex<SoftwareCursor>>,
+    hardware: Option<Box<dyn CursorPlane + Send>>,
+    uploaded: bool,
+}
+
+impl CursorOutput {
+    /// Shows the image at the given position, preferring the hardware plane.
+    fn show(&mut self, image: Option<&Arc<CursorImage>>, position: (f64, f64)) {
+        if let Some(plane) = self.hardware.as_mut() {
+            let mut result = Ok(());
+            if !self.uploaded {
+                result = plane.set_image(image.map(|image| &**image));
+            }
+            if result.is_ok() {
+                result = plane.move_to(position.0 as i32, position.1 as i32);
+            }
+
+            match result {
+                Ok(_) => {
+                    self.uploaded = true;
+                    return;
+                }
+                Err(_) => {
+                    warn!("Hardware cursor failed, falling back to software cursor");
+                    self.hardware = None;
+                    self.uploaded = false;
+                }
+            }
+        }
+
+        let mut software = self.software.lock();
+        if !self.uploaded {
+            software.image = image.cloned();
+            software.generation += 1;
+            self.uploaded = true;
+        }
+        software.position = position;
+        software.visible = true;
+    }
+
+    fn hide(&mut self) {
+        if let Some(plane) = self.hardware.as_mut() {
+            if plane.set_image(None).is_err() {
+                debug!("Failed to hide hardware cursor");
+            }
+            self.uploaded = false;
+        }
+        self.software.lock().visible = false;
+    }
+}
+
+/// Loads the image for one of flutter's system cursor kinds from the theme.
+fn load_system_cursor(theme: &CursorTheme, kind: &str) -> Option<CursorImage> {
+    let image = system_cursor_names(kind)
+        .iter()
+        .filter_map(|name| theme.load(name))
+        .next();
+    if image.is_none() {
+        warn!("No cursor found for {}", kind);
+    }
+    image
+}
+
+/// Tracks the cursor image and which output it is displayed on.
+pub struct CursorManager {
+    theme: Arc<CursorTheme>,
+    image: Option<Arc<CursorImage>>,
+    outputs: Vec<CursorOutput>,
+    active: Option<(FlutterEngineWeakRef, (f64, f64))>,
+}
+
+impl CursorManager {
+    pub fn new(theme: CursorTheme) -> Self {
+        let image = load_system_cursor(&theme, "basic");
+        Self {
+            theme: Arc::new(theme),
+            image: image.map(Arc::new),
+            outputs: Vec::new(),
+            active: None,
+        }
+    }
+
+    pub fn set_theme(&mut self, theme: CursorTheme) {
+        self.theme = Arc::new(theme);
+    }
+
+    pub(crate) fn add_output(
+        &mut self,
+        engine: FlutterEngineWeakRef,
+        software: Arc<Mutex<SoftwareCursor>>,
+        hardware: Option<Box<dyn CursorPlane + Send>>,
+    ) {
+        self.outputs.push(CursorOutput {
+            engine,
+            software,
+            hardware,
+            uploaded: false,
+        });
+    }
+
+    pub(crate) fn remove_output(&mut self, engine: &FlutterEngineWeakRef) {
+        self.outputs
+            .retain(|output| !output.engine.ptr_equal(engine.clone()));
+
+        let removed = match self.active.as_ref() {
+            Some((active, _)) => active.ptr_equal(engine.clone()),
+            None => false,
+        };
+        if removed {
+            self.active = None;
+        }
+    }
+
+    /// Replaces the cursor image, or hides the cursor when `None`.
+    pub fn set_image(&mut self, image: Option<CursorImage>) {
+        self.image = image.map(Arc::new);
+        for output in &mut self.outputs {
+            output.uploaded = false;
+        }
+        self.refresh();
+    }
+
+    /// Switches to one of flutter's system cursor kinds, loaded from the cursor theme. The lock
+    /// is not held while the cursor is read from disk.
+    pub fn set_system_cursor(cursor: &Mutex<Self>, kind: &str) {
+        if kind == "none" {
+            cursor.lock().set_image(None);
+            return;
+        }
+
+        let theme = cursor.lock().theme.clone();
+        if let Some(image) = load_system_cursor(&theme, kind) {
+            cursor.lock().set_image(Some(image));
+        }
+    }
+
+    /// Moves the cursor to the given position within the output owning the engine.
+    pub(crate) fn move_to(&mut self, engine: &FlutterEngineWeakRef, position: (f64, f64)) {
+        if let Some((active, _)) = self.active.as_ref() {
+            if !active.ptr_equal(engine.clone()) {
+                self.hide();
+            }
+        }
+
+        self.active = Some((engine.clone(), position));
+        self.refresh();
+    }
+
+    /// Hides the cursor on all outputs.
+    pub(crate) fn hide(&mut self) {
+        if let Some((engine, _)) = self.active.take() {
+            for output in &mut self.outputs {
+                if output.engine.ptr_equal(engine.clone()) {
+                    output.hide();
+                }
+            }
+        }
+    }
+
+    fn refresh(&mut self) {
+        let (engine, position) = match self.active.as_ref() {
+            Some(active) => active,
+            None => return,
+        };
+
+        for output in &mut self.outputs {
+            if output.engine.ptr_equal(engine.clone()) {
+                match self.image.as_ref() {
+                    Some(image) => output.show(Some(image), *position),
+                    None => output.hide(),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_the_pixel_count() {
+        assert!(CursorImage::from_argb(2, 3, (0, 0), vec![0; 6]).is_some());
+        assert!(CursorImage::from_argb(2, 3, (0, 0), vec![0; 5]).is_none());
+        assert!(CursorImage::from_argb(0, 3, (0, 0), vec![0; 1]).is_none());
+        assert!(CursorImage::from_argb(0x1_0000, 0x1_0000, (0, 0), Vec::new()).is_none());
+    }
+
+    #[test]
+    fn converts_to_rgba() {
+        let image = CursorImage::from_argb(2, 1, (0, 0), vec![0x8040_2010, 0xff00_00ff]).unwrap();
+        assert_eq!(
+            image.to_rgba(),
+            vec![0x40, 0x20, 0x10, 0x80, 0x00, 0x00, 0xff, 0xff]
+        );
+    }
+}
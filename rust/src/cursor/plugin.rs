@@ -0,0 +1,68 @@
+use crate::cursor::CursorManager;
+use flutter_engine::channel::{ChannelRegistrar, MethodCallHandler, StandardMethodChannel};
+use flutter_engine::codec::{MethodCall, Value};
+use flutter_engine::error::MethodCallError;
+use flutter_engine::plugins::Plugin;
+use flutter_engine::FlutterEngine;
+use log::debug;
+use parking_lot::{Mutex, RwLock};
+use std::sync::{Arc, Weak};
+
+pub const PLUGIN_NAME: &str = module_path!();
+pub const CHANNEL_NAME: &str = "flutter/mousecursor";
+
+/// Handles flutter's requests to change the mouse cursor shape.
+pub(crate) struct MouseCursorPlugin {
+    channel: Weak<StandardMethodChannel>,
+    handler: Arc<RwLock<Handler>>,
+}
+
+struct Handler {
+    cursor: Arc<Mutex<CursorManager>>,
+}
+
+impl MouseCursorPlugin {
+    pub fn new(cursor: Arc<Mutex<CursorManager>>) -> Self {
+        Self {
+            channel: Weak::new(),
+            handler: Arc::new(RwLock::new(Handler { cursor })),
+        }
+    }
+}
+
+impl Plugin for MouseCursorPlugin {
+    fn plugin_name() -> &'static str {
+        PLUGIN_NAME
+    }
+
+    fn init_channels(&mut self, registrar: &mut ChannelRegistrar) {
+        let method_handler = Arc::downgrade(&self.handler);
+        self.channel =
+            registrar.register_channel(StandardMethodChannel::new(CHANNEL_NAME, method_handler));
+    }
+}
+
+impl MethodCallHandler for Handler {
+    fn on_method_call(
+        &mut self,
+        call: MethodCall,
+        _engine: FlutterEngine,
+    ) -> Result<Value, MethodCallError> {
+        match call.method.as_str() {
+            "activateSystemCursor" => {
+                let kind = match &call.args {
+                    Value::Map(args) => match args.get("kind") {
+                        Some(Value::String(kind)) => kind.clone(),
+                        _ => return Err(MethodCallError::ArgParseError(None)),
+                    },
+                    _ => return Err(MethodCallError::ArgParseError(None)),
+                };
+
+                debug!("Activating system cursor {}", kind);
+                CursorManager::set_system_cursor(&self.cursor, &kind);
+                Ok(Value::Null)
+            }
+            _ => Err(MethodCallError::NotImplemented),
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use crate::cursor::CursorImage;
+use log::{debug, trace};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const XCURSOR_MAGIC: u32 = 0x7275_6358; // "Xcur"
+const XCURSOR_IMAGE_TYPE: u32 = 0xfffd_0002;
+const XCURSOR_IMAGE_HEADER: u32 = 36;
+const XCURSOR_MAX_SIZE: u32 = 0x7fff;
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Parses an XCursor file, returning the first frame of the image closest to the given nominal
+/// size.
+pub fn parse(data: &[u8], size: u32) -> Option<CursorImage> {
+    if read_u32(data, 0)? != XCURSOR_MAGIC {
+        return None;
+    }
+
+    let header = read_u32(data, 4)? as usize;
+    let toc_count = read_u32(data, 12)? as usize;
+
+    // Find the image chunk with the closest size, earlier chunks win ties to pick frame 0
+    let mut best: Option<(u32, usize)> = None;
+    for i in 0..toc_count {
+        let entry = header + i * 12;
+        if read_u32(data, entry)? != XCURSOR_IMAGE_TYPE {
+            continue;
+        }
+
+        let nominal = read_u32(data, entry + 4)?;
+        let position = read_u32(data, entry + 8)? as usize;
+        let distance = nominal.max(size) - nominal.min(size);
+
+        if best.map(|(best, _)| distance < best).unwrap_or(true) {
+            best = Some((distance, position));
+        }
+    }
+
+    let (_, position) = best?;
+    if read_u32(data, position)? != XCURSOR_IMAGE_HEADER
+        || read_u32(data, position + 4)? != XCURSOR_IMAGE_TYPE
+    {
+        return None;
+    }
+
+    let width = read_u32(data, position + 16)?;
+    let height = read_u32(data, position + 20)?;
+    let xhot = read_u32(data, position + 24)?;
+    let yhot = read_u32(data, position + 28)?;
+    if width > XCURSOR_MAX_SIZE || height > XCURSOR_MAX_SIZE || xhot > width || yhot > height {
+        return None;
+    }
+
+    let start = position + XCURSOR_IMAGE_HEADER as usize;
+    let end = start.checked_add((width as usize * height as usize).checked_mul(4)?)?;
+    let pixels = data
+        .get(start..end)?
+        .chunks_exact(4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+
+    CursorImage::from_argb(width, height, (xhot, yhot), pixels)
+}
+
+/// Locates cursors using the same search rules as libXcursor.
+pub struct CursorTheme {
+    name: String,
+    size: u32,
+    search_path: Vec<PathBuf>,
+}
+
+impl CursorTheme {
+    /// Loads the theme named by `XCURSOR_THEME`, at the size in `XCURSOR_SIZE`.
+    pub fn from_env() -> Self {
+        let name = env::var("XCURSOR_THEME").unwrap_or_else(|_| "default".to_string());
+        let size = env::var("XCURSOR_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(24);
+        Self::new(name, size)
+    }
+
+    pub fn new(name: String, size: u32) -> Self {
+        let search_path = match env::var("XCURSOR_PATH") {
+            Ok(path) => path.split(':').map(expand_home).collect(),
+            Err(_) => vec![
+                expand_home("~/.local/share/icons"),
+                expand_home("~/.icons"),
+                PathBuf::from("/usr/share/icons"),
+                PathBuf::from("/usr/share/pixmaps"),
+            ],
+        };
+
+        Self {
+            name,
+            size,
+            search_path,
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Loads the named cursor from this theme or any theme it inherits from.
+    pub fn load(&self, cursor: &str) -> Option<CursorImage> {
+        let mut visited = HashSet::new();
+        self.load_from(&self.name, cursor, &mut visited)
+    }
+
+    fn load_from(
+        &self,
+        theme: &str,
+        cursor: &str,
+        visited: &mut HashSet<String>,
+    ) -> Option<CursorImage> {
+        if !visited.insert(theme.to_string()) {
+            return None;
+        }
+
+        for dir in &self.search_path {
+            let path = dir.join(theme).join("cursors").join(cursor);
+            if let Ok(data) = fs::read(&path) {
+                trace!("Loading cursor {:?}", path);
+                if let Some(image) = parse(&data, self.size) {
+                    return Some(image);
+                }
+                debug!("Invalid cursor file {:?}", path);
+            }
+        }
+
+        for parent in self.inherits(theme) {
+            if let Some(image) = self.load_from(&parent, cursor, visited) {
+                return Some(image);
+            }
+        }
+
+        None
+    }
+
+    /// Reads the `Inherits` key from the theme's `index.theme`.
+    fn inherits(&self, theme: &str) -> Vec<String> {
+        for dir in &self.search_path {
+            let index = match fs::read_to_string(dir.join(theme).join("index.theme")) {
+                Ok(index) => index,
+                Err(_) => continue,
+            };
+
+            for line in index.lines() {
+                let mut parts = line.splitn(2, '=');
+                if parts.next().map(str::trim) == Some("Inherits") {
+                    return parts
+                        .next()
+                        .unwrap_or("")
+                        .split(&[',', ';'][..])
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_string)
+                        .collect();
+                }
+            }
+        }
+
+        Vec::new()
+    }
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    if let Some(relative) = path.strip_prefix("~/") {
+        if let Some(home) = env::var_os("HOME") {
+            return Path::new(&home).join(relative);
+        }
+    }
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a cursor file with an image chunk for each nominal size and image size.
+    fn cursor_file(images: &[(u32, (u32, u32))]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let push = |data: &mut Vec<u8>, value: u32| data.extend_from_slice(&value.to_le_bytes());
+
+        push(&mut data, XCURSOR_MAGIC);
+        push(&mut data, 16);
+        push(&mut data, 0x1_0000);
+        push(&mut data, images.len() as u32);
+
+        let mut position = 16 + images.len() as u32 * 12;
+        for (nominal, (width, height)) in images {
+            push(&mut data, XCURSOR_IMAGE_TYPE);
+            push(&mut data, *nominal);
+            push(&mut data, position);
+            position += XCURSOR_IMAGE_HEADER + width * height * 4;
+        }
+
+        for (nominal, (width, height)) in images {
+            for value in &[
+                XCURSOR_IMAGE_HEADER,
+                XCURSOR_IMAGE_TYPE,
+                *nominal,
+                1,
+                *width,
+                *height,
+                width / 2,
+                height / 2,
+                0,
+            ] {
+                push(&mut data, *value);
+            }
+            for i in 0..width * height {
+                push(&mut data, 0xff00_0000 | nominal << 8 | i);
+            }
+        }
+        data
+    }
+
+    /// Offset of the `index`th entry of the table of contents.
+    fn toc_entry(index: usize) -> usize {
+        16 + index * 12
+    }
+
+    fn set_u32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn picks_the_closest_size() {
+        let data = cursor_file(&[(24, (2, 3)), (32, (4, 4)), (48, (6, 6))]);
+
+        let image = parse(&data, 30).unwrap();
+        assert_eq!((image.width(), image.height()), (4, 4));
+        assert_eq!(image.hotspot(), (2, 2));
+        assert_eq!(image.pixels()[0], 0xff00_2000);
+        assert_eq!(image.pixels()[15], 0xff00_200f);
+
+        let image = parse(&data, 16).unwrap();
+        assert_eq!((image.width(), image.height()), (2, 3));
+        assert_eq!(image.pixels().len(), 6);
+
+        let image = parse(&data, 64).unwrap();
+        assert_eq!((image.width(), image.height()), (6, 6));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let data = cursor_file(&[(24, (2, 2))]);
+        assert!(parse(&data, 24).is_some());
+
+        for len in 0..data.len() {
+            assert!(parse(&data[..len], 24).is_none(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut data = cursor_file(&[(24, (2, 2))]);
+        set_u32(&mut data, 0, 0x1234_5678);
+        assert!(parse(&data, 24).is_none());
+
+        // A table of contents without images
+        let mut data = cursor_file(&[(24, (2, 2))]);
+        set_u32(&mut data, toc_entry(0), 0xfffe_0001);
+        assert!(parse(&data, 24).is_none());
+
+        // More entries than the file holds
+        let mut data = cursor_file(&[(24, (2, 2))]);
+        set_u32(&mut data, 12, u32::MAX);
+        assert!(parse(&data, 24).is_none());
+    }
+
+    #[test]
+    fn rejects_chunks_past_the_end() {
+        let data = cursor_file(&[(24, (2, 2))]);
+
+        for position in &[data.len() as u32, data.len() as u32 + 100, u32::MAX] {
+            let mut data = data.clone();
+            set_u32(&mut data, toc_entry(0) + 8, *position);
+            assert!(parse(&data, 24).is_none(), "chunk at {}", position);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_images() {
+        let data = cursor_file(&[(24, (2, 2))]);
+        let chunk = toc_entry(1);
+
+        // Too large for XCursor, too large for the data, and a hotspot outside the image
+        for (offset, value) in &[(16, 0x8000), (20, 0x7fff), (16, u32::MAX), (24, 3)] {
+            let mut data = data.clone();
+            set_u32(&mut data, chunk + offset, *value);
+            assert!(parse(&data, 24).is_none(), "{:#x} at {}", value, offset);
+        }
+    }
+}
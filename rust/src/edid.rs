@@ -0,0 +1,483 @@
+const EDID_HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+const EDID_BLOCK_SIZE: usize = 128;
+
+const DESCRIPTOR_SERIAL: u8 = 0xff;
+const DESCRIPTOR_NAME: u8 = 0xfc;
+
+/// Modes flagged in the established timings bitmap, from the most significant bit of byte 35.
+const ESTABLISHED_TIMINGS: [(u32, u32, u32); 17] = [
+    (720, 400, 70),
+    (720, 400, 88),
+    (640, 480, 60),
+    (640, 480, 67),
+    (640, 480, 72),
+    (640, 480, 75),
+    (800, 600, 56),
+    (800, 600, 60),
+    (800, 600, 72),
+    (800, 600, 75),
+    (832, 624, 75),
+    (1024, 768, 87),
+    (1024, 768, 60),
+    (1024, 768, 70),
+    (1024, 768, 75),
+    (1280, 1024, 75),
+    (1152, 870, 75),
+];
+
+/// A mode the display claims to support.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EdidMode {
+    pub width: u32,
+    pub height: u32,
+    /// Refresh rate in Hz, counted in fields for interlaced modes like DRM does.
+    pub refresh: u32,
+    pub interlaced: bool,
+}
+
+/// A detailed timing descriptor, the first of which is the display's native mode.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DetailedTiming {
+    pub pixel_clock_khz: u32,
+    pub hactive: u32,
+    pub hblank: u32,
+    pub hsync_offset: u32,
+    pub hsync_width: u32,
+    /// Lines of a single field for interlaced timings.
+    pub vactive: u32,
+    pub vblank: u32,
+    pub vsync_offset: u32,
+    pub vsync_width: u32,
+    /// Image size in millimetres, zero if unknown.
+    pub image_size_mm: (u32, u32),
+    pub interlaced: bool,
+}
+
+impl DetailedTiming {
+    fn parse(d: &[u8]) -> Option<Self> {
+        let pixel_clock = u16::from_le_bytes([d[0], d[1]]) as u32;
+        if pixel_clock == 0 {
+            return None;
+        }
+
+        let timing = Self {
+            pixel_clock_khz: pixel_clock * 10,
+            hactive: d[2] as u32 | (d[4] as u32 & 0xf0) << 4,
+            hblank: d[3] as u32 | (d[4] as u32 & 0x0f) << 8,
+            vactive: d[5] as u32 | (d[7] as u32 & 0xf0) << 4,
+            vblank: d[6] as u32 | (d[7] as u32 & 0x0f) << 8,
+            hsync_offset: d[8] as u32 | (d[11] as u32 & 0xc0) << 2,
+            hsync_width: d[9] as u32 | (d[11] as u32 & 0x30) << 4,
+            vsync_offset: (d[10] as u32 >> 4) | (d[11] as u32 & 0x0c) << 2,
+            vsync_width: (d[10] as u32 & 0x0f) | (d[11] as u32 & 0x03) << 4,
+            image_size_mm: (
+                d[12] as u32 | (d[14] as u32 & 0xf0) << 4,
+                d[13] as u32 | (d[14] as u32 & 0x0f) << 8,
+            ),
+            interlaced: d[17] & 0x80 != 0,
+        };
+
+        if timing.hactive == 0 || timing.vactive == 0 {
+            return None;
+        }
+        Some(timing)
+    }
+
+    /// Refresh rate in millihertz, which is the field rate for interlaced timings.
+    pub fn refresh_mhz(&self) -> u32 {
+        let total = (self.hactive + self.hblank) as u64 * (self.vactive + self.vblank) as u64;
+        (self.pixel_clock_khz as u64 * 1_000_000 / total) as u32
+    }
+
+    fn mode(&self) -> EdidMode {
+        EdidMode {
+            width: self.hactive,
+            height: if self.interlaced {
+                self.vactive * 2
+            } else {
+                self.vactive
+            },
+            refresh: (self.refresh_mhz() + 500) / 1000,
+            interlaced: self.interlaced,
+        }
+    }
+}
+
+/// Identity and capabilities of a display, as reported in its EDID.
+#[derive(Clone, Debug)]
+pub struct Edid {
+    /// EDID version and revision, such as `(1, 4)`.
+    pub version: (u8, u8),
+    /// Three letter PNP id of the manufacturer, such as `DEL` or `SAM`.
+    pub manufacturer: String,
+    pub product_code: u16,
+    pub serial_number: u32,
+    /// Monitor name descriptor.
+    pub name: Option<String>,
+    /// Serial number descriptor, usually more useful than `serial_number`.
+    pub serial: Option<String>,
+    /// Physical size in millimetres, if known.
+    pub physical_size: Option<(u32, u32)>,
+    /// The display's native mode.
+    pub native_timing: Option<DetailedTiming>,
+    /// Every detailed, standard and established mode, native mode first.
+    pub modes: Vec<EdidMode>,
+}
+
+impl Edid {
+    /// Parses the base EDID block. Returns `None` if the data is not a valid EDID. Extension
+    /// blocks, such as the CEA-861 block of TVs, are ignored, so modes only listed there are
+    /// missing.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < EDID_BLOCK_SIZE || data[..8] != EDID_HEADER {
+            return None;
+        }
+
+        let checksum = data[..EDID_BLOCK_SIZE]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if checksum != 0 {
+            return None;
+        }
+
+        // Three 5 bit letters, 1 = 'A'
+        let id = u16::from_be_bytes([data[8], data[9]]);
+        let manufacturer = [(id >> 10) & 0x1f, (id >> 5) & 0x1f, id & 0x1f]
+            .iter()
+            .map(|c| (b'A' - 1 + *c as u8) as char)
+            .collect();
+
+        let mut edid = Self {
+            version: (data[18], data[19]),
+            manufacturer,
+            product_code: u16::from_le_bytes([data[10], data[11]]),
+            serial_number: u32::from_le_bytes([data[12], data[13], data[14], data[15]]),
+            name: None,
+            serial: None,
+            physical_size: None,
+            native_timing: None,
+            modes: Vec::new(),
+        };
+
+        let mut timings = Vec::new();
+        for descriptor in data[54..126].chunks(18) {
+            // Display descriptors have a zero pixel clock
+            if descriptor[0] != 0 || descriptor[1] != 0 {
+                timings.extend(DetailedTiming::parse(descriptor));
+                continue;
+            }
+
+            match descriptor[3] {
+                DESCRIPTOR_NAME => edid.name = descriptor_text(&descriptor[5..]),
+                DESCRIPTOR_SERIAL => edid.serial = descriptor_text(&descriptor[5..]),
+                _ => {}
+            }
+        }
+        edid.native_timing = timings.first().cloned();
+
+        // The native timing's image size is more precise than the basic size in centimetres
+        edid.physical_size = match edid.native_timing {
+            Some(timing) if timing.image_size_mm.0 > 0 && timing.image_size_mm.1 > 0 => {
+                Some(timing.image_size_mm)
+            }
+            _ if data[21] > 0 && data[22] > 0 => Some((data[21] as u32 * 10, data[22] as u32 * 10)),
+            _ => None,
+        };
+
+        let version = edid.version;
+        let standard = data[38..54]
+            .chunks(2)
+            .filter_map(|timing| standard_timing(timing, version));
+        let established = ESTABLISHED_TIMINGS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| data[35 + bit / 8] & (0x80 >> (bit % 8)) != 0)
+            .map(|(_, (width, height, refresh))| EdidMode {
+                width: *width,
+                height: *height,
+                refresh: *refresh,
+                interlaced: false,
+            });
+
+        for mode in timings
+            .iter()
+            .map(DetailedTiming::mode)
+            .chain(standard)
+            .chain(established)
+        {
+            if !edid.modes.contains(&mode) {
+                edid.modes.push(mode);
+            }
+        }
+
+        Some(edid)
+    }
+}
+
+fn standard_timing(data: &[u8], version: (u8, u8)) -> Option<EdidMode> {
+    // Unused entries are filled with 0x01
+    if (data[0] == 0x01 && data[1] == 0x01) || data[0] == 0 {
+        return None;
+    }
+
+    let width = (data[0] as u32 + 31) * 8;
+    let height = match data[1] >> 6 {
+        0 if version < (1, 3) => width,
+        0 => width * 10 / 16,
+        1 => width * 3 / 4,
+        2 => width * 4 / 5,
+        _ => width * 9 / 16,
+    };
+
+    Some(EdidMode {
+        width,
+        height,
+        refresh: (data[1] & 0x3f) as u32 + 60,
+        interlaced: false,
+    })
+}
+
+/// Decodes descriptor text, which is terminated by a newline and padded with spaces.
+fn descriptor_text(data: &[u8]) -> Option<String> {
+    let text: String = data
+        .iter()
+        .take_while(|c| **c != b'\n')
+        .map(|c| *c as char)
+        .collect();
+    let text = text.trim();
+
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Modelled on the EDIDs of a 24" 1080p desktop monitor, a 14" laptop panel which lists a
+    // second, lower refresh rate, and a TV with a CEA-861 extension block.
+    const DESKTOP: [u8; 128] = [
+        0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x10, 0xac, 0xe4, 0xa0, 0x30, 0x4a, 0x4c,
+        0x4c, 0x0c, 0x1d, 0x01, 0x04, 0xa5, 0x35, 0x1e, 0x78, 0x3a, 0xee, 0x95, 0xa3, 0x54, 0x4c,
+        0x99, 0x26, 0x0f, 0x50, 0x54, 0xa5, 0x4b, 0x00, 0x81, 0x00, 0x81, 0x80, 0x81, 0xc0, 0x95,
+        0x00, 0xa9, 0xc0, 0xb3, 0x00, 0xd1, 0xc0, 0x01, 0x01, 0x02, 0x3a, 0x80, 0x18, 0x71, 0x38,
+        0x2d, 0x40, 0x58, 0x2c, 0x45, 0x00, 0x0f, 0x28, 0x21, 0x00, 0x00, 0x1e, 0x00, 0x00, 0x00,
+        0xff, 0x00, 0x37, 0x4d, 0x54, 0x30, 0x31, 0x39, 0x34, 0x4c, 0x34, 0x4c, 0x4c, 0x4c, 0x0a,
+        0x00, 0x00, 0x00, 0xfc, 0x00, 0x44, 0x45, 0x4c, 0x4c, 0x20, 0x50, 0x32, 0x34, 0x31, 0x39,
+        0x48, 0x0a, 0x20, 0x00, 0x00, 0x00, 0xfd, 0x00, 0x32, 0x4b, 0x1e, 0x53, 0x11, 0x00, 0x0a,
+        0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x00, 0xdc,
+    ];
+
+    const LAPTOP: [u8; 128] = [
+        0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x09, 0xe5, 0x47, 0x07, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x1c, 0x01, 0x04, 0x95, 0x1f, 0x11, 0x78, 0x02, 0xa2, 0x42, 0x97, 0x57, 0x52,
+        0x8c, 0x27, 0x25, 0x50, 0x54, 0x00, 0x00, 0x00, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+        0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x1a, 0x36, 0x80, 0xa0, 0x70, 0x38,
+        0x1e, 0x40, 0x30, 0x20, 0x35, 0x00, 0x35, 0xae, 0x10, 0x00, 0x00, 0x1a, 0x48, 0x2b, 0x80,
+        0xa0, 0x70, 0x38, 0x1e, 0x40, 0x30, 0x20, 0x35, 0x00, 0x35, 0xae, 0x10, 0x00, 0x00, 0x1a,
+        0x00, 0x00, 0x00, 0xfe, 0x00, 0x42, 0x4f, 0x45, 0x20, 0x48, 0x46, 0x0a, 0x20, 0x20, 0x20,
+        0x20, 0x20, 0x20, 0x00, 0x00, 0x00, 0xfe, 0x00, 0x4e, 0x56, 0x31, 0x34, 0x30, 0x46, 0x48,
+        0x4d, 0x2d, 0x4e, 0x34, 0x39, 0x0a, 0x00, 0x37,
+    ];
+
+    const TV: [u8; 256] = [
+        0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x4c, 0x2d, 0x97, 0x0f, 0x00, 0x0e, 0x00,
+        0x01, 0x22, 0x1b, 0x01, 0x03, 0x80, 0xa0, 0x5a, 0x78, 0x0a, 0xee, 0x91, 0xa3, 0x54, 0x4c,
+        0x99, 0x26, 0x0f, 0x50, 0x54, 0xbd, 0xef, 0x80, 0x81, 0x00, 0x81, 0x80, 0x81, 0xc0, 0x95,
+        0x00, 0xa9, 0xc0, 0xb3, 0x00, 0xd1, 0xc0, 0x01, 0x01, 0x02, 0x3a, 0x80, 0x18, 0x71, 0x38,
+        0x2d, 0x40, 0x58, 0x2c, 0x45, 0x00, 0x40, 0x84, 0x63, 0x00, 0x00, 0x1e, 0x01, 0x1d, 0x80,
+        0x18, 0x71, 0x1c, 0x16, 0x20, 0x58, 0x2c, 0x25, 0x00, 0x40, 0x84, 0x63, 0x00, 0x00, 0x9e,
+        0x00, 0x00, 0x00, 0xfd, 0x00, 0x18, 0x4b, 0x0f, 0x87, 0x3c, 0x00, 0x0a, 0x20, 0x20, 0x20,
+        0x20, 0x20, 0x20, 0x00, 0x00, 0x00, 0xfc, 0x00, 0x53, 0x41, 0x4d, 0x53, 0x55, 0x4e, 0x47,
+        0x0a, 0x20, 0x20, 0x20, 0x20, 0x20, 0x01, 0x94, 0x02, 0x03, 0x1b, 0xf0, 0x4c, 0x90, 0x04,
+        0x1f, 0x13, 0x05, 0x14, 0x20, 0x22, 0x61, 0x60, 0x5f, 0x01, 0x23, 0x09, 0x07, 0x07, 0x65,
+        0x03, 0x0c, 0x00, 0x10, 0x00, 0x01, 0x1d, 0x00, 0xbc, 0x52, 0xd0, 0x1e, 0x20, 0xb8, 0x28,
+        0x55, 0x40, 0x40, 0x84, 0x63, 0x00, 0x00, 0x1e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xb0,
+    ];
+
+    fn mode(width: u32, height: u32, refresh: u32) -> EdidMode {
+        EdidMode {
+            width,
+            height,
+            refresh,
+            interlaced: false,
+        }
+    }
+
+    fn interlaced(width: u32, height: u32, refresh: u32) -> EdidMode {
+        EdidMode {
+            interlaced: true,
+            ..mode(width, height, refresh)
+        }
+    }
+
+    #[test]
+    fn desktop_monitor() {
+        let edid = Edid::parse(&DESKTOP).unwrap();
+        assert_eq!(edid.version, (1, 4));
+        assert_eq!(edid.manufacturer, "DEL");
+        assert_eq!(edid.product_code, 0xa0e4);
+        assert_eq!(edid.serial_number, 0x4c4c_4a30);
+        assert_eq!(edid.name.as_deref(), Some("DELL P2419H"));
+        assert_eq!(edid.serial.as_deref(), Some("7MT0194L4LLL"));
+        assert_eq!(edid.physical_size, Some((527, 296)));
+        assert_eq!(
+            edid.native_timing,
+            Some(DetailedTiming {
+                pixel_clock_khz: 148_500,
+                hactive: 1920,
+                hblank: 280,
+                hsync_offset: 88,
+                hsync_width: 44,
+                vactive: 1080,
+                vblank: 45,
+                vsync_offset: 4,
+                vsync_width: 5,
+                image_size_mm: (527, 296),
+                interlaced: false,
+            })
+        );
+        assert_eq!(edid.native_timing.unwrap().refresh_mhz(), 60_000);
+        assert_eq!(
+            edid.modes,
+            vec![
+                // Detailed
+                mode(1920, 1080, 60),
+                // Standard, 1920x1080 is already listed
+                mode(1280, 800, 60),
+                mode(1280, 1024, 60),
+                mode(1280, 720, 60),
+                mode(1440, 900, 60),
+                mode(1600, 900, 60),
+                mode(1680, 1050, 60),
+                // Established
+                mode(720, 400, 70),
+                mode(640, 480, 60),
+                mode(640, 480, 75),
+                mode(800, 600, 60),
+                mode(800, 600, 75),
+                mode(1024, 768, 60),
+                mode(1024, 768, 75),
+                mode(1280, 1024, 75),
+            ]
+        );
+    }
+
+    #[test]
+    fn laptop_panel() {
+        let edid = Edid::parse(&LAPTOP).unwrap();
+        assert_eq!(edid.manufacturer, "BOE");
+        assert_eq!(edid.product_code, 0x0747);
+        assert_eq!(edid.serial_number, 0);
+        // Panels describe themselves in unspecified text descriptors
+        assert_eq!(edid.name, None);
+        assert_eq!(edid.serial, None);
+        assert_eq!(edid.physical_size, Some((309, 174)));
+
+        let native = edid.native_timing.unwrap();
+        assert_eq!((native.hactive, native.vactive), (1920, 1080));
+        assert_eq!(native.pixel_clock_khz, 138_500);
+        assert_eq!(native.refresh_mhz(), 59_987);
+        assert_eq!(edid.modes, vec![mode(1920, 1080, 60), mode(1920, 1080, 48)]);
+    }
+
+    #[test]
+    fn tv() {
+        let edid = Edid::parse(&TV).unwrap();
+        assert_eq!(edid.version, (1, 3));
+        assert_eq!(edid.manufacturer, "SAM");
+        assert_eq!(edid.product_code, 0x0f97);
+        assert_eq!(edid.serial_number, 0x0100_0e00);
+        assert_eq!(edid.name.as_deref(), Some("SAMSUNG"));
+        assert_eq!(edid.serial, None);
+        assert_eq!(edid.physical_size, Some((1600, 900)));
+
+        let native = edid.native_timing.unwrap();
+        assert_eq!((native.hactive, native.vactive), (1920, 1080));
+        assert!(!native.interlaced);
+        assert_eq!(
+            edid.modes,
+            vec![
+                // Detailed, the second is 1080i
+                mode(1920, 1080, 60),
+                interlaced(1920, 1080, 60),
+                // Standard
+                mode(1280, 800, 60),
+                mode(1280, 1024, 60),
+                mode(1280, 720, 60),
+                mode(1440, 900, 60),
+                mode(1600, 900, 60),
+                mode(1680, 1050, 60),
+                // Established
+                mode(720, 400, 70),
+                mode(640, 480, 60),
+                mode(640, 480, 67),
+                mode(640, 480, 72),
+                mode(640, 480, 75),
+                mode(800, 600, 60),
+                mode(800, 600, 72),
+                mode(800, 600, 75),
+                mode(832, 624, 75),
+                mode(1024, 768, 60),
+                mode(1024, 768, 70),
+                mode(1024, 768, 75),
+                mode(1280, 1024, 75),
+                mode(1152, 870, 75),
+            ]
+        );
+    }
+
+    #[test]
+    fn interlaced_timing() {
+        let timing = DetailedTiming::parse(&TV[72..90]).unwrap();
+        assert!(timing.interlaced);
+        assert_eq!((timing.hactive, timing.vactive), (1920, 540));
+        assert_eq!(timing.refresh_mhz(), 60_053);
+        assert_eq!(timing.mode(), interlaced(1920, 1080, 60));
+    }
+
+    #[test]
+    fn ignores_extension_blocks() {
+        // The CEA block's 720p50 timing and 4K video codes aren't reported
+        let edid = Edid::parse(&TV).unwrap();
+        assert!(!edid.modes.contains(&mode(1280, 720, 50)));
+        assert!(edid.modes.iter().all(|mode| mode.width < 3840));
+        assert_eq!(edid.modes, Edid::parse(&TV[..128]).unwrap().modes);
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(Edid::parse(&[]).is_none());
+        assert!(Edid::parse(&DESKTOP[..8]).is_none());
+        assert!(Edid::parse(&DESKTOP[..127]).is_none());
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut data = DESKTOP;
+        data[127] = data[127].wrapping_add(1);
+        assert!(Edid::parse(&data).is_none());
+
+        let mut data = LAPTOP;
+        data[20] ^= 0x01;
+        assert!(Edid::parse(&data).is_none());
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut data = DESKTOP;
+        data[0] = 0xff;
+        // Keep the checksum valid, so only the header is wrong
+        data[127] = data[127].wrapping_sub(0xff);
+        assert!(Edid::parse(&data).is_none());
+    }
+}
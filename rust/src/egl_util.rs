@@ -1,3 +1,4 @@
+use crate::error::Error;
 use core::{mem, ptr};
 use log::{debug, info, trace};
 
@@ -5,9 +6,66 @@ use smithay::backend::egl::context::PixelFormatRequirements;
 use smithay::backend::egl::{ffi, native};
 use smithay::backend::graphics::{PixelFormat, SwapBuffersError};
 use std::cell::Cell;
-use std::ffi::{c_void, CStr, CString};
+use std::ffi::{c_void, CStr, CString, OsStr};
 use std::ops::{Deref, DerefMut};
-use std::os::raw::c_int;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// From `EGL_MESA_platform_surfaceless`, which smithay's bindings don't include.
+const PLATFORM_SURFACELESS_MESA: ffi::egl::types::EGLenum = 0x31DD;
+
+/// From `EGL_EXT_platform_device` and `EGL_EXT_device_drm`, which smithay's bindings don't include.
+const PLATFORM_DEVICE_EXT: ffi::egl::types::EGLenum = 0x313F;
+const DRM_DEVICE_FILE_EXT: ffi::egl::types::EGLint = 0x3233;
+
+/// From `EGL_EXT_platform_x11` and `EGL_EXT_platform_wayland`, which smithay's bindings don't
+/// include.
+const PLATFORM_X11_EXT: ffi::egl::types::EGLenum = 0x31D5;
+const PLATFORM_WAYLAND_EXT: ffi::egl::types::EGLenum = 0x31D8;
+
+type QueryDevicesExt = unsafe extern "system" fn(
+    ffi::egl::types::EGLint,
+    *mut *mut c_void,
+    *mut ffi::egl::types::EGLint,
+) -> ffi::egl::types::EGLBoolean;
+type QueryDeviceStringExt =
+    unsafe extern "system" fn(*mut c_void, ffi::egl::types::EGLint) -> *const c_char;
+
+/// The display server a native display belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NativePlatform {
+    Wayland,
+    X11,
+}
+
+impl NativePlatform {
+    fn platform(self) -> ffi::egl::types::EGLenum {
+        match self {
+            NativePlatform::Wayland => PLATFORM_WAYLAND_EXT,
+            NativePlatform::X11 => PLATFORM_X11_EXT,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            NativePlatform::Wayland => "EGL_EXT_platform_wayland",
+            NativePlatform::X11 => "EGL_EXT_platform_x11",
+        }
+    }
+}
+
+/// Extensions of EGL itself rather than of a display, which EGL 1.5 or
+/// `EGL_EXT_client_extensions` list.
+unsafe fn client_extensions() -> Vec<String> {
+    let extensions = ffi::egl::QueryString(ffi::egl::NO_DISPLAY, ffi::egl::EXTENSIONS as i32);
+    if extensions.is_null() {
+        return Vec::new();
+    }
+    let extensions = CStr::from_ptr(extensions).to_string_lossy();
+    trace!("EGL client extensions: {}", extensions);
+    extensions.split(' ').map(|e| e.to_string()).collect()
+}
 
 pub struct WrappedDisplay(ffi::egl::types::EGLDisplay);
 
@@ -22,15 +80,119 @@ impl Clone for WrappedDisplay {
 }
 
 impl WrappedDisplay {
-    pub unsafe fn get_current() -> Self {
+    pub unsafe fn get_current() -> Result<Self, Error> {
         let display = ffi::egl::GetCurrentDisplay();
 
         if display == ptr::null() {
-            panic!("Failed to fetch display");
+            return Err(Error::Egl("no display is current".to_string()));
         }
 
         trace!("Current display was {:?}", display);
-        WrappedDisplay(display)
+        Ok(WrappedDisplay(display))
+    }
+
+    /// Opens a display which needs neither a GPU nor a display server, preferring Mesa's
+    /// surfaceless platform over the default display.
+    pub unsafe fn headless() -> Result<Self, Error> {
+        ffi::make_sure_egl_is_loaded();
+
+        let extensions = client_extensions();
+        let mut display = ptr::null();
+        if extensions.iter().any(|e| e == "EGL_EXT_platform_base")
+            && extensions
+                .iter()
+                .any(|e| e == "EGL_MESA_platform_surfaceless")
+        {
+            display = ffi::egl::GetPlatformDisplayEXT(
+                PLATFORM_SURFACELESS_MESA,
+                ptr::null_mut(),
+                ptr::null(),
+            );
+        }
+        if display.is_null() {
+            debug!("Surfaceless platform unavailable, using the default display");
+            display = ffi::egl::GetDisplay(ffi::egl::DEFAULT_DISPLAY);
+        }
+
+        if display.is_null() {
+            return Err(Error::Egl("no headless display available".to_string()));
+        }
+        Ok(WrappedDisplay(display))
+    }
+
+    /// Opens the EGL display of a Wayland `wl_display` or an Xlib `Display`, falling back to
+    /// `eglGetDisplay` where EGL can't be told which of them it is.
+    pub unsafe fn for_native(
+        platform: NativePlatform,
+        native_display: *mut c_void,
+    ) -> Result<Self, Error> {
+        ffi::make_sure_egl_is_loaded();
+
+        let extensions = client_extensions();
+        let mut display = ptr::null();
+        if extensions.iter().any(|e| e == "EGL_EXT_platform_base")
+            && extensions.iter().any(|e| e == platform.extension())
+        {
+            display =
+                ffi::egl::GetPlatformDisplayEXT(platform.platform(), native_display, ptr::null());
+        }
+        if display.is_null() {
+            debug!(
+                "{} unavailable, guessing the platform",
+                platform.extension()
+            );
+            display = ffi::egl::GetDisplay(native_display as *const _);
+        }
+
+        if display.is_null() {
+            return Err(Error::Egl(format!("no display for {:?}", platform)));
+        }
+        Ok(WrappedDisplay(display))
+    }
+
+    /// Opens the display of the GPU with the given DRM device node, for rendering into pbuffers
+    /// without showing anything on it.
+    pub unsafe fn for_device(path: &Path) -> Result<Self, Error> {
+        ffi::make_sure_egl_is_loaded();
+
+        let query_devices = ffi::egl::GetProcAddress("eglQueryDevicesEXT\0".as_ptr() as *const _);
+        let query_device_string =
+            ffi::egl::GetProcAddress("eglQueryDeviceStringEXT\0".as_ptr() as *const _);
+        if (query_devices as *const c_void).is_null()
+            || (query_device_string as *const c_void).is_null()
+        {
+            return Err(Error::Egl("EGL devices can't be enumerated".to_string()));
+        }
+        let query_devices: QueryDevicesExt = mem::transmute(query_devices);
+        let query_device_string: QueryDeviceStringExt = mem::transmute(query_device_string);
+
+        let mut count = 0;
+        if query_devices(0, ptr::null_mut(), &mut count) == 0 {
+            return Err(Error::Egl("failed to list EGL devices".to_string()));
+        }
+        let mut devices = vec![ptr::null_mut(); count.max(0) as usize];
+        if query_devices(count, devices.as_mut_ptr(), &mut count) == 0 {
+            return Err(Error::Egl("failed to list EGL devices".to_string()));
+        }
+        devices.truncate(count.max(0) as usize);
+
+        // Devices without a DRM node, such as software renderers, don't report a file
+        let device = devices
+            .into_iter()
+            .find(|device| {
+                let file = query_device_string(*device, DRM_DEVICE_FILE_EXT);
+                !file.is_null()
+                    && Path::new(OsStr::from_bytes(CStr::from_ptr(file).to_bytes()))
+                        .canonicalize()
+                        .map_or(false, |file| file == path)
+            })
+            .ok_or_else(|| Error::Egl(format!("no EGL device for {:?}", path)))?;
+
+        let display = ffi::egl::GetPlatformDisplayEXT(PLATFORM_DEVICE_EXT, device, ptr::null());
+        if display.is_null() {
+            return Err(Error::Egl(format!("no display for {:?}", path)));
+        }
+        Ok(WrappedDisplay(display))
     }
 
     pub unsafe fn clear_current(&self) {
@@ -63,7 +225,7 @@ impl Clone for WrappedContext {
 }
 
 impl WrappedContext {
-    pub unsafe fn create_context() -> WrappedContext {
+    pub unsafe fn create_context() -> Result<WrappedContext, Error> {
         debug!("Trying to initialize EGL with OpenGLES 3.0");
 
         let old_context = ffi::egl::GetCurrentContext();
@@ -72,7 +234,10 @@ impl WrappedContext {
         let display = ffi::egl::GetCurrentDisplay();
         trace!("Current display was {:?}", display);
 
-        create_context_inner((3, 0), old_context, display)
+        // The context is never bound to a surface, but headless displays may only have configs
+        // for pbuffers
+        create_context_inner((3, 0), old_context, display, ffi::egl::WINDOW_BIT)
+            .or_else(|_| create_context_inner((3, 0), old_context, display, ffi::egl::PBUFFER_BIT))
         //    attributes.version = Some((3, 0));
         //    match EGLContext::<B, N>::new_internal(ptr, attributes, reqs, log.clone()) {
         //        Ok(x) => return Ok(x),
@@ -88,10 +253,10 @@ impl WrappedContext {
     pub unsafe fn create_context_dir(
         share_context: ffi::egl::types::EGLContext,
         display: ffi::egl::types::EGLDisplay,
-    ) -> WrappedContext {
+    ) -> Result<WrappedContext, Error> {
         debug!("Trying to initialize EGL with OpenGLES 3.0");
 
-        create_context_inner((3, 0), share_context, display)
+        create_context_inner((3, 0), share_context, display, ffi::egl::WINDOW_BIT)
         //    attributes.version = Some((3, 0));
         //    match EGLContext::<B, N>::new_internal(ptr, attributes, reqs, log.clone()) {
         //        Ok(x) => return Ok(x),
@@ -104,6 +269,37 @@ impl WrappedContext {
         //    }
     }
 
+    /// Creates a context for rendering into pbuffers on the given display.
+    pub unsafe fn create_pbuffer_context(
+        display: &WrappedDisplay,
+    ) -> Result<WrappedContext, Error> {
+        debug!("Trying to initialize EGL with OpenGLES 3.0");
+
+        create_context_inner(
+            (3, 0),
+            ffi::egl::NO_CONTEXT,
+            display.0,
+            ffi::egl::PBUFFER_BIT,
+        )
+    }
+
+    /// Creates a context for rendering into windows on the given display.
+    pub unsafe fn create_window_context(display: &WrappedDisplay) -> Result<WrappedContext, Error> {
+        debug!("Trying to initialize EGL with OpenGLES 3.0");
+
+        create_context_inner(
+            (3, 0),
+            ffi::egl::NO_CONTEXT,
+            display.0,
+            ffi::egl::WINDOW_BIT,
+        )
+    }
+
+    /// Destroys the context. Neither it nor any clone may be used afterwards.
+    pub unsafe fn destroy(self) {
+        ffi::egl::DestroyContext(self.display, self.context);
+    }
+
     pub unsafe fn make_current(&self) -> bool {
         let ret = ffi::egl::MakeCurrent(self.display, ptr::null(), ptr::null(), self.context);
         ret == 1
@@ -119,7 +315,7 @@ impl WrappedContext {
         unsafe { ffi::egl::GetCurrentContext() == self.context }
     }
 
-    pub fn create_surface<N>(&self, native: N) -> WrappedSurface<N>
+    pub fn create_surface<N>(&self, native: N) -> Result<WrappedSurface<N>, Error>
     where
         N: native::NativeSurface,
     {
@@ -131,13 +327,20 @@ impl WrappedContext {
             &self.surface_attributes,
         )
     }
+
+    /// Creates an offscreen surface of the given size. The context must have been created with
+    /// `create_pbuffer_context`.
+    pub fn create_pbuffer(&self, size: (u32, u32)) -> Result<WrappedPbuffer, Error> {
+        WrappedPbuffer::create(self.context, self.display, self.config_id, size)
+    }
 }
 
 unsafe fn create_context_inner(
     version: (u8, u8),
     share_context: ffi::egl::types::EGLContext,
     display: ffi::egl::types::EGLDisplay,
-) -> WrappedContext {
+    surface_type: ffi::egl::types::EGLenum,
+) -> Result<WrappedContext, Error> {
     let reqs: PixelFormatRequirements = Default::default();
 
     let egl_version = {
@@ -145,7 +348,7 @@ unsafe fn create_context_inner(
         let mut minor = mem::MaybeUninit::uninit();
 
         if ffi::egl::Initialize(display, major.as_mut_ptr(), minor.as_mut_ptr()) == 0 {
-            panic!("Display reinit failed");
+            return Err(Error::Egl("display reinitialization failed".to_string()));
         }
 
         let major = major.assume_init();
@@ -167,7 +370,9 @@ unsafe fn create_context_inner(
     trace!("EGL Extensions: {:?}", extensions);
 
     if egl_version >= (1, 2) && ffi::egl::BindAPI(ffi::egl::OPENGL_ES_API) == 0 {
-        panic!("OpenGLES not supported by the underlying EGL implementation");
+        return Err(Error::Egl(
+            "OpenGLES not supported by the underlying EGL implementation".to_string(),
+        ));
     }
 
     let descriptor = {
@@ -179,17 +384,19 @@ unsafe fn create_context_inner(
             out.push(ffi::egl::RGB_BUFFER as c_int);
         }
 
-        trace!("Setting SURFACE_TYPE to WINDOW");
+        trace!("Setting SURFACE_TYPE to {:#x}", surface_type);
 
         out.push(ffi::egl::SURFACE_TYPE as c_int);
         // TODO: Some versions of Mesa report a BAD_ATTRIBUTE error
         // if we ask for PBUFFER_BIT as well as WINDOW_BIT
-        out.push((ffi::egl::WINDOW_BIT) as c_int);
+        out.push(surface_type as c_int);
 
         match version {
             (3, _) => {
                 if egl_version < (1, 3) {
-                    panic!("OpenglES 3.* is not supported on EGL Versions lower then 1.3");
+                    return Err(Error::Egl(
+                        "OpenGLES 3.* is not supported on EGL versions lower than 1.3".to_string(),
+                    ));
                 }
                 trace!("Setting RENDERABLE_TYPE to OPENGL_ES3");
                 out.push(ffi::egl::RENDERABLE_TYPE as c_int);
@@ -200,7 +407,9 @@ unsafe fn create_context_inner(
             }
             (2, _) => {
                 if egl_version < (1, 3) {
-                    panic!("OpenglES 2.* is not supported on EGL Versions lower then 1.3");
+                    return Err(Error::Egl(
+                        "OpenGLES 2.* is not supported on EGL versions lower than 1.3".to_string(),
+                    ));
                 }
                 trace!("Setting RENDERABLE_TYPE to OPENGL_ES2");
                 out.push(ffi::egl::RENDERABLE_TYPE as c_int);
@@ -285,13 +494,13 @@ unsafe fn create_context_inner(
         num_configs.as_mut_ptr(),
     ) == 0
     {
-        panic!("Config failed");
+        return Err(Error::Egl("eglChooseConfig failed".to_string()));
     }
     let config_id = config_id.assume_init();
     let num_configs = num_configs.assume_init();
 
     if num_configs == 0 {
-        panic!("No matching color format found");
+        return Err(Error::Egl("no matching color format found".to_string()));
     }
 
     // analyzing each config
@@ -305,7 +514,7 @@ unsafe fn create_context_inner(
                 value.as_mut_ptr(),
             );
             if res == 0 {
-                panic!("Config failed");
+                return Err(Error::Egl("eglGetConfigAttrib failed".to_string()));
             }
             value.assume_init()
         }};
@@ -362,8 +571,17 @@ unsafe fn create_context_inner(
 
     if context.is_null() {
         match ffi::egl::GetError() as u32 {
-            ffi::egl::BAD_ATTRIBUTE => panic!("Creation failed"),
-            err_no => panic!("Unknown error {}", err_no),
+            ffi::egl::BAD_ATTRIBUTE => {
+                return Err(Error::Egl(
+                    "context creation failed with bad attribute".to_string(),
+                ))
+            }
+            err_no => {
+                return Err(Error::Egl(format!(
+                    "context creation failed (eglGetError returned 0x{:x})",
+                    err_no
+                )))
+            }
         }
     }
     trace!("EGL context successfully created");
@@ -390,13 +608,13 @@ unsafe fn create_context_inner(
     };
     info!("EGL context created");
 
-    WrappedContext {
+    Ok(WrappedContext {
         display,
         context,
         surface_attributes,
         config_id,
         pixel_format: desc,
-    }
+    })
 }
 
 pub struct WrappedSurface<N: native::NativeSurface> {
@@ -432,7 +650,7 @@ impl<N: native::NativeSurface> WrappedSurface<N> {
         native: N,
         config_id: ffi::egl::types::EGLConfig,
         surface_attributes: &Vec<c_int>,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         let surface = unsafe {
             ffi::egl::CreateWindowSurface(
                 display,
@@ -443,17 +661,20 @@ impl<N: native::NativeSurface> WrappedSurface<N> {
         };
 
         if surface.is_null() {
-            panic!("Surface creation failed");
+            return Err(Error::Egl(format!(
+                "surface creation failed (eglGetError returned 0x{:x})",
+                unsafe { ffi::egl::GetError() }
+            )));
         }
 
-        WrappedSurface {
+        Ok(WrappedSurface {
             context,
             display,
             native,
             surface: Cell::new(surface),
             config_id,
             surface_attributes: surface_attributes.clone(),
-        }
+        })
     }
 
     pub fn swap_buffers(&self) -> ::std::result::Result<(), SwapBuffersError> {
@@ -522,3 +743,87 @@ impl<N: native::NativeSurface> Drop for WrappedSurface<N> {
         }
     }
 }
+
+/// An offscreen surface, for outputs without a display.
+pub struct WrappedPbuffer {
+    context: ffi::egl::types::EGLContext,
+    display: ffi::egl::types::EGLDisplay,
+    surface: ffi::egl::types::EGLSurface,
+    size: (u32, u32),
+}
+
+unsafe impl Send for WrappedPbuffer {}
+
+unsafe impl Sync for WrappedPbuffer {}
+
+impl WrappedPbuffer {
+    fn create(
+        context: ffi::egl::types::EGLContext,
+        display: ffi::egl::types::EGLDisplay,
+        config_id: ffi::egl::types::EGLConfig,
+        size: (u32, u32),
+    ) -> Result<Self, Error> {
+        let attributes = [
+            ffi::egl::WIDTH as c_int,
+            size.0 as c_int,
+            ffi::egl::HEIGHT as c_int,
+            size.1 as c_int,
+            ffi::egl::NONE as c_int,
+        ];
+        let surface =
+            unsafe { ffi::egl::CreatePbufferSurface(display, config_id, attributes.as_ptr()) };
+
+        if surface.is_null() {
+            return Err(Error::Egl(format!(
+                "pbuffer creation failed (eglGetError returned 0x{:x})",
+                unsafe { ffi::egl::GetError() }
+            )));
+        }
+
+        Ok(WrappedPbuffer {
+            context,
+            display,
+            surface,
+            size,
+        })
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    /// Finishes the frame. Pbuffers are single buffered, so the frame stays in place afterwards.
+    pub fn swap_buffers(&self) -> ::std::result::Result<(), SwapBuffersError> {
+        let ret = unsafe { ffi::egl::SwapBuffers(self.display, self.surface) };
+
+        if ret == 0 {
+            match unsafe { ffi::egl::GetError() } as u32 {
+                ffi::egl::CONTEXT_LOST => Err(SwapBuffersError::ContextLost),
+                err => Err(SwapBuffersError::Unknown(err)),
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    pub unsafe fn make_current(&self) -> ::std::result::Result<(), SwapBuffersError> {
+        let ret = ffi::egl::MakeCurrent(self.display, self.surface, self.surface, self.context);
+
+        if ret == 0 {
+            match ffi::egl::GetError() as u32 {
+                ffi::egl::CONTEXT_LOST => Err(SwapBuffersError::ContextLost),
+                err => Err(SwapBuffersError::Unknown(err)),
+            }
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for WrappedPbuffer {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::egl::DestroySurface(self.display, self.surface);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::Error as IoError;
+
+/// Errors which can occur while setting up backends and outputs.
+#[derive(Debug)]
+pub enum Error {
+    /// No session could be opened, neither through logind nor directly on the tty.
+    NoSession,
+    /// The udev device monitor could not be created.
+    Udev(IoError),
+    /// The GPUs of the seat could not be listed.
+    GpuEnumeration(IoError),
+    /// Libinput could not be assigned to the session's seat.
+    SeatAssignment(String),
+    /// A backend could not be bound to the event loop.
+    EventSource(IoError),
+    /// The winit window could not be created.
+    Window(String),
+    /// The winit event loop thread could not be started.
+    EventLoop(String),
+    /// An EGL call failed while setting up a context or surface.
+    Egl(String),
+    /// The backend's context could not be made current.
+    BackendContext,
+    /// The flutter engine failed to be created or started.
+    Engine(String),
+    /// The output's platform thread panicked during setup.
+    OutputThread,
+    /// The display mode could not be changed.
+    Mode(String),
+    /// A frame could not be captured or encoded.
+    Capture(String),
+    /// A keyboard layout could not be compiled into a keymap.
+    Keymap(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoSession => write!(f, "failed to open a session"),
+            Error::Udev(err) => write!(f, "failed to monitor udev devices: {}", err),
+            Error::GpuEnumeration(err) => write!(f, "failed to list GPUs: {}", err),
+            Error::SeatAssignment(seat) => write!(f, "failed to assign libinput to seat {}", seat),
+            Error::EventSource(err) => write!(f, "failed to bind event source: {}", err),
+            Error::Window(err) => write!(f, "failed to create window: {}", err),
+            Error::EventLoop(err) => write!(f, "failed to start the winit event loop: {}", err),
+            Error::Egl(err) => write!(f, "EGL error: {}", err),
+            Error::BackendContext => write!(f, "failed to make the backend context current"),
+            Error::Engine(err) => write!(f, "flutter engine error: {}", err),
+            Error::OutputThread => write!(f, "output thread panicked during setup"),
+            Error::Mode(err) => write!(f, "failed to change mode: {}", err),
+            Error::Capture(err) => write!(f, "failed to capture frame: {}", err),
+            Error::Keymap(config) => write!(f, "failed to compile keymap for {}", config),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Udev(err) | Error::GpuEnumeration(err) | Error::EventSource(err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,742 @@
+use core::{mem, ptr};
+use smithay::backend::egl::ffi;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_void};
+use std::time::Duration;
+
+#[allow(non_camel_case_types)]
+pub mod types {
+    pub type GLenum = u32;
+    pub type GLboolean = u8;
+    pub type GLbitfield = u32;
+    pub type GLint = i32;
+    pub type GLuint = u32;
+    pub type GLsizei = i32;
+    pub type GLfloat = f32;
+    pub type GLchar = std::os::raw::c_char;
+    pub type GLuint64 = u64;
+    pub type GLsync = *const std::os::raw::c_void;
+}
+
+use self::types::*;
+
+pub const FALSE: GLboolean = 0;
+pub const TRIANGLE_STRIP: GLenum = 0x0005;
+pub const ONE: GLenum = 1;
+pub const ONE_MINUS_SRC_ALPHA: GLenum = 0x0303;
+pub const BLEND: GLenum = 0x0BE2;
+pub const SCISSOR_TEST: GLenum = 0x0C11;
+pub const VIEWPORT: GLenum = 0x0BA2;
+pub const BLEND_SRC_RGB: GLenum = 0x80C9;
+pub const BLEND_DST_RGB: GLenum = 0x80C8;
+pub const BLEND_SRC_ALPHA: GLenum = 0x80CB;
+pub const BLEND_DST_ALPHA: GLenum = 0x80CA;
+pub const TEXTURE_2D: GLenum = 0x0DE1;
+pub const TEXTURE_BINDING_2D: GLenum = 0x8069;
+pub const TEXTURE0: GLenum = 0x84C0;
+pub const ACTIVE_TEXTURE: GLenum = 0x84E0;
+pub const TEXTURE_MAG_FILTER: GLenum = 0x2800;
+pub const TEXTURE_MIN_FILTER: GLenum = 0x2801;
+pub const TEXTURE_WRAP_S: GLenum = 0x2802;
+pub const TEXTURE_WRAP_T: GLenum = 0x2803;
+pub const NEAREST: GLint = 0x2600;
+pub const LINEAR: GLint = 0x2601;
+pub const CLAMP_TO_EDGE: GLint = 0x812F;
+pub const RGBA: GLenum = 0x1908;
+pub const UNSIGNED_BYTE: GLenum = 0x1401;
+pub const FLOAT: GLenum = 0x1406;
+pub const FRAGMENT_SHADER: GLenum = 0x8B30;
+pub const VERTEX_SHADER: GLenum = 0x8B31;
+pub const COMPILE_STATUS: GLenum = 0x8B81;
+pub const LINK_STATUS: GLenum = 0x8B82;
+pub const INFO_LOG_LENGTH: GLenum = 0x8B84;
+pub const CURRENT_PROGRAM: GLenum = 0x8B8D;
+pub const ARRAY_BUFFER: GLenum = 0x8892;
+pub const ARRAY_BUFFER_BINDING: GLenum = 0x8894;
+pub const FRAMEBUFFER: GLenum = 0x8D40;
+pub const FRAMEBUFFER_BINDING: GLenum = 0x8CA6;
+pub const FRAMEBUFFER_COMPLETE: GLenum = 0x8CD5;
+pub const COLOR_ATTACHMENT0: GLenum = 0x8CE0;
+pub const STENCIL_ATTACHMENT: GLenum = 0x8D20;
+pub const RENDERBUFFER: GLenum = 0x8D41;
+pub const RENDERBUFFER_BINDING: GLenum = 0x8CA7;
+pub const STENCIL_INDEX8: GLenum = 0x8D48;
+pub const SYNC_GPU_COMMANDS_COMPLETE: GLenum = 0x9117;
+pub const ALREADY_SIGNALED: GLenum = 0x911A;
+pub const CONDITION_SATISFIED: GLenum = 0x911C;
+
+macro_rules! gl_functions {
+    (
+        $(#[$meta:meta])*
+        pub struct $gl:ident;
+        $($name:ident = $symbol:expr => fn($($arg:ty),*) $(-> $ret:ty)?;)*
+    ) => {
+        $(#[$meta])*
+        pub struct $gl {
+            $(pub $name: unsafe extern "system" fn($($arg),*) $(-> $ret)?,)*
+        }
+
+        impl $gl {
+            /// Loads all functions through EGL. Requires an initialized EGL display.
+            pub unsafe fn load() -> Result<Self, String> {
+                Ok($gl {
+                    $($name: {
+                        let symbol = concat!($symbol, "\0");
+                        let addr = ffi::egl::GetProcAddress(symbol.as_ptr() as *const c_char);
+                        if (addr as *const c_void).is_null() {
+                            return Err(format!("Missing GL function {}", $symbol));
+                        }
+                        mem::transmute(addr)
+                    },)*
+                })
+            }
+        }
+    };
+}
+
+gl_functions! {
+    /// Function pointers for the subset of OpenGL ES 2.0 used by the embedder itself.
+    pub struct Gl;
+
+    active_texture = "glActiveTexture" => fn(GLenum);
+    attach_shader = "glAttachShader" => fn(GLuint, GLuint);
+    bind_buffer = "glBindBuffer" => fn(GLenum, GLuint);
+    bind_framebuffer = "glBindFramebuffer" => fn(GLenum, GLuint);
+    bind_renderbuffer = "glBindRenderbuffer" => fn(GLenum, GLuint);
+    bind_texture = "glBindTexture" => fn(GLenum, GLuint);
+    blend_func_separate = "glBlendFuncSeparate" => fn(GLenum, GLenum, GLenum, GLenum);
+    check_framebuffer_status = "glCheckFramebufferStatus" => fn(GLenum) -> GLenum;
+    compile_shader = "glCompileShader" => fn(GLuint);
+    create_program = "glCreateProgram" => fn() -> GLuint;
+    create_shader = "glCreateShader" => fn(GLenum) -> GLuint;
+    delete_framebuffers = "glDeleteFramebuffers" => fn(GLsizei, *const GLuint);
+    delete_program = "glDeleteProgram" => fn(GLuint);
+    delete_renderbuffers = "glDeleteRenderbuffers" => fn(GLsizei, *const GLuint);
+    delete_shader = "glDeleteShader" => fn(GLuint);
+    delete_textures = "glDeleteTextures" => fn(GLsizei, *const GLuint);
+    disable = "glDisable" => fn(GLenum);
+    disable_vertex_attrib_array = "glDisableVertexAttribArray" => fn(GLuint);
+    draw_arrays = "glDrawArrays" => fn(GLenum, GLint, GLsizei);
+    enable = "glEnable" => fn(GLenum);
+    enable_vertex_attrib_array = "glEnableVertexAttribArray" => fn(GLuint);
+    flush = "glFlush" => fn();
+    framebuffer_renderbuffer = "glFramebufferRenderbuffer" =>
+        fn(GLenum, GLenum, GLenum, GLuint);
+    framebuffer_texture_2d = "glFramebufferTexture2D" =>
+        fn(GLenum, GLenum, GLenum, GLuint, GLint);
+    gen_framebuffers = "glGenFramebuffers" => fn(GLsizei, *mut GLuint);
+    gen_renderbuffers = "glGenRenderbuffers" => fn(GLsizei, *mut GLuint);
+    gen_textures = "glGenTextures" => fn(GLsizei, *mut GLuint);
+    get_attrib_location = "glGetAttribLocation" => fn(GLuint, *const GLchar) -> GLint;
+    get_integerv = "glGetIntegerv" => fn(GLenum, *mut GLint);
+    get_program_info_log = "glGetProgramInfoLog" =>
+        fn(GLuint, GLsizei, *mut GLsizei, *mut GLchar);
+    get_programiv = "glGetProgramiv" => fn(GLuint, GLenum, *mut GLint);
+    get_shader_info_log = "glGetShaderInfoLog" =>
+        fn(GLuint, GLsizei, *mut GLsizei, *mut GLchar);
+    get_shaderiv = "glGetShaderiv" => fn(GLuint, GLenum, *mut GLint);
+    get_uniform_location = "glGetUniformLocation" => fn(GLuint, *const GLchar) -> GLint;
+    is_enabled = "glIsEnabled" => fn(GLenum) -> GLboolean;
+    link_program = "glLinkProgram" => fn(GLuint);
+    read_pixels = "glReadPixels" =>
+        fn(GLint, GLint, GLsizei, GLsizei, GLenum, GLenum, *mut c_void);
+    renderbuffer_storage = "glRenderbufferStorage" => fn(GLenum, GLenum, GLsizei, GLsizei);
+    shader_source = "glShaderSource" =>
+        fn(GLuint, GLsizei, *const *const GLchar, *const GLint);
+    tex_image_2d = "glTexImage2D" =>
+        fn(GLenum, GLint, GLint, GLsizei, GLsizei, GLint, GLenum, GLenum, *const c_void);
+    tex_parameteri = "glTexParameteri" => fn(GLenum, GLenum, GLint);
+    uniform1i = "glUniform1i" => fn(GLint, GLint);
+    uniform4f = "glUniform4f" => fn(GLint, GLfloat, GLfloat, GLfloat, GLfloat);
+    use_program = "glUseProgram" => fn(GLuint);
+    vertex_attrib_pointer = "glVertexAttribPointer" =>
+        fn(GLuint, GLint, GLenum, GLboolean, GLsizei, *const c_void);
+    viewport = "glViewport" => fn(GLint, GLint, GLsizei, GLsizei);
+}
+
+gl_functions! {
+    /// Sync objects from OpenGL ES 3.0, which are shared between contexts.
+    #[derive(Clone)]
+    pub struct GlSync;
+
+    client_wait_sync = "glClientWaitSync" => fn(GLsync, GLbitfield, GLuint64) -> GLenum;
+    delete_sync = "glDeleteSync" => fn(GLsync);
+    fence_sync = "glFenceSync" => fn(GLenum, GLbitfield) -> GLsync;
+}
+
+/// Signalled once the GPU has finished the commands issued before it was inserted, which lets
+/// another context sharing its objects wait for them.
+pub struct Fence {
+    sync: GlSync,
+    fence: GLsync,
+}
+
+// The sync object is shared by every context in the share group, not owned by a thread
+unsafe impl Send for Fence {}
+
+impl Fence {
+    /// Waits for the fence to be signalled. Returns false if it timed out or waiting failed.
+    pub unsafe fn wait(&self, timeout: Duration) -> bool {
+        let result = (self.sync.client_wait_sync)(self.fence, 0, timeout.as_nanos() as GLuint64);
+        result == ALREADY_SIGNALED || result == CONDITION_SATISFIED
+    }
+
+    /// Deletes the fence, which needs a context of the share group to be current.
+    pub unsafe fn delete(self) {
+        (self.sync.delete_sync)(self.fence);
+    }
+}
+
+impl Gl {
+    unsafe fn get_integer(&self, name: GLenum) -> GLint {
+        let mut value = 0;
+        (self.get_integerv)(name, &mut value);
+        value
+    }
+
+    unsafe fn build_shader(&self, kind: GLenum, source: &str) -> Result<GLuint, String> {
+        let shader = (self.create_shader)(kind);
+        let source = CString::new(source).unwrap();
+        (self.shader_source)(shader, 1, &source.as_ptr(), ptr::null());
+        (self.compile_shader)(shader);
+
+        let mut status = 0;
+        (self.get_shaderiv)(shader, COMPILE_STATUS, &mut status);
+        if status == 0 {
+            let mut length = 0;
+            (self.get_shaderiv)(shader, INFO_LOG_LENGTH, &mut length);
+            let mut log = vec![0u8; length.max(1) as usize];
+            (self.get_shader_info_log)(
+                shader,
+                log.len() as GLsizei,
+                ptr::null_mut(),
+                log.as_mut_ptr() as *mut GLchar,
+            );
+            (self.delete_shader)(shader);
+            return Err(String::from_utf8_lossy(&log).into_owned());
+        }
+
+        Ok(shader)
+    }
+
+    pub unsafe fn build_program(
+        &self,
+        vertex_source: &str,
+        fragment_source: &str,
+    ) -> Result<GLuint, String> {
+        let vertex = self.build_shader(VERTEX_SHADER, vertex_source)?;
+        let fragment = match self.build_shader(FRAGMENT_SHADER, fragment_source) {
+            Ok(fragment) => fragment,
+            Err(err) => {
+                (self.delete_shader)(vertex);
+                return Err(err);
+            }
+        };
+
+        let program = (self.create_program)();
+        (self.attach_shader)(program, vertex);
+        (self.attach_shader)(program, fragment);
+        (self.link_program)(program);
+        (self.delete_shader)(vertex);
+        (self.delete_shader)(fragment);
+
+        let mut status = 0;
+        (self.get_programiv)(program, LINK_STATUS, &mut status);
+        if status == 0 {
+            let mut length = 0;
+            (self.get_programiv)(program, INFO_LOG_LENGTH, &mut length);
+            let mut log = vec![0u8; length.max(1) as usize];
+            (self.get_program_info_log)(
+                program,
+                log.len() as GLsizei,
+                ptr::null_mut(),
+                log.as_mut_ptr() as *mut GLchar,
+            );
+            (self.delete_program)(program);
+            return Err(String::from_utf8_lossy(&log).into_owned());
+        }
+
+        Ok(program)
+    }
+}
+
+/// Snapshot of the GL state touched by `TextureRenderer` and `OffscreenTarget`.
+///
+/// Flutter does not expect anybody else to use its context, so everything we change in between
+/// its frames must be put back afterwards.
+struct SavedState {
+    program: GLint,
+    array_buffer: GLint,
+    framebuffer: GLint,
+    renderbuffer: GLint,
+    active_texture: GLint,
+    texture: GLint,
+    viewport: [GLint; 4],
+    blend: GLboolean,
+    scissor: GLboolean,
+    blend_func: [GLint; 4],
+}
+
+impl SavedState {
+    unsafe fn save(gl: &Gl) -> Self {
+        let mut viewport = [0; 4];
+        (gl.get_integerv)(VIEWPORT, viewport.as_mut_ptr());
+
+        Self {
+            program: gl.get_integer(CURRENT_PROGRAM),
+            array_buffer: gl.get_integer(ARRAY_BUFFER_BINDING),
+            framebuffer: gl.get_integer(FRAMEBUFFER_BINDING),
+            renderbuffer: gl.get_integer(RENDERBUFFER_BINDING),
+            active_texture: gl.get_integer(ACTIVE_TEXTURE),
+            texture: {
+                (gl.active_texture)(TEXTURE0);
+                gl.get_integer(TEXTURE_BINDING_2D)
+            },
+            viewport,
+            blend: (gl.is_enabled)(BLEND),
+            scissor: (gl.is_enabled)(SCISSOR_TEST),
+            blend_func: [
+                gl.get_integer(BLEND_SRC_RGB),
+                gl.get_integer(BLEND_DST_RGB),
+                gl.get_integer(BLEND_SRC_ALPHA),
+                gl.get_integer(BLEND_DST_ALPHA),
+            ],
+        }
+    }
+
+    unsafe fn restore(&self, gl: &Gl) {
+        (gl.use_program)(self.program as GLuint);
+        (gl.bind_buffer)(ARRAY_BUFFER, self.array_buffer as GLuint);
+        (gl.bind_framebuffer)(FRAMEBUFFER, self.framebuffer as GLuint);
+        (gl.bind_renderbuffer)(RENDERBUFFER, self.renderbuffer as GLuint);
+        (gl.bind_texture)(TEXTURE_2D, self.texture as GLuint);
+        (gl.active_texture)(self.active_texture as GLenum);
+        (gl.viewport)(
+            self.viewport[0],
+            self.viewport[1],
+            self.viewport[2],
+            self.viewport[3],
+        );
+        set_enabled(gl, BLEND, self.blend);
+        set_enabled(gl, SCISSOR_TEST, self.scissor);
+        (gl.blend_func_separate)(
+            self.blend_func[0] as GLenum,
+            self.blend_func[1] as GLenum,
+            self.blend_func[2] as GLenum,
+            self.blend_func[3] as GLenum,
+        );
+    }
+}
+
+unsafe fn set_enabled(gl: &Gl, cap: GLenum, enabled: GLboolean) {
+    if enabled == FALSE {
+        (gl.disable)(cap);
+    } else {
+        (gl.enable)(cap);
+    }
+}
+
+/// Reads back the current surface's default framebuffer as tightly packed RGBA pixels, top row
+/// first.
+pub unsafe fn read_framebuffer(size: (u32, u32)) -> Result<Vec<u8>, String> {
+    let gl = Gl::load()?;
+    let saved = SavedState::save(&gl);
+
+    (gl.bind_framebuffer)(FRAMEBUFFER, 0);
+    let pixels = read_pixels(&gl, size);
+
+    saved.restore(&gl);
+    Ok(pixels)
+}
+
+/// Reads back a texture of the current context's share group as tightly packed RGBA pixels, top
+/// row first.
+pub unsafe fn read_texture(texture: GLuint, size: (u32, u32)) -> Result<Vec<u8>, String> {
+    let gl = Gl::load()?;
+    let saved = SavedState::save(&gl);
+
+    // Framebuffers aren't shared between contexts, so the texture needs one of our own
+    let mut framebuffer = 0;
+    (gl.gen_framebuffers)(1, &mut framebuffer);
+    (gl.bind_framebuffer)(FRAMEBUFFER, framebuffer);
+    (gl.framebuffer_texture_2d)(FRAMEBUFFER, COLOR_ATTACHMENT0, TEXTURE_2D, texture, 0);
+    let status = (gl.check_framebuffer_status)(FRAMEBUFFER);
+    let pixels = if status == FRAMEBUFFER_COMPLETE {
+        Ok(read_pixels(&gl, size))
+    } else {
+        Err(format!("Framebuffer incomplete: {:#x}", status))
+    };
+
+    saved.restore(&gl);
+    (gl.delete_framebuffers)(1, &framebuffer);
+    pixels
+}
+
+/// Reads the bound framebuffer, flipping the rows which GL starts at the bottom.
+unsafe fn read_pixels(gl: &Gl, size: (u32, u32)) -> Vec<u8> {
+    let (width, height) = (size.0 as usize, size.1 as usize);
+    let mut pixels = vec![0u8; width * height * 4];
+    (gl.read_pixels)(
+        0,
+        0,
+        width as GLsizei,
+        height as GLsizei,
+        RGBA,
+        UNSIGNED_BYTE,
+        pixels.as_mut_ptr() as *mut c_void,
+    );
+
+    let stride = width * 4;
+    let mut flipped = Vec::with_capacity(pixels.len());
+    for row in pixels.chunks(stride.max(1)).rev() {
+        flipped.extend_from_slice(row);
+    }
+    flipped
+}
+
+const VERTEX_SHADER_SOURCE: &str = r#"
+attribute vec2 position;
+uniform vec4 rect;
+varying vec2 tex_coord;
+
+void main() {
+    gl_Position = vec4(rect.xy + position * rect.zw, 0.0, 1.0);
+    tex_coord = position;
+}
+"#;
+
+const FRAGMENT_SHADER_SOURCE: &str = r#"
+precision mediump float;
+uniform sampler2D tex;
+varying vec2 tex_coord;
+
+void main() {
+    gl_FragColor = texture2D(tex, tex_coord);
+}
+"#;
+
+const QUAD: [GLfloat; 8] = [0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+
+/// Draws RGBA textures on top of the current framebuffer, e.g. a software cursor.
+pub struct TextureRenderer {
+    gl: Gl,
+    program: GLuint,
+    position_attrib: GLuint,
+    rect_uniform: GLint,
+    tex_uniform: GLint,
+    texture: GLuint,
+    texture_size: (u32, u32),
+}
+
+impl TextureRenderer {
+    /// Creates the renderer using the current context.
+    pub unsafe fn new() -> Result<Self, String> {
+        let gl = Gl::load()?;
+        let program = gl.build_program(VERTEX_SHADER_SOURCE, FRAGMENT_SHADER_SOURCE)?;
+
+        let position_attrib =
+            (gl.get_attrib_location)(program, "position\0".as_ptr() as *const GLchar);
+        let rect_uniform = (gl.get_uniform_location)(program, "rect\0".as_ptr() as *const GLchar);
+        let tex_uniform = (gl.get_uniform_location)(program, "tex\0".as_ptr() as *const GLchar);
+
+        let mut texture = 0;
+        (gl.gen_textures)(1, &mut texture);
+
+        Ok(Self {
+            gl,
+            program,
+            position_attrib: position_attrib as GLuint,
+            rect_uniform,
+            tex_uniform,
+            texture,
+            texture_size: (0, 0),
+        })
+    }
+
+    /// Replaces the texture contents with the given tightly packed RGBA pixels.
+    pub unsafe fn upload(&mut self, width: u32, height: u32, rgba: &[u8]) {
+        let gl = &self.gl;
+        let saved = SavedState::save(gl);
+
+        (gl.bind_texture)(TEXTURE_2D, self.texture);
+        (gl.tex_parameteri)(TEXTURE_2D, TEXTURE_MIN_FILTER, NEAREST);
+        (gl.tex_parameteri)(TEXTURE_2D, TEXTURE_MAG_FILTER, NEAREST);
+        (gl.tex_parameteri)(TEXTURE_2D, TEXTURE_WRAP_S, CLAMP_TO_EDGE);
+        (gl.tex_parameteri)(TEXTURE_2D, TEXTURE_WRAP_T, CLAMP_TO_EDGE);
+        (gl.tex_image_2d)(
+            TEXTURE_2D,
+            0,
+            RGBA as GLint,
+            width as GLsizei,
+            height as GLsizei,
+            0,
+            RGBA,
+            UNSIGNED_BYTE,
+            rgba.as_ptr() as *const c_void,
+        );
+        self.texture_size = (width, height);
+
+        saved.restore(gl);
+    }
+
+    /// Draws the texture with premultiplied alpha blending onto the default framebuffer.
+    ///
+    /// `position` is the top left corner in pixels, relative to the top left of a framebuffer of
+    /// the given size.
+    pub unsafe fn draw(&self, framebuffer: (u32, u32), position: (f64, f64)) {
+        if self.texture_size == (0, 0) {
+            return;
+        }
+
+        let gl = &self.gl;
+        let saved = SavedState::save(gl);
+
+        let (fb_width, fb_height) = (framebuffer.0 as f32, framebuffer.1 as f32);
+        let (width, height) = (self.texture_size.0 as f32, self.texture_size.1 as f32);
+
+        // Convert into normalized device coordinates, which have y pointing up
+        let x = 2.0 * position.0 as f32 / fb_width - 1.0;
+        let y = 1.0 - 2.0 * position.1 as f32 / fb_height;
+        let w = 2.0 * width / fb_width;
+        let h = -2.0 * height / fb_height;
+
+        (gl.bind_framebuffer)(FRAMEBUFFER, 0);
+        (gl.bind_buffer)(ARRAY_BUFFER, 0);
+        (gl.viewport)(0, 0, framebuffer.0 as GLsizei, framebuffer.1 as GLsizei);
+        (gl.disable)(SCISSOR_TEST);
+        (gl.enable)(BLEND);
+        (gl.blend_func_separate)(ONE, ONE_MINUS_SRC_ALPHA, ONE, ONE_MINUS_SRC_ALPHA);
+
+        (gl.use_program)(self.program);
+        (gl.active_texture)(TEXTURE0);
+        (gl.bind_texture)(TEXTURE_2D, self.texture);
+        (gl.uniform1i)(self.tex_uniform, 0);
+        (gl.uniform4f)(self.rect_uniform, x, y, w, h);
+
+        (gl.enable_vertex_attrib_array)(self.position_attrib);
+        (gl.vertex_attrib_pointer)(
+            self.position_attrib,
+            2,
+            FLOAT,
+            FALSE,
+            0,
+            QUAD.as_ptr() as *const c_void,
+        );
+        (gl.draw_arrays)(TRIANGLE_STRIP, 0, 4);
+        (gl.disable_vertex_attrib_array)(self.position_attrib);
+
+        saved.restore(gl);
+    }
+}
+
+impl Drop for TextureRenderer {
+    fn drop(&mut self) {
+        // Only valid while our context is current, which is the case on the render thread
+        unsafe {
+            (self.gl.delete_textures)(1, &self.texture);
+            (self.gl.delete_program)(self.program);
+        }
+    }
+}
+
+const BLIT_VERTEX_SHADER_SOURCE: &str = r#"
+attribute vec2 position;
+attribute vec2 source_coord;
+varying vec2 tex_coord;
+
+void main() {
+    gl_Position = vec4(position, 0.0, 1.0);
+    tex_coord = source_coord;
+}
+"#;
+
+/// Covers the whole viewport, as bottom left, bottom right, top left and top right corners.
+const FULLSCREEN_QUAD: [GLfloat; 8] = [-1.0, -1.0, 1.0, -1.0, -1.0, 1.0, 1.0, 1.0];
+
+/// A framebuffer backed by a colour texture and a stencil buffer, which flutter renders into
+/// instead of the default framebuffer so the frame can be processed before it is presented.
+pub struct OffscreenTarget {
+    gl: Gl,
+    sync: Option<GlSync>,
+    program: GLuint,
+    position_attrib: GLuint,
+    source_coord_attrib: GLuint,
+    tex_uniform: GLint,
+    framebuffer: GLuint,
+    texture: GLuint,
+    stencil: GLuint,
+    size: (u32, u32),
+}
+
+impl OffscreenTarget {
+    /// Creates the target using the current context. It has no storage until `prepare` is called.
+    pub unsafe fn new() -> Result<Self, String> {
+        let gl = Gl::load()?;
+        let program = gl.build_program(BLIT_VERTEX_SHADER_SOURCE, FRAGMENT_SHADER_SOURCE)?;
+
+        let position_attrib =
+            (gl.get_attrib_location)(program, "position\0".as_ptr() as *const GLchar);
+        let source_coord_attrib =
+            (gl.get_attrib_location)(program, "source_coord\0".as_ptr() as *const GLchar);
+        let tex_uniform = (gl.get_uniform_location)(program, "tex\0".as_ptr() as *const GLchar);
+
+        let mut framebuffer = 0;
+        (gl.gen_framebuffers)(1, &mut framebuffer);
+        let mut texture = 0;
+        (gl.gen_textures)(1, &mut texture);
+        let mut stencil = 0;
+        (gl.gen_renderbuffers)(1, &mut stencil);
+
+        Ok(Self {
+            gl,
+            // Only needed to read frames from another context
+            sync: GlSync::load().ok(),
+            program,
+            position_attrib: position_attrib as GLuint,
+            source_coord_attrib: source_coord_attrib as GLuint,
+            tex_uniform,
+            framebuffer,
+            texture,
+            stencil,
+            size: (0, 0),
+        })
+    }
+
+    /// Size of the colour texture in pixels.
+    pub fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    /// The colour texture, which keeps the last frame until flutter draws the next one.
+    pub fn texture(&self) -> GLuint {
+        self.texture
+    }
+
+    /// Inserts a fence after the commands issued so far, such as the last `blit`, and flushes
+    /// them.
+    pub unsafe fn fence(&self) -> Result<Fence, String> {
+        let sync = match self.sync.clone() {
+            Some(sync) => sync,
+            None => return Err("Sync objects are not supported".to_string()),
+        };
+        let fence = (sync.fence_sync)(SYNC_GPU_COMMANDS_COMPLETE, 0);
+        if fence.is_null() {
+            return Err("Failed to create fence".to_string());
+        }
+        // Other contexts can only see the fence signalled once it has been flushed
+        (self.gl.flush)();
+        Ok(Fence { sync, fence })
+    }
+
+    /// Makes sure the buffers have the given size, reallocating them if the size changed, and
+    /// returns the framebuffer to render into.
+    pub unsafe fn prepare(&mut self, size: (u32, u32)) -> Result<GLuint, String> {
+        if self.size == size {
+            return Ok(self.framebuffer);
+        }
+
+        let gl = &self.gl;
+        let saved = SavedState::save(gl);
+
+        // Linear filtering smooths the frame while it is scaled to a new output size
+        (gl.bind_texture)(TEXTURE_2D, self.texture);
+        (gl.tex_parameteri)(TEXTURE_2D, TEXTURE_MIN_FILTER, LINEAR);
+        (gl.tex_parameteri)(TEXTURE_2D, TEXTURE_MAG_FILTER, LINEAR);
+        (gl.tex_parameteri)(TEXTURE_2D, TEXTURE_WRAP_S, CLAMP_TO_EDGE);
+        (gl.tex_parameteri)(TEXTURE_2D, TEXTURE_WRAP_T, CLAMP_TO_EDGE);
+        (gl.tex_image_2d)(
+            TEXTURE_2D,
+            0,
+            RGBA as GLint,
+            size.0 as GLsizei,
+            size.1 as GLsizei,
+            0,
+            RGBA,
+            UNSIGNED_BYTE,
+            ptr::null(),
+        );
+
+        // Skia clips complex paths with the stencil buffer
+        (gl.bind_renderbuffer)(RENDERBUFFER, self.stencil);
+        (gl.renderbuffer_storage)(
+            RENDERBUFFER,
+            STENCIL_INDEX8,
+            size.0 as GLsizei,
+            size.1 as GLsizei,
+        );
+
+        (gl.bind_framebuffer)(FRAMEBUFFER, self.framebuffer);
+        (gl.framebuffer_texture_2d)(FRAMEBUFFER, COLOR_ATTACHMENT0, TEXTURE_2D, self.texture, 0);
+        (gl.framebuffer_renderbuffer)(FRAMEBUFFER, STENCIL_ATTACHMENT, RENDERBUFFER, self.stencil);
+        let status = (gl.check_framebuffer_status)(FRAMEBUFFER);
+
+        saved.restore(gl);
+
+        if status != FRAMEBUFFER_COMPLETE {
+            self.size = (0, 0);
+            return Err(format!("Framebuffer incomplete: {:#x}", status));
+        }
+        self.size = size;
+        Ok(self.framebuffer)
+    }
+
+    /// Copies the rendered frame onto the whole default framebuffer, scaling it to fit.
+    ///
+    /// `source_coords` are the texture coordinates shown at the bottom left, bottom right, top
+    /// left and top right corners of the framebuffer, allowing the frame to be rotated or flipped.
+    pub unsafe fn blit(&self, framebuffer: (u32, u32), source_coords: &[GLfloat; 8]) {
+        if self.size == (0, 0) {
+            return;
+        }
+
+        let gl = &self.gl;
+        let saved = SavedState::save(gl);
+
+        (gl.bind_framebuffer)(FRAMEBUFFER, 0);
+        (gl.bind_buffer)(ARRAY_BUFFER, 0);
+        (gl.viewport)(0, 0, framebuffer.0 as GLsizei, framebuffer.1 as GLsizei);
+        (gl.disable)(SCISSOR_TEST);
+        (gl.disable)(BLEND);
+
+        (gl.use_program)(self.program);
+        (gl.active_texture)(TEXTURE0);
+        (gl.bind_texture)(TEXTURE_2D, self.texture);
+        (gl.uniform1i)(self.tex_uniform, 0);
+
+        (gl.enable_vertex_attrib_array)(self.position_attrib);
+        (gl.vertex_attrib_pointer)(
+            self.position_attrib,
+            2,
+            FLOAT,
+            FALSE,
+            0,
+            FULLSCREEN_QUAD.as_ptr() as *const c_void,
+        );
+        (gl.enable_vertex_attrib_array)(self.source_coord_attrib);
+        (gl.vertex_attrib_pointer)(
+            self.source_coord_attrib,
+            2,
+            FLOAT,
+            FALSE,
+            0,
+            source_coords.as_ptr() as *const c_void,
+        );
+        (gl.draw_arrays)(TRIANGLE_STRIP, 0, 4);
+        (gl.disable_vertex_attrib_array)(self.source_coord_attrib);
+        (gl.disable_vertex_attrib_array)(self.position_attrib);
+
+        saved.restore(gl);
+    }
+}
+
+impl Drop for OffscreenTarget {
+    fn drop(&mut self) {
+        // Only valid while our context is current, which is the case on the render thread
+        unsafe {
+            (self.gl.delete_framebuffers)(1, &self.framebuffer);
+            (self.gl.delete_renderbuffers)(1, &self.stencil);
+            (self.gl.delete_textures)(1, &self.texture);
+            (self.gl.delete_program)(self.program);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use log::warn;
+use smithay::reexports::nix::sys::stat::dev_t;
+use smithay::reexports::udev::{Device as UdevDevice, Enumerator};
+use std::ffi::OsStr;
+use std::io::Error as IoError;
+use std::path::{Path, PathBuf};
+
+/// Seat of devices without an `ID_SEAT` udev property.
+const DEFAULT_SEAT: &str = "seat0";
+
+/// The bus a GPU is attached to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GpuBus {
+    Pci {
+        /// Address such as `0000:01:00.0`.
+        slot: String,
+        vendor: u16,
+        device: u16,
+    },
+    /// A display controller built into the SoC, as on most ARM boards.
+    Platform { name: String },
+    /// Any other bus, such as USB display adapters.
+    Other { subsystem: Option<String> },
+}
+
+/// A DRM device which can drive displays.
+#[derive(Clone, Debug)]
+pub struct GpuInfo {
+    /// Device node, such as `/dev/dri/card0`.
+    pub path: PathBuf,
+    /// Kernel driver, such as `i915`, `amdgpu` or `vc4`.
+    pub driver: Option<String>,
+    pub bus: GpuBus,
+    /// Whether the firmware used this GPU for the boot console.
+    pub boot_vga: bool,
+    /// Whether this is the seat's primary GPU, the one the system booted with.
+    pub primary: bool,
+    pub(crate) device_id: dev_t,
+}
+
+fn to_string(value: Option<&OsStr>) -> Option<String> {
+    value.map(|value| value.to_string_lossy().into_owned())
+}
+
+fn parse_hex(value: Option<&OsStr>) -> u16 {
+    to_string(value)
+        .and_then(|value| u16::from_str_radix(value.trim().trim_start_matches("0x"), 16).ok())
+        .unwrap_or(0)
+}
+
+fn describe_gpu(device: &UdevDevice, path: &Path) -> Result<GpuInfo, IoError> {
+    let pci = device.parent_with_subsystem("pci")?;
+    let boot_vga = pci
+        .as_ref()
+        .and_then(|pci| pci.attribute_value("boot_vga"))
+        .map_or(false, |value| value == "1");
+
+    // The card is a child of the device bound to the driver
+    let parent = device.parent();
+    let bus = match (pci, parent.as_ref()) {
+        (Some(pci), _) => GpuBus::Pci {
+            slot: pci.sysname().to_string_lossy().into_owned(),
+            vendor: parse_hex(pci.attribute_value("vendor")),
+            device: parse_hex(pci.attribute_value("device")),
+        },
+        (None, Some(parent)) if parent.subsystem() == Some(OsStr::new("platform")) => {
+            GpuBus::Platform {
+                name: parent.sysname().to_string_lossy().into_owned(),
+            }
+        }
+        (None, parent) => GpuBus::Other {
+            subsystem: to_string(parent.and_then(|parent| parent.subsystem())),
+        },
+    };
+
+    Ok(GpuInfo {
+        path: path.to_path_buf(),
+        driver: to_string(parent.as_ref().and_then(|parent| parent.driver())),
+        bus,
+        boot_vga,
+        primary: false,
+        device_id: device.devnum().unwrap_or(0),
+    })
+}
+
+/// Lists the GPUs assigned to a seat, marking the primary one.
+pub(crate) fn enumerate_gpus(seat: &str) -> Result<Vec<GpuInfo>, IoError> {
+    let mut enumerator = Enumerator::new()?;
+    enumerator.match_subsystem("drm")?;
+    enumerator.match_sysname("card[0-9]*")?;
+
+    let mut gpus = Vec::new();
+    for device in enumerator.scan_devices()? {
+        let device_seat = device
+            .property_value("ID_SEAT")
+            .map(|seat| seat.to_string_lossy().into_owned())
+            .unwrap_or_else(|| DEFAULT_SEAT.to_owned());
+        if device_seat != seat {
+            continue;
+        }
+
+        let path = match device.devnode() {
+            Some(path) => path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+            None => continue,
+        };
+        // A card udev can't describe shouldn't keep the others from being used
+        match describe_gpu(&device, &path) {
+            Ok(gpu) => gpus.push(gpu),
+            Err(err) => warn!("Skipping GPU {:?}: {}", path, err),
+        }
+    }
+    gpus.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if let Some(primary) = choose_primary(&gpus) {
+        gpus[primary].primary = true;
+    }
+    Ok(gpus)
+}
+
+/// Picks the GPU the firmware booted with, or the first one if none did.
+pub(crate) fn choose_primary(gpus: &[GpuInfo]) -> Option<usize> {
+    gpus.iter()
+        .position(|gpu| gpu.boot_vga)
+        .or_else(|| gpus.first().map(|_| 0))
+}
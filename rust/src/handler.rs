@@ -1,14 +1,21 @@
 use crate::egl_util::{WrappedContext, WrappedDisplay};
-use flutter_engine::{FlutterEngineWeakRef, FlutterOpenGLHandler};
+use crate::error::Error;
+use crate::gl_util::{read_texture, Fence, OffscreenTarget};
+use flutter_engine::{
+    FlutterEngineWeakRef, FlutterOpenGLHandler, FlutterSoftwareHandler, FlutterVsyncHandler,
+};
 use std::os::raw::c_void;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::input::keyboard::KeyboardManager;
-use crate::output::FlutterOutputBackend;
+use crate::output::{CapturedFrame, FlutterOutputBackend, SoftwareOutputBackend, Transform};
+use crate::vsync::FrameClock;
 use crossbeam::sync::Unparker;
 use flutter_engine::tasks::TaskRunnerHandler;
 use flutter_plugins::textinput::TextInputHandler;
-use parking_lot::Mutex;
+use log::error;
+use parking_lot::{Condvar, Mutex};
 use smithay::backend::egl::ffi;
 
 pub(crate) struct SmithayPlatformTaskHandler {
@@ -27,10 +34,62 @@ impl TaskRunnerHandler for SmithayPlatformTaskHandler {
     }
 }
 
+pub(crate) struct SmithayVsyncHandler {
+    clock: Arc<Mutex<FrameClock>>,
+    unparker: Unparker,
+}
+
+impl SmithayVsyncHandler {
+    pub(crate) fn new(clock: Arc<Mutex<FrameClock>>, unparker: Unparker) -> Self {
+        Self { clock, unparker }
+    }
+}
+
+impl FlutterVsyncHandler for SmithayVsyncHandler {
+    fn request_frame(&mut self) {
+        // The platform thread answers once the frame may start
+        self.clock.lock().request_frame();
+        self.unparker.unpark();
+    }
+}
+
+/// Framebuffer flutter renders into when rendering offscreen, created on the render thread once
+/// flutter asks for it.
+#[derive(Default)]
+struct Offscreen {
+    target: Option<OffscreenTarget>,
+    failed: bool,
+    /// Whether flutter is drawing into the target, which then holds a partial frame.
+    drawing: bool,
+    /// Signalled once the GPU has finished the frame last presented from the target.
+    presented: Option<Fence>,
+}
+
+impl Offscreen {
+    /// Deletes the target, which needs flutter's context to be current.
+    fn discard(&mut self) {
+        self.target = None;
+        if let Some(fence) = self.presented.take() {
+            unsafe { fence.delete() };
+        }
+    }
+}
+
+/// The offscreen target, shared with the `FrameReader` of the output.
+#[derive(Default)]
+pub(crate) struct SharedOffscreen {
+    offscreen: Mutex<Offscreen>,
+    presented: Condvar,
+}
+
 pub(crate) struct SmithayOpenGLHandler {
     backend: Box<dyn FlutterOutputBackend + Send>,
     display: WrappedDisplay,
     resource_context: WrappedContext,
+    clock: Arc<Mutex<FrameClock>>,
+    transform: Transform,
+    render_offscreen: bool,
+    offscreen: Arc<SharedOffscreen>,
 }
 
 impl SmithayOpenGLHandler {
@@ -38,19 +97,60 @@ impl SmithayOpenGLHandler {
         backend: Box<dyn FlutterOutputBackend + Send>,
         display: WrappedDisplay,
         resource_context: WrappedContext,
+        clock: Arc<Mutex<FrameClock>>,
+        transform: Transform,
+        render_offscreen: bool,
+        offscreen: Arc<SharedOffscreen>,
     ) -> Self {
         Self {
             backend,
             display,
             resource_context,
+            clock,
+            transform,
+            render_offscreen,
+            offscreen,
         }
     }
 }
 
+impl Drop for SmithayOpenGLHandler {
+    fn drop(&mut self) {
+        // The frame reader must not keep the target alive past flutter's context
+        self.offscreen.offscreen.lock().discard();
+    }
+}
+
 impl FlutterOpenGLHandler for SmithayOpenGLHandler {
     fn swap_buffers(&self) -> bool {
+        let mut offscreen = self.offscreen.offscreen.lock();
+        if let Some(target) = offscreen.target.as_ref() {
+            unsafe {
+                target.blit(
+                    self.backend.get_framebuffer_dimensions(),
+                    &self.transform.source_coords(),
+                );
+
+                // Lets the frame reader wait for the frame from its own context
+                let fence = target.fence();
+                if let Some(fence) = offscreen.presented.take() {
+                    fence.delete();
+                }
+                match fence {
+                    Ok(fence) => offscreen.presented = Some(fence),
+                    Err(err) => error!("Failed to keep frame for captures: {}", err),
+                }
+            }
+        }
+        offscreen.drawing = false;
+        drop(offscreen);
+        self.offscreen.presented.notify_all();
+
         match self.backend.swap_buffers() {
-            Ok(_) => true,
+            Ok(_) => {
+                self.clock.lock().frame_presented(Instant::now());
+                true
+            }
             Err(_) => false,
         }
     }
@@ -71,7 +171,41 @@ impl FlutterOpenGLHandler for SmithayOpenGLHandler {
     }
 
     fn fbo_callback(&self) -> u32 {
-        0
+        if !self.render_offscreen {
+            return 0;
+        }
+
+        let mut offscreen = self.offscreen.offscreen.lock();
+        if offscreen.target.is_none() && !offscreen.failed {
+            match unsafe { OffscreenTarget::new() } {
+                Ok(target) => offscreen.target = Some(target),
+                Err(err) => {
+                    error!("Failed to set up offscreen rendering: {}", err);
+                    offscreen.failed = true;
+                }
+            }
+        }
+
+        // Without a target the frame is rendered directly, rather than not at all
+        let target = match offscreen.target.as_mut() {
+            Some(target) => target,
+            None => return 0,
+        };
+        let size = self
+            .transform
+            .transform_size(self.backend.get_framebuffer_dimensions());
+        match unsafe { target.prepare(size) } {
+            Ok(framebuffer) => {
+                offscreen.drawing = true;
+                framebuffer
+            }
+            Err(err) => {
+                error!("Failed to resize offscreen framebuffer: {}", err);
+                offscreen.discard();
+                offscreen.failed = true;
+                0
+            }
+        }
     }
 
     fn make_resource_current(&self) -> bool {
@@ -83,6 +217,152 @@ impl FlutterOpenGLHandler for SmithayOpenGLHandler {
     }
 }
 
+/// Copy of the last frame presented by `SmithaySoftwareHandler`, as flutter drew it.
+#[derive(Default)]
+pub(crate) struct SoftwareFrame {
+    pixels: Vec<u8>,
+    width: usize,
+    row_bytes: usize,
+    height: usize,
+}
+
+impl SoftwareFrame {
+    fn capture(&self) -> Option<CapturedFrame> {
+        if self.height == 0 {
+            return None;
+        }
+
+        // Flutter draws BGRA, captures are RGBA. Rows may be padded past the frame's width.
+        let width = self.width.min(self.row_bytes / 4);
+        let mut pixels = Vec::with_capacity(width * self.height * 4);
+        for row in self.pixels.chunks(self.row_bytes).take(self.height) {
+            for pixel in row.chunks_exact(4).take(width) {
+                pixels.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+            }
+        }
+        Some(CapturedFrame::new(width as u32, self.height as u32, pixels))
+    }
+}
+
+pub(crate) struct SmithaySoftwareHandler {
+    backend: Box<dyn SoftwareOutputBackend + Send>,
+    clock: Arc<Mutex<FrameClock>>,
+    last_frame: Arc<Mutex<SoftwareFrame>>,
+}
+
+impl SmithaySoftwareHandler {
+    pub(crate) fn new(
+        backend: Box<dyn SoftwareOutputBackend + Send>,
+        clock: Arc<Mutex<FrameClock>>,
+        last_frame: Arc<Mutex<SoftwareFrame>>,
+    ) -> Self {
+        Self {
+            backend,
+            clock,
+            last_frame,
+        }
+    }
+}
+
+impl FlutterSoftwareHandler for SmithaySoftwareHandler {
+    fn present(&self, allocation: &[u8], row_bytes: usize, height: usize) -> bool {
+        match self.backend.present(allocation, row_bytes, height) {
+            Ok(_) => {
+                self.clock.lock().frame_presented(Instant::now());
+
+                // Flutter reuses the allocation, so captures need a copy
+                let mut frame = self.last_frame.lock();
+                frame.pixels.clear();
+                frame.pixels.extend_from_slice(allocation);
+                frame.width = self.backend.get_framebuffer_dimensions().0 as usize;
+                frame.row_bytes = row_bytes;
+                frame.height = height;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Reads back the last frame an output presented, for `FlutterOutput::capture_frame`.
+pub(crate) enum FrameReader {
+    /// Reads the offscreen target through a context sharing its texture, as flutter's context
+    /// belongs to the render thread.
+    OpenGL {
+        display: WrappedDisplay,
+        context: WrappedContext,
+        offscreen: Arc<SharedOffscreen>,
+    },
+    Software(Arc<Mutex<SoftwareFrame>>),
+    /// Frames rendered straight to the display are gone once presented.
+    Unavailable,
+}
+
+impl FrameReader {
+    /// Waits up to `timeout` for flutter to finish drawing, if it is in the middle of a frame.
+    pub(crate) fn read(&self, timeout: Duration) -> Result<CapturedFrame, Error> {
+        match self {
+            FrameReader::OpenGL {
+                display,
+                context,
+                offscreen,
+            } => unsafe { read_offscreen(display, context, offscreen, timeout) },
+            FrameReader::Software(frame) => frame
+                .lock()
+                .capture()
+                .ok_or_else(|| Error::Capture("no frame was presented yet".to_string())),
+            FrameReader::Unavailable => Err(Error::Capture(
+                "frames are only kept when rendering offscreen".to_string(),
+            )),
+        }
+    }
+}
+
+unsafe fn read_offscreen(
+    display: &WrappedDisplay,
+    context: &WrappedContext,
+    shared: &SharedOffscreen,
+    timeout: Duration,
+) -> Result<CapturedFrame, Error> {
+    let deadline = Instant::now() + timeout;
+    let mut offscreen = shared.offscreen.lock();
+    while offscreen.drawing {
+        if shared
+            .presented
+            .wait_until(&mut offscreen, deadline)
+            .timed_out()
+        {
+            return Err(Error::Capture(
+                "flutter did not finish its frame".to_string(),
+            ));
+        }
+    }
+
+    // Holding the lock keeps flutter from drawing the next frame until we're done
+    let (target, fence) = match (offscreen.target.as_ref(), offscreen.presented.as_ref()) {
+        (Some(target), Some(fence)) => (target, fence),
+        _ => return Err(Error::Capture("no frame was presented yet".to_string())),
+    };
+    if !context.make_current() {
+        return Err(Error::Capture(
+            "the capture context can't be made current".to_string(),
+        ));
+    }
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let pixels = if fence.wait(remaining) {
+        read_texture(target.texture(), target.size()).map_err(Error::Capture)
+    } else {
+        Err(Error::Capture(
+            "the GPU did not finish the frame".to_string(),
+        ))
+    };
+    display.clear_current();
+
+    let (width, height) = target.size();
+    Ok(CapturedFrame::new(width, height, pixels?))
+}
+
 pub struct SmithayTextInputHandler {
     pub keyboard: Arc<Mutex<KeyboardManager>>,
     pub engine: FlutterEngineWeakRef,
@@ -0,0 +1,164 @@
+use crate::egl_util::{WrappedContext, WrappedDisplay, WrappedPbuffer};
+use crate::error::Error;
+use log::debug;
+use std::sync::Arc;
+
+use crate::input::keyboard::{KeyboardConfig, KeyboardManager};
+use crate::output::{
+    FlutterEngineOptions, FlutterOutput, FlutterOutputBackend, ModeControl, SoftwareOutputBackend,
+    Transform, VsyncMode,
+};
+use crate::EngineWeakCollection;
+use parking_lot::Mutex;
+
+/// Frame rate of headless outputs, which have no display to follow.
+const HEADLESS_REFRESH: u32 = 60;
+
+/// Renders into an EGL pbuffer, without a GPU or display server when Mesa's surfaceless platform
+/// is available.
+pub struct HeadlessOutputBackend {
+    surface: WrappedPbuffer,
+}
+
+impl FlutterOutputBackend for HeadlessOutputBackend {
+    fn swap_buffers(&self) -> Result<(), ()> {
+        self.surface.swap_buffers().map_err(|_| ())
+    }
+
+    fn make_current(&self) -> Result<(), ()> {
+        unsafe { self.surface.make_current().map_err(|_| ()) }
+    }
+
+    fn get_framebuffer_dimensions(&self) -> (u32, u32) {
+        self.surface.size()
+    }
+
+    fn vsync_mode(&self) -> VsyncMode {
+        VsyncMode::Timer {
+            interval: VsyncMode::interval_from_refresh(HEADLESS_REFRESH),
+        }
+    }
+
+    fn pixel_ratio(&self) -> f64 {
+        // There is no physical size to derive it from, `FlutterEngineOptions` can override it
+        1.0
+    }
+
+    fn mode_control(&self) -> Option<Arc<dyn ModeControl>> {
+        None
+    }
+
+    fn apply_transform(&self, _transform: Transform) -> bool {
+        false
+    }
+}
+
+/// Discards frames of flutter's software renderer, which needs neither EGL nor a GPU. Frames can
+/// still be read with `FlutterOutput::capture_frame`.
+pub struct HeadlessSoftwareBackend {
+    size: (u32, u32),
+}
+
+impl SoftwareOutputBackend for HeadlessSoftwareBackend {
+    fn present(&self, _pixels: &[u8], _row_bytes: usize, _height: usize) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn get_framebuffer_dimensions(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn vsync_mode(&self) -> VsyncMode {
+        VsyncMode::Timer {
+            interval: VsyncMode::interval_from_refresh(HEADLESS_REFRESH),
+        }
+    }
+
+    fn pixel_ratio(&self) -> f64 {
+        1.0
+    }
+
+    fn mode_control(&self) -> Option<Arc<dyn ModeControl>> {
+        None
+    }
+}
+
+/// Runs flutter engines without any display, such as for integration tests in CI.
+pub struct HeadlessOutputManager {
+    engines: EngineWeakCollection,
+    keyboard: Arc<Mutex<KeyboardManager>>,
+    outputs: Mutex<Vec<FlutterOutput>>,
+}
+
+impl HeadlessOutputManager {
+    pub fn new() -> Self {
+        let engines = EngineWeakCollection::new();
+
+        Self {
+            keyboard: Arc::new(Mutex::new(KeyboardManager::new(engines.clone()))),
+            engines,
+            outputs: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Changes the keyboard layout and repeat settings, recompiling the keymap immediately.
+    pub fn set_keyboard_config(&self, config: KeyboardConfig) -> Result<(), Error> {
+        self.keyboard.lock().set_config(config)
+    }
+
+    /// Starts an engine rendering at the given size in pixels.
+    pub fn create_output(
+        &self,
+        size: (u32, u32),
+        mut options: FlutterEngineOptions,
+    ) -> Result<FlutterOutput, Error> {
+        debug!("Creating headless output of {}x{}", size.0, size.1);
+
+        // Frames are only ever seen through `FlutterOutput::capture_frame`, which needs them kept
+        options.set_offscreen(true);
+
+        let surface = unsafe {
+            let display = WrappedDisplay::headless()?;
+            let context = WrappedContext::create_pbuffer_context(&display)?;
+            context.create_pbuffer(size)?
+        };
+
+        let backend = HeadlessOutputBackend { surface };
+        let output = FlutterOutput::new(backend, options, self.keyboard.clone())?;
+        self.add_output(&output);
+
+        Ok(output)
+    }
+
+    /// Starts an engine rendering in software at the given size in pixels, for machines where
+    /// not even a software EGL implementation is available.
+    pub fn create_software_output(
+        &self,
+        size: (u32, u32),
+        options: FlutterEngineOptions,
+    ) -> Result<FlutterOutput, Error> {
+        debug!("Creating headless software output of {}x{}", size.0, size.1);
+
+        let backend = HeadlessSoftwareBackend { size };
+        let output = FlutterOutput::new_software(backend, options, self.keyboard.clone())?;
+        self.add_output(&output);
+
+        Ok(output)
+    }
+
+    fn add_output(&self, output: &FlutterOutput) {
+        self.engines.add(output.engine().downgrade());
+
+        let mut outputs = self.outputs.lock();
+        outputs.retain(|output| output.is_running());
+        outputs.push(output.clone());
+    }
+}
+
+impl Drop for HeadlessOutputManager {
+    /// Shuts down every output's engine and waits for its thread.
+    fn drop(&mut self) {
+        let outputs: Vec<_> = self.outputs.lock().drain(..).collect();
+        FlutterOutput::shutdown_all(&outputs);
+    }
+}
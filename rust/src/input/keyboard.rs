@@ -1,15 +1,17 @@
+use crate::error::Error;
 use crate::input::glfw;
 use crate::EngineWeakCollection;
 use crossbeam::channel;
 use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
-use flutter_engine::FlutterEngineWeakRef;
+use flutter_engine::{FlutterEngine, FlutterEngineWeakRef};
 use flutter_plugins::keyevent::{KeyAction, KeyActionType, KeyEventPlugin};
 use flutter_plugins::textinput::TextInputPlugin;
 use log::debug;
-use log::info;
+use log::error;
 use parking_lot::Mutex;
 use smithay::backend::input::KeyState;
 use smithay::reexports::input as libinput;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
@@ -33,111 +35,166 @@ pub struct KeyboardConfig {
     /// preferences, like which key combinations are used for switching layouts, or which key is the
     /// Compose key.
     pub options: Option<String>,
-    /// The rate at which to repeat key press events.
+    /// The rate at which to repeat key press events, in repeats per second. Zero or less disables
+    /// key repeat.
     pub rate: i32,
-    /// The delay after which key press events should be repeated when being held.
+    /// The delay in milliseconds after which key press events should be repeated when being held.
     pub delay: i32,
 }
 
+impl Default for KeyboardConfig {
+    fn default() -> Self {
+        Self {
+            rules: "".to_string(),
+            model: "".to_string(),
+            layout: "".to_string(),
+            variant: "".to_string(),
+            options: None,
+            rate: 20,
+            delay: 1000,
+        }
+    }
+}
+
+/// Identifies a keyboard to the key repeater, which can't share libinput devices as they aren't
+/// thread safe.
+#[derive(Clone, Eq, Hash, PartialEq)]
+enum RepeatSource {
+    Default,
+    Device(String),
+}
+
+impl From<&KeyboardSource> for RepeatSource {
+    fn from(source: &KeyboardSource) -> Self {
+        match source {
+            KeyboardSource::Default => RepeatSource::Default,
+            KeyboardSource::Device(device) => RepeatSource::Device(device.sysname().to_string()),
+        }
+    }
+}
+
 enum KeyRepeatAction {
-    Pressed(KeyRepeatInfo),
-    Released(u32),
-    Stop,
+    Pressed(RepeatSource, KeyRepeatInfo),
+    Released(RepeatSource, u32),
+    /// Stops repeating the key held on a keyboard.
+    Stop(RepeatSource),
+    /// Stops repeating keys on every keyboard.
+    StopAll,
 }
 
 struct KeyRepeatInfo {
     code: u32,
     state: xkb::State,
+    rate: i32,
+    delay: i32,
 }
 
 unsafe impl Send for KeyRepeatInfo {}
 
+/// A key being repeated, and when to send it next.
+struct KeyRepeat {
+    info: KeyRepeatInfo,
+    next_send: Instant,
+}
+
+/// The engine key events go to, shared with the key repeater.
+#[derive(Clone)]
+struct KeyboardFocus {
+    focused: Arc<Mutex<Option<FlutterEngineWeakRef>>>,
+    engines: EngineWeakCollection,
+}
+
+impl KeyboardFocus {
+    /// Returns the focused engine. Without one the first engine takes focus, so that a single
+    /// output receives keys without being focused first.
+    fn engine(&self) -> Option<FlutterEngine> {
+        let mut focused = self.focused.lock();
+        if let Some(engine) = focused.as_ref().and_then(|engine| engine.upgrade()) {
+            return Some(engine);
+        }
+
+        let engine = self.engines.first();
+        *focused = engine.as_ref().map(|engine| engine.downgrade());
+        engine
+    }
+}
+
 fn key_repeater_thread(
     repeat_recv: Receiver<KeyRepeatAction>,
-    engines: EngineWeakCollection,
+    focus: KeyboardFocus,
     textinput: Arc<Mutex<Option<FlutterEngineWeakRef>>>,
 ) {
-    let rate = 50;
-    let delay = 1000;
-
-    let mut repeat = None;
-
-    'outer: loop {
-        // Wait until we have a key press
-        while repeat.is_none() {
-            match repeat_recv.recv() {
-                Ok(act) => match act {
-                    KeyRepeatAction::Pressed(info) => {
-                        repeat = Some(info);
-                    }
-                    _ => {}
-                },
+    // Each keyboard repeats its own last pressed key
+    let mut repeats: HashMap<RepeatSource, KeyRepeat> = HashMap::new();
+
+    loop {
+        // Wait until the next repeat is due, or indefinitely while no key is held
+        let next_send = repeats.values().map(|repeat| repeat.next_send).min();
+        let action = match next_send {
+            None => match repeat_recv.recv() {
+                Ok(action) => Some(action),
                 Err(_) => return,
-            };
-        }
-
-        let repeat_info = match repeat.as_ref() {
-            None => {
-                continue 'outer;
+            },
+            Some(next_send) => {
+                // Ensure we don't try to sleep for a negative length of time, possible if system
+                // can't keep up with key repeats
+                let timeout = next_send.saturating_duration_since(Instant::now());
+                match repeat_recv.recv_timeout(timeout) {
+                    Ok(action) => Some(action),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
             }
-            Some(info) => info,
         };
 
-        let mut next_send = Instant::now() + Duration::from_millis(delay);
-        loop {
-            let mut now = Instant::now();
-
-            // Check if we should send a key now
-            if next_send <= now {
-                // TODO: Send repeat event
-                info!("We should repeat {} now", repeat_info.code);
-                key_event(
-                    repeat_info.code,
-                    KeyState::Pressed,
-                    &repeat_info.state,
-                    &engines,
-                    &textinput,
-                );
+        match action {
+            Some(KeyRepeatAction::Pressed(source, info)) => {
+                // Ignore presses of the key which is already repeating
+                if let Some(repeat) = repeats.get(&source) {
+                    if repeat.info.code == info.code {
+                        continue;
+                    }
+                }
 
-                next_send = now + Duration::from_millis(rate);
-                now = Instant::now();
+                let delay = Duration::from_millis(info.delay.max(0) as u64);
+                repeats.insert(
+                    source,
+                    KeyRepeat {
+                        info,
+                        next_send: Instant::now() + delay,
+                    },
+                );
             }
-
-            // Ensure we don't try to sleep for a negative length of time, possible if system can't
-            // keep up with key repeats
-            let timeout = if next_send <= now {
-                Duration::from_millis(0)
-            } else {
-                next_send - now
-            };
-
-            match repeat_recv.recv_timeout(timeout) {
-                Ok(act) => {
-                    match act {
-                        KeyRepeatAction::Pressed(info) => {
-                            // Ignore we have press same key again
-                            if repeat_info.code != info.code {
-                                repeat = Some(info);
-                                continue 'outer;
-                            }
-                        }
-                        KeyRepeatAction::Released(key) => {
-                            // Ensure we have released the same key
-                            if repeat_info.code == key {
-                                repeat = None;
-                                continue 'outer;
-                            }
-                        }
-                        KeyRepeatAction::Stop => {
-                            repeat = None;
-                            continue 'outer;
-                        }
+            Some(KeyRepeatAction::Released(source, code)) => {
+                // Ensure we have released the same key
+                if repeats
+                    .get(&source)
+                    .map_or(false, |repeat| repeat.info.code == code)
+                {
+                    repeats.remove(&source);
+                }
+            }
+            Some(KeyRepeatAction::Stop(source)) => {
+                repeats.remove(&source);
+            }
+            Some(KeyRepeatAction::StopAll) => repeats.clear(),
+            None => {
+                let now = Instant::now();
+                for repeat in repeats.values_mut() {
+                    if repeat.next_send > now {
+                        continue;
                     }
+
+                    key_event(
+                        repeat.info.code,
+                        KeyState::Pressed,
+                        &repeat.info.state,
+                        &focus,
+                        &textinput,
+                    );
+                    let interval = 1_000_000 / repeat.info.rate.max(1) as u64;
+                    repeat.next_send = now + Duration::from_micros(interval);
                 }
-                Err(err) => match err {
-                    RecvTimeoutError::Timeout => {}
-                    RecvTimeoutError::Disconnected => return,
-                },
             }
         }
     }
@@ -147,13 +204,68 @@ struct ActiveConfig {
     config: KeyboardConfig,
     keymap: xkb::Keymap,
     state: xkb::State,
+    /// Whether the config was chosen for this keyboard, rather than following the default.
+    custom: bool,
+    /// Scancodes of the keys currently held.
+    pressed: Vec<u32>,
+    /// Held keys whose key-up was already sent to an engine that lost focus.
+    released: Vec<u32>,
+}
+
+impl ActiveConfig {
+    /// Takes over the state of the keyboard's previous keymap. Locks such as caps lock are kept,
+    /// and held keys are pressed again so that modifiers stay active until they are released.
+    fn restore_state(&mut self, previous: &ActiveConfig) {
+        self.state.update_mask(
+            0,
+            0,
+            previous.state.serialize_mods(xkb::STATE_MODS_LOCKED),
+            0,
+            0,
+            previous.state.serialize_layout(xkb::STATE_LAYOUT_LOCKED),
+        );
+        for &scancode in &previous.pressed {
+            self.state.update_key(scancode, xkb::KeyDirection::Down);
+        }
+        self.pressed = previous.pressed.clone();
+        self.released = previous.released.clone();
+    }
+}
+
+/// Identifies the keyboard a key event originated from.
+#[derive(Clone, Eq, Hash, PartialEq)]
+pub enum KeyboardSource {
+    /// Keyboards without a device of their own, such as the winit window's.
+    Default,
+    Device(libinput::Device),
+}
+
+/// Description of a physical keyboard, used to choose its layout.
+#[derive(Clone, Debug)]
+pub struct KeyboardDevice {
+    pub name: String,
+    pub sysname: String,
+    pub vendor: u32,
+    pub product: u32,
+}
+
+impl KeyboardDevice {
+    pub(crate) fn from_libinput(device: &libinput::Device) -> Self {
+        Self {
+            name: device.name().to_string(),
+            sysname: device.sysname().to_string(),
+            vendor: device.id_vendor(),
+            product: device.id_product(),
+        }
+    }
 }
 
 pub struct KeyboardManager {
     context: xkb::Context,
-    current_config: Option<ActiveConfig>,
+    config: KeyboardConfig,
+    active: HashMap<KeyboardSource, ActiveConfig>,
     repeat_sender: Sender<KeyRepeatAction>,
-    engines: EngineWeakCollection,
+    focus: KeyboardFocus,
     devices: Vec<libinput::Device>,
     textinput: Arc<Mutex<Option<FlutterEngineWeakRef>>>,
 }
@@ -167,65 +279,143 @@ impl KeyboardManager {
         let (repeat_sender, repeat_recv) = channel::unbounded();
 
         let textinput = Arc::new(Mutex::new(None));
+        let focus = KeyboardFocus {
+            focused: Arc::new(Mutex::new(None)),
+            engines,
+        };
 
-        let engines_copy = engines.clone();
+        let focus_copy = focus.clone();
         let textinput_copy = textinput.clone();
-        thread::Builder::new()
+        let repeater = thread::Builder::new()
             .name("keyboard-keyrepeater".to_string())
-            .spawn(move || key_repeater_thread(repeat_recv, engines_copy, textinput_copy))
-            .expect("Failed to create key repeater thread");
+            .spawn(move || key_repeater_thread(repeat_recv, focus_copy, textinput_copy));
+        if let Err(err) = repeater {
+            // Keys still work, they just don't repeat
+            error!("Failed to create key repeater thread: {}", err);
+        }
 
         Self {
             context,
-            current_config: None,
+            config: KeyboardConfig::default(),
+            active: HashMap::new(),
             repeat_sender,
-            engines,
+            focus,
             devices: vec![],
             textinput,
         }
     }
 
-    pub fn select_layout(&mut self) {
-        if self.current_config.is_none() {
-            // TODO: Allow keyboard configuration
-            // For now we just force the default layout and repeat options
-            let config = KeyboardConfig {
-                rules: "".to_string(),
-                model: "".to_string(),
-                layout: "".to_string(),
-                variant: "".to_string(),
-                options: None,
-                rate: 50,
-                delay: 1000,
+    /// Replaces the default keyboard configuration, recompiling the keymap immediately for every
+    /// keyboard without a layout of its own.
+    ///
+    /// On failure the previous configuration is kept.
+    pub fn set_config(&mut self, config: KeyboardConfig) -> Result<(), Error> {
+        let mut default = self.compile(config.clone(), false)?;
+
+        // Stop current repeat, as config has changed
+        self.send_repeat(KeyRepeatAction::StopAll);
+
+        // Keyboards following the default are recompiled, keeping the keys they hold
+        let sources: Vec<_> = self
+            .active
+            .iter()
+            .filter(|(source, active)| !active.custom && **source != KeyboardSource::Default)
+            .map(|(source, _)| source.clone())
+            .collect();
+        for source in sources {
+            let mut active = match self.compile(config.clone(), false) {
+                Ok(active) => active,
+                Err(_) => continue,
             };
+            if let Some(previous) = self.active.get(&source) {
+                active.restore_state(previous);
+            }
+            self.active.insert(source, active);
+        }
 
-            let keymap = xkb::Keymap::new_from_names(
-                &self.context,
-                &config.rules,
-                &config.model,
-                &config.layout,
-                &config.variant,
-                config.options.clone(),
-                xkb::KEYMAP_COMPILE_NO_FLAGS,
-            )
-            .unwrap();
-            let state = xkb::State::new(&keymap);
-
-            // Stop current repeat, as config has changed
-            self.repeat_sender.send(KeyRepeatAction::Stop).unwrap();
-
-            self.current_config = Some(ActiveConfig {
-                config,
-                keymap,
-                state,
-            });
+        if let Some(previous) = self.active.get(&KeyboardSource::Default) {
+            default.restore_state(previous);
+        }
+        self.active.insert(KeyboardSource::Default, default);
+        self.config = config;
+        self.update_leds();
+        Ok(())
+    }
+
+    pub fn config(&self) -> &KeyboardConfig {
+        &self.config
+    }
+
+    /// Ensures the keyboard has a compiled keymap.
+    ///
+    /// `select` is only called when the keyboard has none yet, and may return a layout specific to
+    /// this keyboard. Otherwise the default configuration is used.
+    pub fn select_layout<F>(&mut self, source: KeyboardSource, select: F)
+    where
+        F: FnOnce() -> Option<KeyboardConfig>,
+    {
+        if self.active.contains_key(&source) {
+            return;
+        }
+
+        let custom = select().and_then(|config| match self.compile(config, true) {
+            Ok(active) => Some(active),
+            Err(err) => {
+                error!("{}, using the default layout", err);
+                None
+            }
+        });
+        let active = match custom {
+            Some(active) => Ok(active),
+            None => self.compile(self.config.clone(), false),
+        };
+
+        // Fall back to the default layout, which should always compile
+        let active = active.or_else(|err| {
+            error!("{}, using the built-in layout", err);
+            self.compile(KeyboardConfig::default(), false)
+        });
+        match active {
+            Ok(active) => {
+                self.active.insert(source, active);
+            }
+            Err(err) => error!("{}, the keyboard is ignored", err),
         }
     }
 
-    pub fn key(&mut self, rawcode: u32, keystate: KeyState) {
+    fn compile(&self, config: KeyboardConfig, custom: bool) -> Result<ActiveConfig, Error> {
+        let keymap = xkb::Keymap::new_from_names(
+            &self.context,
+            &config.rules,
+            &config.model,
+            &config.layout,
+            &config.variant,
+            config.options.clone(),
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .ok_or_else(|| Error::Keymap(format!("{:?}", config)))?;
+        let state = xkb::State::new(&keymap);
+
+        Ok(ActiveConfig {
+            config,
+            keymap,
+            state,
+            custom,
+            pressed: Vec::new(),
+            released: Vec::new(),
+        })
+    }
+
+    pub fn key(&mut self, source: &KeyboardSource, rawcode: u32, keystate: KeyState) {
         let scancode = rawcode + 8;
 
-        let config = self.current_config.as_mut().expect("No layout is active");
+        let config = match self.active.get_mut(source) {
+            Some(config) => config,
+            None => {
+                debug!("Dropping key of a keyboard without keymap");
+                return;
+            }
+        };
 
         // Update state
         let direction = match keystate {
@@ -233,57 +423,143 @@ impl KeyboardManager {
             KeyState::Released => xkb::KeyDirection::Up,
         };
         config.state.update_key(scancode, direction);
+        config.pressed.retain(|&pressed| pressed != scancode);
+        if keystate == KeyState::Pressed {
+            config.pressed.push(scancode);
+        }
+        let released = config.released.contains(&scancode);
+        config.released.retain(|&key| key != scancode);
+
+        // Dispatch key press, unless the engine that got it has already been told of its release
+        if !(released && keystate == KeyState::Released) {
+            key_event(
+                rawcode,
+                keystate,
+                &mut config.state,
+                &self.focus,
+                &self.textinput,
+            );
+        }
 
-        // Dispatch key press
-        key_event(
-            rawcode,
-            keystate,
-            &mut config.state,
-            &self.engines,
-            &self.textinput,
-        );
-
-        self.repeat_sender
-            .send(match keystate {
-                KeyState::Released => KeyRepeatAction::Released(rawcode),
-                KeyState::Pressed => match config.keymap.key_repeats(scancode) {
-                    true => KeyRepeatAction::Pressed(KeyRepeatInfo {
-                        code: rawcode,
-                        state: config.state.clone(),
-                    }),
-                    false => KeyRepeatAction::Stop,
-                },
-            })
-            .unwrap();
+        let repeat_source = RepeatSource::from(source);
+        let action = match keystate {
+            KeyState::Released => KeyRepeatAction::Released(repeat_source, rawcode),
+            KeyState::Pressed => {
+                match config.keymap.key_repeats(scancode) && config.config.rate > 0 {
+                    true => KeyRepeatAction::Pressed(
+                        repeat_source,
+                        KeyRepeatInfo {
+                            code: rawcode,
+                            state: config.state.clone(),
+                            rate: config.config.rate,
+                            delay: config.config.delay,
+                        },
+                    ),
+                    false => KeyRepeatAction::Stop(repeat_source),
+                }
+            }
+        };
+        self.send_repeat(action);
 
         self.update_leds();
     }
 
+    fn send_repeat(&self, action: KeyRepeatAction) {
+        if self.repeat_sender.send(action).is_err() {
+            debug!("Key repeater is not running");
+        }
+    }
+
     pub fn update_devices(&mut self, devices: Vec<libinput::Device>) {
+        // Forget the state of keyboards which have been unplugged
+        self.active.retain(|source, _| match source {
+            KeyboardSource::Default => true,
+            KeyboardSource::Device(device) => devices.contains(device),
+        });
+
         self.devices = devices;
         self.update_leds();
     }
 
     fn update_leds(&mut self) {
-        let mut leds = libinput::Led::empty();
-
-        if let Some(config) = self.current_config.as_ref() {
-            if config
-                .state
-                .mod_name_is_active(xkb::MOD_NAME_CAPS, xkb::STATE_MODS_EFFECTIVE)
-            {
-                leds |= libinput::Led::CAPSLOCK;
+        for device in &mut self.devices {
+            let mut leds = libinput::Led::empty();
+
+            let source = KeyboardSource::Device(device.clone());
+            if let Some(config) = self.active.get(&source) {
+                if config
+                    .state
+                    .mod_name_is_active(xkb::MOD_NAME_CAPS, xkb::STATE_MODS_EFFECTIVE)
+                {
+                    leds |= libinput::Led::CAPSLOCK;
+                }
+                if config
+                    .state
+                    .mod_name_is_active(xkb::MOD_NAME_NUM, xkb::STATE_MODS_EFFECTIVE)
+                {
+                    leds |= libinput::Led::NUMLOCK;
+                }
+            }
+
+            device.led_update(leds);
+        }
+    }
+
+    /// The engine key events go to, if any has been focused yet.
+    pub fn focus(&self) -> Option<FlutterEngineWeakRef> {
+        self.focus.focused.lock().clone()
+    }
+
+    /// Sends key events to the given engine from now on.
+    pub fn set_focus(&mut self, engine: FlutterEngineWeakRef) {
+        let previous = {
+            let mut focused = self.focus.focused.lock();
+            if let Some(current) = focused.as_ref() {
+                if current.ptr_equal(engine.clone()) {
+                    return;
+                }
             }
-            if config
-                .state
-                .mod_name_is_active(xkb::MOD_NAME_NUM, xkb::STATE_MODS_EFFECTIVE)
-            {
-                leds |= libinput::Led::NUMLOCK;
+            focused.replace(engine)
+        };
+
+        self.release_held_keys(previous);
+    }
+
+    /// Takes focus away from the engine, if it has it. Until another engine is focused, keys go
+    /// to the first engine.
+    pub fn clear_focus(&mut self, engine: FlutterEngineWeakRef) {
+        {
+            let mut focused = self.focus.focused.lock();
+            match focused.take() {
+                Some(current) if !current.ptr_equal(engine.clone()) => {
+                    *focused = Some(current);
+                    return;
+                }
+                Some(_) => {}
+                None => return,
             }
         }
 
-        for device in &mut self.devices {
-            device.led_update(leds);
+        self.release_held_keys(Some(engine));
+    }
+
+    /// Tells the engine that lost focus that the held keys were released, as their actual
+    /// release goes to the newly focused engine, if anywhere.
+    fn release_held_keys(&mut self, previous: Option<FlutterEngineWeakRef>) {
+        // Held keys don't repeat into the newly focused engine
+        self.send_repeat(KeyRepeatAction::StopAll);
+
+        let engine = match previous.and_then(|engine| engine.upgrade()) {
+            Some(engine) => engine,
+            None => return,
+        };
+        for config in self.active.values_mut() {
+            for &scancode in &config.pressed {
+                if !config.released.contains(&scancode) {
+                    send_key_action(&engine, scancode - 8, KeyState::Released, &config.state);
+                    config.released.push(scancode);
+                }
+            }
         }
     }
 
@@ -305,7 +581,7 @@ fn key_event(
     rawcode: u32,
     keystate: KeyState,
     state: &xkb::State,
-    engines: &EngineWeakCollection,
+    focus: &KeyboardFocus,
     textinput: &Arc<Mutex<Option<FlutterEngineWeakRef>>>,
 ) {
     // Offset the rawcode by 8, as the evdev XKB rules reflect X's
@@ -319,42 +595,21 @@ fn key_event(
         scancode, keystate, keycode, content,
     );
 
-    // Convert modifiers
-    let shift = state.mod_name_is_active(xkb::MOD_NAME_SHIFT, xkb::STATE_MODS_EFFECTIVE) as i32;
-    let ctrl = state.mod_name_is_active(xkb::MOD_NAME_CTRL, xkb::STATE_MODS_EFFECTIVE) as i32;
-    let alt = state.mod_name_is_active(xkb::MOD_NAME_ALT, xkb::STATE_MODS_EFFECTIVE) as i32;
-    let logo = state.mod_name_is_active(xkb::MOD_NAME_LOGO, xkb::STATE_MODS_EFFECTIVE) as i32;
-    let caps = state.mod_name_is_active(xkb::MOD_NAME_CAPS, xkb::STATE_MODS_EFFECTIVE) as i32;
-    let num = state.mod_name_is_active(xkb::MOD_NAME_NUM, xkb::STATE_MODS_EFFECTIVE) as i32;
-    let modifiers = shift | ctrl << 1 | alt << 2 | logo << 3 | caps << 4 | num << 5;
+    // Send key event to the focused engine
+    let engine = match focus.engine() {
+        Some(engine) => engine,
+        None => return,
+    };
+    send_key_action(&engine, rawcode, keystate, state);
 
-    // Send key event to all engines
-    engines.for_each(move |engine| {
-        engine.run_on_platform_thread(move |engine| {
-            engine.with_plugin(move |plugin: &KeyEventPlugin| {
-                plugin.key_action(KeyAction {
-                    toolkit: "glfw".to_string(),
-                    key_code: keycode,
-                    scan_code: scancode as i32,
-                    modifiers,
-                    keymap: "linux".to_string(),
-                    _type: match keystate {
-                        KeyState::Released => KeyActionType::Keyup,
-                        KeyState::Pressed => KeyActionType::Keydown,
-                    },
-                });
-            });
-        });
-    });
-
-    // Send text events
+    // Send text events, if the focused engine is editing text
     if !content.is_empty()
         && keystate == KeyState::Pressed
         && content.chars().all(|x| !x.is_control())
     {
         let textinput = textinput.lock();
-        if let Some(engine) = textinput.as_ref() {
-            if let Some(engine) = engine.upgrade() {
+        if let Some(target) = textinput.as_ref() {
+            if target.ptr_equal(engine.downgrade()) {
                 engine.run_on_platform_thread(move |engine| {
                     engine.with_plugin_mut(move |plugin: &mut TextInputPlugin| {
                         plugin.with_state(|state| {
@@ -367,3 +622,33 @@ fn key_event(
         }
     }
 }
+
+fn send_key_action(engine: &FlutterEngine, rawcode: u32, keystate: KeyState, state: &xkb::State) {
+    let scancode = rawcode + 8;
+    let keycode = glfw::map_key(rawcode);
+
+    // Convert modifiers
+    let shift = state.mod_name_is_active(xkb::MOD_NAME_SHIFT, xkb::STATE_MODS_EFFECTIVE) as i32;
+    let ctrl = state.mod_name_is_active(xkb::MOD_NAME_CTRL, xkb::STATE_MODS_EFFECTIVE) as i32;
+    let alt = state.mod_name_is_active(xkb::MOD_NAME_ALT, xkb::STATE_MODS_EFFECTIVE) as i32;
+    let logo = state.mod_name_is_active(xkb::MOD_NAME_LOGO, xkb::STATE_MODS_EFFECTIVE) as i32;
+    let caps = state.mod_name_is_active(xkb::MOD_NAME_CAPS, xkb::STATE_MODS_EFFECTIVE) as i32;
+    let num = state.mod_name_is_active(xkb::MOD_NAME_NUM, xkb::STATE_MODS_EFFECTIVE) as i32;
+    let modifiers = shift | ctrl << 1 | alt << 2 | logo << 3 | caps << 4 | num << 5;
+
+    engine.run_on_platform_thread(move |engine| {
+        engine.with_plugin(move |plugin: &KeyEventPlugin| {
+            plugin.key_action(KeyAction {
+                toolkit: "glfw".to_string(),
+                key_code: keycode,
+                scan_code: scancode as i32,
+                modifiers,
+                keymap: "linux".to_string(),
+                _type: match keystate {
+                    KeyState::Released => KeyActionType::Keyup,
+                    KeyState::Pressed => KeyActionType::Keydown,
+                },
+            });
+        });
+    });
+}
@@ -1,9 +1,15 @@
-use crate::input::keyboard::KeyboardManager;
+use crate::input::keyboard::{KeyboardDevice, KeyboardManager, KeyboardSource};
+use crate::input::pointer::{PointerManager, SCROLL_LINE_HEIGHT};
+use crate::input::touch::TouchManager;
+use crate::udev::UdevOutputManagerHandler;
 use parking_lot::Mutex;
-use smithay::backend::input::{InputHandler, KeyState, KeyboardKeyEvent, Seat};
+use smithay::backend::input::{
+    Axis, AxisSource, InputHandler, KeyState, KeyboardKeyEvent, PointerAxisEvent, Seat,
+};
 use smithay::backend::libinput::LibinputInputBackend;
 use smithay::reexports::input as libinput;
 use smithay::reexports::input::event;
+use smithay::reexports::input::event::touch::{TouchEventPosition, TouchEventSlot};
 use std::sync::Arc;
 
 use log::error;
@@ -12,12 +18,27 @@ use smithay::backend::session::Session;
 
 pub struct LibInputHandler {
     keyboard: Arc<Mutex<KeyboardManager>>,
+    pointer: Arc<Mutex<PointerManager>>,
+    touch: Arc<Mutex<TouchManager>>,
+    handler: Arc<dyn UdevOutputManagerHandler>,
     session: AutoSession,
 }
 
 impl LibInputHandler {
-    pub fn new(keyboard: Arc<Mutex<KeyboardManager>>, session: AutoSession) -> Self {
-        Self { keyboard, session }
+    pub fn new(
+        keyboard: Arc<Mutex<KeyboardManager>>,
+        pointer: Arc<Mutex<PointerManager>>,
+        touch: Arc<Mutex<TouchManager>>,
+        handler: Arc<dyn UdevOutputManagerHandler>,
+        session: AutoSession,
+    ) -> Self {
+        Self {
+            keyboard,
+            pointer,
+            touch,
+            handler,
+            session,
+        }
     }
 }
 
@@ -37,14 +58,18 @@ impl InputHandler<LibinputInputBackend> for LibInputHandler {
     fn on_keyboard_key(&mut self, seat: &Seat, event: event::keyboard::KeyboardKeyEvent) {
         let mut keyboard = self.keyboard.lock();
 
-        // TODO: Select keyboard layout
-        // let device = event.device();
-        keyboard.select_layout();
+        // Each keyboard keeps its own layout and modifier state
+        let device = event.device();
+        let handler = &self.handler;
+        let source = KeyboardSource::Device(device.clone());
+        keyboard.select_layout(source.clone(), || {
+            handler.configure_keyboard(&KeyboardDevice::from_libinput(&device))
+        });
 
         // Send key press
         let keycode = event.key_code();
         let state = event.state();
-        keyboard.key(keycode, state);
+        keyboard.key(&source, keycode, state);
 
         // error!("keycode: {}", keycode);
         if state == KeyState::Pressed {
@@ -57,7 +82,7 @@ impl InputHandler<LibinputInputBackend> for LibInputHandler {
     }
 
     fn on_pointer_move(&mut self, seat: &Seat, event: event::pointer::PointerMotionEvent) {
-        // TODO: Implement pointer support
+        self.pointer.lock().motion(event.dx(), event.dy());
     }
 
     fn on_pointer_move_absolute(
@@ -65,46 +90,79 @@ impl InputHandler<LibinputInputBackend> for LibInputHandler {
         seat: &Seat,
         event: event::pointer::PointerMotionAbsoluteEvent,
     ) {
-        // TODO: Implement pointer support
+        let mut pointer = self.pointer.lock();
+        let (width, height) = pointer.layout_size();
+        pointer.motion_absolute(
+            event.absolute_x_transformed(width as u32),
+            event.absolute_y_transformed(height as u32),
+        );
     }
 
     fn on_pointer_button(&mut self, seat: &Seat, event: event::pointer::PointerButtonEvent) {
-        // TODO: Implement pointer support
+        let pressed = event.button_state() == event::pointer::ButtonState::Pressed;
+        self.pointer.lock().button(event.button(), pressed);
     }
 
     fn on_pointer_axis(&mut self, seat: &Seat, event: event::pointer::PointerAxisEvent) {
-        // TODO: Implement pointer support
+        let amount = |axis| match event.source() {
+            AxisSource::Wheel | AxisSource::WheelTilt => event
+                .amount_discrete(axis)
+                .map(|steps| steps * SCROLL_LINE_HEIGHT),
+            _ => event.amount(axis),
+        };
+        let dx = amount(Axis::Horizontal).unwrap_or(0.0);
+        let dy = amount(Axis::Vertical).unwrap_or(0.0);
+
+        if dx != 0.0 || dy != 0.0 {
+            self.pointer.lock().axis(dx, dy);
+        }
     }
 
     fn on_touch_down(&mut self, seat: &Seat, event: event::touch::TouchDownEvent) {
-        // TODO: Implement touch support
+        let mut touch = self.touch.lock();
+        if let Some((width, height)) = touch.output_size() {
+            let position = (event.x_transformed(width), event.y_transformed(height));
+            touch.down(seat.name(), event.seat_slot(), position);
+        }
     }
 
     fn on_touch_motion(&mut self, seat: &Seat, event: event::touch::TouchMotionEvent) {
-        // TODO: Implement touch support
+        let mut touch = self.touch.lock();
+        if let Some((width, height)) = touch.output_size() {
+            let position = (event.x_transformed(width), event.y_transformed(height));
+            touch.motion(seat.name(), event.seat_slot(), position);
+        }
     }
 
     fn on_touch_up(&mut self, seat: &Seat, event: event::touch::TouchUpEvent) {
-        // TODO: Implement touch support
+        self.touch.lock().up(seat.name(), event.seat_slot());
     }
 
     fn on_touch_cancel(&mut self, seat: &Seat, event: event::touch::TouchCancelEvent) {
-        // TODO: Implement touch support
+        self.touch.lock().cancel(seat.name());
     }
 
     fn on_touch_frame(&mut self, seat: &Seat, event: event::touch::TouchFrameEvent) {
-        // TODO: Implement touch support
+        self.touch.lock().frame(seat.name());
     }
 
     fn on_input_config_changed(&mut self, config: &mut [libinput::Device]) {
         let mut keyboards = Vec::new();
+        let mut has_pointer = false;
 
         for device in config {
             if device.has_capability(libinput::DeviceCapability::Keyboard) {
                 keyboards.push(device.clone());
             }
+            if device.has_capability(libinput::DeviceCapability::Pointer) {
+                has_pointer = true;
+            }
         }
 
         self.keyboard.lock().update_devices(keyboards);
+
+        if !has_pointer {
+            self.pointer.lock().remove();
+        }
     }
 }
@@ -1,4 +1,6 @@
 mod glfw;
 pub mod keyboard;
 pub mod libinput;
+pub mod pointer;
+pub mod touch;
 pub mod winit;
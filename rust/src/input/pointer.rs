@@ -0,0 +1,405 @@
+use crate::cursor::CursorManager;
+use crate::input::keyboard::KeyboardManager;
+use crate::OutputLayout;
+use flutter_engine::ffi::{
+    FlutterPointerDeviceKind, FlutterPointerMouseButtons, FlutterPointerPhase,
+    FlutterPointerSignalKind,
+};
+use flutter_engine::FlutterEngineWeakRef;
+use log::debug;
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Device id used for the (single) mouse pointer.
+pub(crate) const MOUSE_DEVICE_ID: i32 = 0;
+
+/// Distance in pixels scrolled by a single wheel click.
+pub(crate) const SCROLL_LINE_HEIGHT: f64 = 20.0;
+
+// Linux evdev button codes, as reported by libinput
+const BTN_LEFT: u32 = 0x110;
+const BTN_RIGHT: u32 = 0x111;
+const BTN_MIDDLE: u32 = 0x112;
+const BTN_SIDE: u32 = 0x113;
+const BTN_EXTRA: u32 = 0x114;
+const BTN_FORWARD: u32 = 0x115;
+const BTN_BACK: u32 = 0x116;
+
+/// Bitmask of pressed buttons, using the same bit layout as flutter's `FlutterPointerMouseButtons`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct PointerButtons(u8);
+
+impl PointerButtons {
+    pub const PRIMARY: Self = PointerButtons(1 << 0);
+    pub const SECONDARY: Self = PointerButtons(1 << 1);
+    pub const MIDDLE: Self = PointerButtons(1 << 2);
+    pub const BACK: Self = PointerButtons(1 << 3);
+    pub const FORWARD: Self = PointerButtons(1 << 4);
+
+    pub fn empty() -> Self {
+        PointerButtons(0)
+    }
+
+    /// Maps a linux evdev button code to the matching flutter button.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            BTN_LEFT => Some(Self::PRIMARY),
+            BTN_RIGHT => Some(Self::SECONDARY),
+            BTN_MIDDLE => Some(Self::MIDDLE),
+            BTN_SIDE | BTN_BACK => Some(Self::BACK),
+            BTN_EXTRA | BTN_FORWARD => Some(Self::FORWARD),
+            _ => None,
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+}
+
+impl From<PointerButtons> for FlutterPointerMouseButtons {
+    fn from(buttons: PointerButtons) -> Self {
+        FlutterPointerMouseButtons::from_bits_truncate(buttons.0.into())
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct PointerEvent {
+    pub device_kind: FlutterPointerDeviceKind,
+    pub device: i32,
+    pub phase: FlutterPointerPhase,
+    pub position: (f64, f64),
+    pub signal_kind: FlutterPointerSignalKind,
+    pub scroll_delta: (f64, f64),
+    pub buttons: PointerButtons,
+}
+
+impl PointerEvent {
+    pub fn mouse(
+        phase: FlutterPointerPhase,
+        position: (f64, f64),
+        buttons: PointerButtons,
+    ) -> Self {
+        Self {
+            device_kind: FlutterPointerDeviceKind::Mouse,
+            device: MOUSE_DEVICE_ID,
+            phase,
+            position,
+            signal_kind: FlutterPointerSignalKind::None,
+            scroll_delta: (0.0, 0.0),
+            buttons,
+        }
+    }
+
+    pub fn scroll(position: (f64, f64), delta: (f64, f64), buttons: PointerButtons) -> Self {
+        Self {
+            signal_kind: FlutterPointerSignalKind::Scroll,
+            scroll_delta: delta,
+            ..Self::mouse(motion_phase(buttons), position, buttons)
+        }
+    }
+}
+
+/// The phase to report for movement, depending on whether any buttons are held.
+pub(crate) fn motion_phase(buttons: PointerButtons) -> FlutterPointerPhase {
+    if buttons.is_empty() {
+        FlutterPointerPhase::Hover
+    } else {
+        FlutterPointerPhase::Move
+    }
+}
+
+/// Sends a batch of pointer events to the given engine, on its platform thread.
+pub(crate) fn send_pointer_events(engine: &FlutterEngineWeakRef, events: Vec<PointerEvent>) {
+    if events.is_empty() {
+        return;
+    }
+
+    if let Some(engine) = engine.upgrade() {
+        engine.run_on_platform_thread(move |engine| {
+            for event in events {
+                engine.send_pointer_event(
+                    event.device_kind,
+                    event.device,
+                    event.phase,
+                    event.position,
+                    event.signal_kind,
+                    event.scroll_delta,
+                    event.buttons.into(),
+                );
+            }
+        });
+    }
+}
+
+/// The output the pointer is currently added to.
+struct PointerTarget {
+    engine: FlutterEngineWeakRef,
+    position: (f64, f64),
+}
+
+pub struct PointerManager {
+    layout: OutputLayout,
+    position: (f64, f64),
+    buttons: PointerButtons,
+    target: Option<PointerTarget>,
+    cursor: Option<Arc<Mutex<CursorManager>>>,
+    keyboard: Option<Arc<Mutex<KeyboardManager>>>,
+}
+
+impl PointerManager {
+    pub fn new(layout: OutputLayout) -> Self {
+        Self {
+            layout,
+            position: (0.0, 0.0),
+            buttons: PointerButtons::empty(),
+            target: None,
+            cursor: None,
+            keyboard: None,
+        }
+    }
+
+    /// Sets the cursor to keep in sync with the pointer position.
+    pub(crate) fn set_cursor(&mut self, cursor: Arc<Mutex<CursorManager>>) {
+        self.cursor = Some(cursor);
+        self.update_cursor();
+    }
+
+    /// Sets the keyboard whose focus follows the pointer onto the output under it.
+    pub(crate) fn set_keyboard(&mut self, keyboard: Arc<Mutex<KeyboardManager>>) {
+        self.keyboard = Some(keyboard);
+    }
+
+    pub fn position(&self) -> (f64, f64) {
+        self.position
+    }
+
+    /// Size of the area the pointer can move within.
+    pub fn layout_size(&self) -> (f64, f64) {
+        self.layout.size()
+    }
+
+    /// Moves the pointer by the given relative amount.
+    pub fn motion(&mut self, dx: f64, dy: f64) {
+        let (x, y) = self.position;
+        self.motion_absolute(x + dx, y + dy);
+    }
+
+    /// Moves the pointer to the given position in the global layout.
+    pub fn motion_absolute(&mut self, x: f64, y: f64) {
+        self.position = self.layout.clamp(x, y);
+        self.update_target();
+        self.update_cursor();
+
+        if let Some(target) = self.target.as_ref() {
+            send_pointer_events(
+                &target.engine,
+                vec![PointerEvent::mouse(
+                    motion_phase(self.buttons),
+                    target.position,
+                    self.buttons,
+                )],
+            );
+        }
+    }
+
+    /// Updates the state of a button, using the linux evdev button code.
+    pub fn button(&mut self, code: u32, pressed: bool) {
+        match PointerButtons::from_code(code) {
+            Some(button) => self.set_button(button, pressed),
+            None => debug!("Ignoring unknown pointer button {:#x}", code),
+        }
+    }
+
+    pub(crate) fn set_button(&mut self, button: PointerButtons, pressed: bool) {
+        if pressed == self.buttons.contains(button) {
+            return;
+        }
+
+        // Resolve the target before the buttons change, so releases go to the grabbing output
+        self.update_target();
+
+        let was_empty = self.buttons.is_empty();
+        if pressed {
+            self.buttons.insert(button);
+        } else {
+            self.buttons.remove(button);
+        }
+
+        let phase = if pressed && was_empty {
+            FlutterPointerPhase::Down
+        } else if !pressed && self.buttons.is_empty() {
+            FlutterPointerPhase::Up
+        } else {
+            FlutterPointerPhase::Move
+        };
+
+        if let Some(target) = self.target.as_ref() {
+            send_pointer_events(
+                &target.engine,
+                vec![PointerEvent::mouse(phase, target.position, self.buttons)],
+            );
+        }
+    }
+
+    /// Sends a scroll signal, with deltas in pixels.
+    pub fn axis(&mut self, dx: f64, dy: f64) {
+        if let Some(target) = self.target.as_ref() {
+            send_pointer_events(
+                &target.engine,
+                vec![PointerEvent::scroll(
+                    target.position,
+                    (dx, dy),
+                    self.buttons,
+                )],
+            );
+        }
+    }
+
+    /// Removes the pointer from its output, e.g. as the last pointer device went away. Held
+    /// buttons are released first, as flutter expects no buttons on removal.
+    pub fn remove(&mut self) {
+        if let Some(target) = self.target.take() {
+            let mut events = Vec::with_capacity(2);
+            if !self.buttons.is_empty() {
+                events.push(PointerEvent::mouse(
+                    FlutterPointerPhase::Up,
+                    target.position,
+                    PointerButtons::empty(),
+                ));
+            }
+            events.push(PointerEvent::mouse(
+                FlutterPointerPhase::Remove,
+                target.position,
+                PointerButtons::empty(),
+            ));
+            send_pointer_events(&target.engine, events);
+        }
+        self.buttons = PointerButtons::empty();
+        self.update_cursor();
+    }
+
+    fn update_cursor(&self) {
+        if let Some(cursor) = self.cursor.as_ref() {
+            match self.target.as_ref() {
+                Some(target) => {
+                    let position = self.layout.to_panel(&target.engine, target.position);
+                    cursor.lock().move_to(&target.engine, position);
+                }
+                None => cursor.lock().hide(),
+            }
+        }
+    }
+
+    /// Moves the pointer between outputs when it crosses an edge. While buttons are held the
+    /// pointer stays with the output it was pressed on.
+    fn update_target(&mut self) {
+        let (x, y) = self.position;
+
+        if !self.buttons.is_empty() {
+            if let Some(target) = self.target.as_mut() {
+                if let Some(position) = self.layout.to_local(&target.engine, x, y) {
+                    target.position = position;
+                    return;
+                }
+            }
+        }
+
+        let (engine, local_x, local_y) = match self.layout.output_at(x, y) {
+            Some(output) => output,
+            None => {
+                self.remove();
+                return;
+            }
+        };
+
+        if let Some(target) = self.target.as_mut() {
+            if target.engine.ptr_equal(engine.clone()) {
+                target.position = (local_x, local_y);
+                return;
+            }
+        }
+
+        self.remove();
+        if let Some(keyboard) = self.keyboard.as_ref() {
+            keyboard.lock().set_focus(engine.clone());
+        }
+        send_pointer_events(
+            &engine,
+            vec![PointerEvent::mouse(
+                FlutterPointerPhase::Add,
+                (local_x, local_y),
+                PointerButtons::empty(),
+            )],
+        );
+        self.target = Some(PointerTarget {
+            engine,
+            position: (local_x, local_y),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_button_codes() {
+        let map = PointerButtons::from_code;
+        assert_eq!(map(BTN_LEFT), Some(PointerButtons::PRIMARY));
+        assert_eq!(map(BTN_RIGHT), Some(PointerButtons::SECONDARY));
+        assert_eq!(map(BTN_MIDDLE), Some(PointerButtons::MIDDLE));
+        assert_eq!(map(BTN_SIDE), Some(PointerButtons::BACK));
+        assert_eq!(map(BTN_BACK), Some(PointerButtons::BACK));
+        assert_eq!(map(BTN_EXTRA), Some(PointerButtons::FORWARD));
+        assert_eq!(map(BTN_FORWARD), Some(PointerButtons::FORWARD));
+        assert_eq!(map(0x117), None);
+        assert_eq!(map(0), None);
+    }
+
+    #[test]
+    fn tracks_pressed_buttons() {
+        let mut buttons = PointerButtons::empty();
+        assert!(buttons.is_empty());
+
+        buttons.insert(PointerButtons::PRIMARY);
+        buttons.insert(PointerButtons::BACK);
+        assert!(buttons.contains(PointerButtons::PRIMARY));
+        assert!(buttons.contains(PointerButtons::BACK));
+        assert!(!buttons.contains(PointerButtons::SECONDARY));
+
+        buttons.remove(PointerButtons::PRIMARY);
+        assert_eq!(buttons, PointerButtons::BACK);
+        buttons.remove(PointerButtons::BACK);
+        assert!(buttons.is_empty());
+    }
+
+    #[test]
+    fn converts_every_held_button() {
+        let convert = |buttons: PointerButtons| FlutterPointerMouseButtons::from(buttons).bits();
+
+        assert_eq!(convert(PointerButtons::empty()), 0);
+        assert_eq!(convert(PointerButtons::PRIMARY), 1);
+        assert_eq!(convert(PointerButtons::SECONDARY), 2);
+        assert_eq!(convert(PointerButtons::MIDDLE), 4);
+        assert_eq!(convert(PointerButtons::BACK), 8);
+        assert_eq!(convert(PointerButtons::FORWARD), 16);
+
+        let mut buttons = PointerButtons::PRIMARY;
+        buttons.insert(PointerButtons::MIDDLE);
+        buttons.insert(PointerButtons::FORWARD);
+        assert_eq!(convert(buttons), 1 | 4 | 16);
+    }
+}
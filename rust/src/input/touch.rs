@@ -0,0 +1,319 @@
+use crate::input::keyboard::KeyboardManager;
+use crate::input::pointer::{send_pointer_events, PointerButtons, PointerEvent};
+use crate::OutputLayout;
+use flutter_engine::ffi::{
+    FlutterPointerDeviceKind, FlutterPointerPhase, FlutterPointerSignalKind,
+};
+use flutter_engine::FlutterEngineWeakRef;
+use log::debug;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// Touch device ids are allocated from here upwards, leaving room for the mouse.
+const TOUCH_DEVICE_ID_OFFSET: i32 = 1;
+
+struct TouchPoint {
+    engine: FlutterEngineWeakRef,
+    device: i32,
+    position: (f64, f64),
+}
+
+struct SeatTouch<S> {
+    slots: HashMap<S, TouchPoint>,
+    pending: Vec<(FlutterEngineWeakRef, PointerEvent)>,
+}
+
+impl<S> Default for SeatTouch<S> {
+    fn default() -> Self {
+        Self {
+            slots: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+/// Tracks active touch points, keyed by seat and then by the backend's slot identifier.
+pub struct TouchManager<S = u32> {
+    layout: OutputLayout,
+    seats: HashMap<String, SeatTouch<S>>,
+    keyboard: Option<Arc<Mutex<KeyboardManager>>>,
+}
+
+fn touch_event(device: i32, phase: FlutterPointerPhase, position: (f64, f64)) -> PointerEvent {
+    PointerEvent {
+        device_kind: FlutterPointerDeviceKind::Touch,
+        device,
+        phase,
+        position,
+        signal_kind: FlutterPointerSignalKind::None,
+        scroll_delta: (0.0, 0.0),
+        buttons: match phase {
+            FlutterPointerPhase::Down | FlutterPointerPhase::Move => PointerButtons::PRIMARY,
+            _ => PointerButtons::empty(),
+        },
+    }
+}
+
+impl<S: Hash + Eq> TouchManager<S> {
+    pub fn new(layout: OutputLayout) -> Self {
+        Self {
+            layout,
+            seats: HashMap::new(),
+            keyboard: None,
+        }
+    }
+
+    /// Sets the keyboard whose focus follows the last touched output.
+    pub(crate) fn set_keyboard(&mut self, keyboard: Arc<Mutex<KeyboardManager>>) {
+        self.keyboard = Some(keyboard);
+    }
+
+    /// Size of the display receiving touch input, or `None` if there are no outputs.
+    pub fn output_size(&self) -> Option<(u32, u32)> {
+        self.layout.primary_panel_size()
+    }
+
+    /// Starts a new touch point, with the position in display pixels.
+    pub fn down(&mut self, seat: &str, slot: S, position: (f64, f64)) {
+        let position = self.layout.primary_to_logical(position);
+        let engine = match self.layout.primary() {
+            Some((engine, _, _)) => engine,
+            None => return,
+        };
+        if let Some(keyboard) = self.keyboard.as_ref() {
+            keyboard.lock().set_focus(engine.clone());
+        }
+
+        let device = self.allocate_device();
+        let seat = self.seats.entry(seat.to_string()).or_default();
+
+        // A slot can only be down once, drop any stale point using it
+        if let Some(old) = seat.slots.remove(&slot) {
+            debug!("Touch slot pressed twice, cancelling old point");
+            seat.queue_end(old, FlutterPointerPhase::Cancel);
+        }
+
+        seat.pending.push((
+            engine.clone(),
+            touch_event(device, FlutterPointerPhase::Add, position),
+        ));
+        seat.pending.push((
+            engine.clone(),
+            touch_event(device, FlutterPointerPhase::Down, position),
+        ));
+        seat.slots.insert(
+            slot,
+            TouchPoint {
+                engine,
+                device,
+                position,
+            },
+        );
+    }
+
+    pub fn motion(&mut self, seat: &str, slot: S, position: (f64, f64)) {
+        let position = self.layout.primary_to_logical(position);
+        let seat = match self.seats.get_mut(seat) {
+            Some(seat) => seat,
+            None => return,
+        };
+
+        if let Some(point) = seat.slots.get_mut(&slot) {
+            point.position = position;
+            seat.pending.push((
+                point.engine.clone(),
+                touch_event(point.device, FlutterPointerPhase::Move, position),
+            ));
+        }
+    }
+
+    pub fn up(&mut self, seat: &str, slot: S) {
+        let seat = match self.seats.get_mut(seat) {
+            Some(seat) => seat,
+            None => return,
+        };
+
+        if let Some(point) = seat.slots.remove(&slot) {
+            seat.queue_end(point, FlutterPointerPhase::Up);
+        }
+    }
+
+    /// Cancels a single touch point, such as when the system takes over its gesture.
+    pub fn cancel_slot(&mut self, seat: &str, slot: S) {
+        let seat = match self.seats.get_mut(seat) {
+            Some(seat) => seat,
+            None => return,
+        };
+
+        if let Some(point) = seat.slots.remove(&slot) {
+            seat.queue_end(point, FlutterPointerPhase::Cancel);
+        }
+    }
+
+    /// Cancels every active touch point on the seat and sends the result immediately.
+    pub fn cancel(&mut self, seat: &str) {
+        if let Some(touch) = self.seats.get_mut(seat) {
+            let points: Vec<TouchPoint> = touch.slots.drain().map(|(_, point)| point).collect();
+            for point in points {
+                touch.queue_end(point, FlutterPointerPhase::Cancel);
+            }
+        }
+        self.frame(seat);
+    }
+
+    /// Sends all events queued since the last frame.
+    pub fn frame(&mut self, seat: &str) {
+        let seat = match self.seats.get_mut(seat) {
+            Some(seat) => seat,
+            None => return,
+        };
+
+        // Group consecutive events for the same engine into a single batch
+        let mut batch: Option<(FlutterEngineWeakRef, Vec<PointerEvent>)> = None;
+        for (engine, event) in seat.pending.drain(..) {
+            match batch.as_mut() {
+                Some((current, events)) if current.ptr_equal(engine.clone()) => events.push(event),
+                _ => {
+                    if let Some((current, events)) = batch.take() {
+                        send_pointer_events(&current, events);
+                    }
+                    batch = Some((engine, vec![event]));
+                }
+            }
+        }
+        if let Some((current, events)) = batch {
+            send_pointer_events(&current, events);
+        }
+    }
+
+    /// Picks the lowest device id not used by any active touch point.
+    fn allocate_device(&self) -> i32 {
+        let mut device = TOUCH_DEVICE_ID_OFFSET;
+        while self
+            .seats
+            .values()
+            .flat_map(|seat| seat.slots.values())
+            .any(|point| point.device == device)
+        {
+            device += 1;
+        }
+        device
+    }
+}
+
+impl<S> SeatTouch<S> {
+    fn queue_end(&mut self, point: TouchPoint, phase: FlutterPointerPhase) {
+        self.pending.push((
+            point.engine.clone(),
+            touch_event(point.device, phase, point.position),
+        ));
+        self.pending.push((
+            point.engine,
+            touch_event(point.device, FlutterPointerPhase::Remove, point.position),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flutter_engine::ffi::FlutterPointerPhase::{Add, Cancel, Down, Move, Remove, Up};
+
+    const SEAT: &str = "seat0";
+
+    fn manager() -> TouchManager {
+        let layout = OutputLayout::new();
+        layout.add(FlutterEngineWeakRef::default(), 800, 600);
+        TouchManager::new(layout)
+    }
+
+    /// Device and phase of each event queued on the seat.
+    fn pending(touch: &TouchManager, seat: &str) -> Vec<(i32, FlutterPointerPhase)> {
+        touch.seats[seat]
+            .pending
+            .iter()
+            .map(|(_, event)| (event.device, event.phase))
+            .collect()
+    }
+
+    fn device(touch: &TouchManager, seat: &str, slot: u32) -> Option<i32> {
+        touch.seats[seat].slots.get(&slot).map(|point| point.device)
+    }
+
+    #[test]
+    fn ends_the_old_point_when_a_slot_is_reused() {
+        let mut touch = manager();
+        touch.down(SEAT, 0, (10.0, 10.0));
+        touch.down(SEAT, 0, (20.0, 20.0));
+
+        assert!(matches!(
+            pending(&touch, SEAT)[..],
+            [
+                (1, Add),
+                (1, Down),
+                (1, Cancel),
+                (1, Remove),
+                (2, Add),
+                (2, Down)
+            ]
+        ));
+        assert_eq!(touch.seats[SEAT].slots.len(), 1);
+        assert_eq!(device(&touch, SEAT, 0), Some(2));
+    }
+
+    #[test]
+    fn allocates_the_lowest_free_device() {
+        let mut touch = manager();
+        touch.down(SEAT, 0, (10.0, 10.0));
+        touch.down(SEAT, 1, (20.0, 20.0));
+        assert_eq!(device(&touch, SEAT, 0), Some(TOUCH_DEVICE_ID_OFFSET));
+        assert_eq!(device(&touch, SEAT, 1), Some(TOUCH_DEVICE_ID_OFFSET + 1));
+
+        // Ids are freed on release and shared between seats
+        touch.up(SEAT, 0);
+        touch.down(SEAT, 2, (30.0, 30.0));
+        touch.down("seat1", 0, (40.0, 40.0));
+        assert_eq!(device(&touch, SEAT, 2), Some(TOUCH_DEVICE_ID_OFFSET));
+        assert_eq!(device(&touch, "seat1", 0), Some(TOUCH_DEVICE_ID_OFFSET + 2));
+    }
+
+    #[test]
+    fn queues_events_until_the_frame() {
+        let mut touch = manager();
+        touch.down(SEAT, 0, (10.0, 10.0));
+        touch.motion(SEAT, 0, (30.0, 40.0));
+        touch.motion(SEAT, 1, (50.0, 50.0));
+        assert!(matches!(
+            pending(&touch, SEAT)[..],
+            [(1, Add), (1, Down), (1, Move)]
+        ));
+        assert_eq!(touch.seats[SEAT].slots[&0].position, (30.0, 40.0));
+
+        touch.frame(SEAT);
+        assert!(pending(&touch, SEAT).is_empty());
+
+        touch.up(SEAT, 0);
+        assert!(matches!(pending(&touch, SEAT)[..], [(1, Up), (1, Remove)]));
+        touch.frame(SEAT);
+        assert!(pending(&touch, SEAT).is_empty());
+        assert!(touch.seats[SEAT].slots.is_empty());
+    }
+
+    #[test]
+    fn cancels_only_the_given_slot() {
+        let mut touch = manager();
+        touch.down(SEAT, 0, (10.0, 10.0));
+        touch.down(SEAT, 1, (20.0, 20.0));
+        touch.frame(SEAT);
+
+        touch.cancel_slot(SEAT, 1);
+        assert!(matches!(
+            pending(&touch, SEAT)[..],
+            [(2, Cancel), (2, Remove)]
+        ));
+        assert_eq!(device(&touch, SEAT, 0), Some(1));
+        assert_eq!(device(&touch, SEAT, 1), None);
+    }
+}
@@ -1,85 +1,101 @@
-use crate::input::keyboard::KeyboardManager;
-use parking_lot::Mutex;
-use smithay::backend::input::{InputHandler, KeyboardKeyEvent, Seat, UnusedEvent};
-use smithay::backend::winit::{
-    WinitInputBackend, WinitKeyboardInputEvent, WinitMouseInputEvent, WinitMouseMovedEvent,
-    WinitMouseWheelEvent, WinitTouchCancelledEvent, WinitTouchEndedEvent, WinitTouchMovedEvent,
-    WinitTouchStartedEvent,
+use crate::input::keyboard::{KeyboardManager, KeyboardSource};
+use crate::input::pointer::{PointerButtons, PointerManager, SCROLL_LINE_HEIGHT};
+use crate::input::touch::TouchManager;
+use crate::OutputLayout;
+use ::winit::event::{
+    ElementState, KeyboardInput, MouseButton, MouseScrollDelta, Touch, TouchPhase, WindowEvent,
 };
+use parking_lot::Mutex;
+use smithay::backend::input::KeyState;
 use std::sync::Arc;
 
+/// Name of the single seat of a window, for touch tracking.
+const SEAT_NAME: &str = "winit";
+
 pub struct WinitInputHandler {
     keyboard: Arc<Mutex<KeyboardManager>>,
+    layout: OutputLayout,
+    pointer: PointerManager,
+    touch: TouchManager<u64>,
+    scale: f64,
 }
 
 impl WinitInputHandler {
-    pub fn new(keyboard: Arc<Mutex<KeyboardManager>>) -> Self {
-        Self { keyboard }
+    pub fn new(keyboard: Arc<Mutex<KeyboardManager>>, layout: OutputLayout, scale: f64) -> Self {
+        Self {
+            keyboard,
+            pointer: PointerManager::new(layout.clone()),
+            touch: TouchManager::new(layout.clone()),
+            layout,
+            scale,
+        }
     }
-}
 
-impl InputHandler<WinitInputBackend> for WinitInputHandler {
-    fn on_seat_created(&mut self, seat: &Seat) {
-        // currently we just create a single static one
+    /// Forwards a window's input event to its engine, ignoring events which aren't input.
+    pub fn handle_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::KeyboardInput { input, .. } => self.on_keyboard_key(input),
+            WindowEvent::CursorMoved { position, .. } => {
+                // Winit reports physical pixels, as the display does
+                let (x, y) = self.layout.primary_to_logical((position.x, position.y));
+                self.pointer.motion_absolute(x, y);
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                let button = match button {
+                    MouseButton::Left => PointerButtons::PRIMARY,
+                    MouseButton::Right => PointerButtons::SECONDARY,
+                    MouseButton::Middle => PointerButtons::MIDDLE,
+                    MouseButton::Other(_) => return,
+                };
+                self.pointer
+                    .set_button(button, *state == ElementState::Pressed);
+            }
+            WindowEvent::MouseWheel { delta, .. } => self.on_scroll(*delta),
+            WindowEvent::Touch(touch) => self.on_touch(touch),
+            WindowEvent::ScaleFactorChanged { scale_factor, .. } => self.scale = *scale_factor,
+            _ => {}
+        }
     }
 
-    fn on_seat_destroyed(&mut self, seat: &Seat) {
-        // currently we just create a single static one
-    }
-
-    fn on_seat_changed(&mut self, seat: &Seat) {
-        // currently we just create a single static one
-    }
-
-    fn on_keyboard_key(&mut self, seat: &Seat, event: WinitKeyboardInputEvent) {
+    fn on_keyboard_key(&mut self, input: &KeyboardInput) {
         let mut keyboard = self.keyboard.lock();
 
         // TODO: Select keyboard layout based on winit settings
-        keyboard.select_layout();
-
-        // Send key press
-        let keycode = event.key_code();
-        let state = event.state();
-        keyboard.key(keycode, state);
-    }
-
-    fn on_pointer_move(&mut self, seat: &Seat, event: UnusedEvent) {
-        // Not supported by winit
-    }
-
-    fn on_pointer_move_absolute(&mut self, seat: &Seat, event: WinitMouseMovedEvent) {
-        // TODO: Implement pointer support
-    }
-
-    fn on_pointer_button(&mut self, seat: &Seat, event: WinitMouseInputEvent) {
-        // TODO: Implement pointer support
-    }
-
-    fn on_pointer_axis(&mut self, seat: &Seat, event: WinitMouseWheelEvent) {
-        // TODO: Implement pointer support
-    }
-
-    fn on_touch_down(&mut self, seat: &Seat, event: WinitTouchStartedEvent) {
-        // TODO: Implement touch support
-    }
-
-    fn on_touch_motion(&mut self, seat: &Seat, event: WinitTouchMovedEvent) {
-        // TODO: Implement touch support
-    }
-
-    fn on_touch_up(&mut self, seat: &Seat, event: WinitTouchEndedEvent) {
-        // TODO: Implement touch support
-    }
-
-    fn on_touch_cancel(&mut self, seat: &Seat, event: WinitTouchCancelledEvent) {
-        // TODO: Implement touch support
+        keyboard.select_layout(KeyboardSource::Default, || None);
+
+        // Winit reports evdev scancodes on linux
+        let state = match input.state {
+            ElementState::Pressed => KeyState::Pressed,
+            ElementState::Released => KeyState::Released,
+        };
+        keyboard.key(&KeyboardSource::Default, input.scancode, state);
     }
 
-    fn on_touch_frame(&mut self, seat: &Seat, event: UnusedEvent) {
-        // Not supported by winit
+    fn on_scroll(&mut self, delta: MouseScrollDelta) {
+        // Winit uses positive values for scrolling up, flutter for scrolling down
+        let (dx, dy) = match delta {
+            MouseScrollDelta::LineDelta(x, y) => (
+                -x as f64 * SCROLL_LINE_HEIGHT * self.scale,
+                -y as f64 * SCROLL_LINE_HEIGHT * self.scale,
+            ),
+            MouseScrollDelta::PixelDelta(delta) => (-delta.x * self.scale, -delta.y * self.scale),
+        };
+
+        if dx != 0.0 || dy != 0.0 {
+            self.pointer.axis(dx, dy);
+        }
     }
 
-    fn on_input_config_changed(&mut self, config: &mut ()) {
-        // Not supported by winit
+    fn on_touch(&mut self, touch: &Touch) {
+        let position = (touch.location.x, touch.location.y);
+        match touch.phase {
+            TouchPhase::Started => self.touch.down(SEAT_NAME, touch.id, position),
+            TouchPhase::Moved => self.touch.motion(SEAT_NAME, touch.id, position),
+            TouchPhase::Ended => self.touch.up(SEAT_NAME, touch.id),
+            TouchPhase::Cancelled => self.touch.cancel_slot(SEAT_NAME, touch.id),
+        }
+
+        // Events are flushed as they arrive, winit has no touch frames
+        self.touch.frame(SEAT_NAME);
     }
 }
@@ -1,38 +1,75 @@
+pub mod cursor;
+pub mod edid;
 mod egl_util;
+pub mod error;
+mod gl_util;
+pub mod gpu;
 pub(crate) mod handler;
+pub mod headless;
 pub(crate) mod input;
+mod lifecycle;
 pub mod output;
 pub mod udev;
+mod vsync;
 pub mod winit;
 
+pub use crate::error::Error;
+pub use crate::input::keyboard::{KeyboardConfig, KeyboardDevice};
+pub use crate::output::Transform;
+
 use flutter_engine::{FlutterEngine, FlutterEngineWeakRef};
 use parking_lot::{RwLock, RwLockUpgradableReadGuard};
-use smithay::reexports::calloop::EventLoop;
+use smithay::reexports::calloop::{EventLoop, LoopSignal};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 pub struct FlutterDrmManager {
     pub(crate) event_loop: EventLoop<()>,
+    running: Arc<AtomicBool>,
 }
 
 impl FlutterDrmManager {
     pub fn new() -> Self {
         Self {
             event_loop: EventLoop::<()>::new().unwrap(),
+            running: Arc::new(AtomicBool::new(true)),
         }
     }
 
-    pub fn run(&mut self) {
-        let running = Arc::new(AtomicBool::new(true));
+    /// Returns a handle which stops `run` from any thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            running: self.running.clone(),
+            signal: self.event_loop.get_signal(),
+        }
+    }
 
-        while running.load(Ordering::SeqCst) {
+    /// Dispatches events until a `ShutdownHandle` stops it. Afterwards, `cleanup` the output
+    /// managers to shut their engines down and give the displays back.
+    pub fn run(&mut self) {
+        while self.running.load(Ordering::SeqCst) {
             if self.event_loop.dispatch(None, &mut ()).is_err() {
-                running.store(false, Ordering::SeqCst);
+                self.running.store(false, Ordering::SeqCst);
             }
         }
     }
 }
 
+/// Stops a running `FlutterDrmManager`, such as from a signal handler or a flutter plugin.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    running: Arc<AtomicBool>,
+    signal: LoopSignal,
+}
+
+impl ShutdownHandle {
+    /// Makes `FlutterDrmManager::run` return once it finishes the current dispatch.
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.signal.wakeup();
+    }
+}
+
 pub struct EngineWeakCollection {
     engines: Arc<RwLock<Vec<FlutterEngineWeakRef>>>,
 }
@@ -56,6 +93,14 @@ impl EngineWeakCollection {
         self.engines.write().push(engine);
     }
 
+    /// The first engine which is still alive.
+    pub fn first(&self) -> Option<FlutterEngine> {
+        self.engines
+            .read()
+            .iter()
+            .find_map(|engine| engine.upgrade())
+    }
+
     pub fn for_each<F>(&self, func: F)
     where
         F: Fn(FlutterEngine),
@@ -77,3 +122,357 @@ impl EngineWeakCollection {
         }
     }
 }
+
+/// Identifies the output an entry of a layout belongs to.
+trait OutputKey: Clone {
+    fn same_output(&self, other: &Self) -> bool;
+}
+
+impl OutputKey for FlutterEngineWeakRef {
+    fn same_output(&self, other: &Self) -> bool {
+        self.ptr_equal(other.clone())
+    }
+}
+
+struct LayoutOutput<K> {
+    key: K,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    transform: Transform,
+}
+
+impl<K> LayoutOutput<K> {
+    fn contains(&self, x: f64, y: f64) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+/// The geometry behind `OutputLayout`, for any kind of output key.
+struct Layout<K> {
+    outputs: Vec<LayoutOutput<K>>,
+}
+
+impl<K: OutputKey> Layout<K> {
+    fn new() -> Self {
+        Self {
+            outputs: Vec::new(),
+        }
+    }
+
+    fn find(&self, key: &K) -> Option<&LayoutOutput<K>> {
+        self.outputs.iter().find(|o| o.key.same_output(key))
+    }
+
+    fn add(&mut self, key: K, width: u32, height: u32) {
+        let x = self.outputs.last().map(|o| o.x + o.width).unwrap_or(0.0);
+
+        self.outputs.push(LayoutOutput {
+            key,
+            x,
+            y: 0.0,
+            width: width as f64,
+            height: height as f64,
+            transform: Transform::Normal,
+        });
+    }
+
+    fn set_transform(&mut self, key: &K, transform: Transform) {
+        for output in self.outputs.iter_mut() {
+            if output.key.same_output(key) {
+                output.transform = transform;
+            }
+        }
+    }
+
+    fn resize(&mut self, key: &K, width: u32, height: u32) {
+        for output in self.outputs.iter_mut() {
+            if output.key.same_output(key) {
+                output.width = width as f64;
+                output.height = height as f64;
+            }
+        }
+        self.arrange();
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.outputs.retain(|o| !o.key.same_output(key));
+        self.arrange();
+    }
+
+    /// Places the outputs next to each other again, after one changed size or went away.
+    fn arrange(&mut self) {
+        let mut x = 0.0;
+        for output in self.outputs.iter_mut() {
+            output.x = x;
+            x += output.width;
+        }
+    }
+
+    fn size(&self) -> (f64, f64) {
+        let width = self
+            .outputs
+            .iter()
+            .map(|o| o.x + o.width)
+            .fold(0.0, f64::max);
+        let height = self
+            .outputs
+            .iter()
+            .map(|o| o.y + o.height)
+            .fold(0.0, f64::max);
+        (width, height)
+    }
+
+    fn clamp(&self, x: f64, y: f64) -> (f64, f64) {
+        if self.outputs.is_empty() {
+            return (0.0, 0.0);
+        }
+
+        let (width, _) = self.size();
+        let x = x.max(0.0).min(width - 1.0);
+
+        match self.outputs.iter().find(|o| x >= o.x && x < o.x + o.width) {
+            Some(output) => (x, y.max(output.y).min(output.y + output.height - 1.0)),
+            None => (x, y.max(0.0)),
+        }
+    }
+
+    fn primary(&self) -> Option<&LayoutOutput<K>> {
+        self.outputs.first()
+    }
+
+    fn primary_panel_size(&self) -> Option<(u32, u32)> {
+        self.primary().map(|o| {
+            o.transform
+                .transform_size((o.width as u32, o.height as u32))
+        })
+    }
+
+    fn primary_to_logical(&self, position: (f64, f64)) -> (f64, f64) {
+        match self.primary() {
+            Some(o) => o.transform.to_logical(position, (o.width, o.height)),
+            None => position,
+        }
+    }
+
+    fn output_at(&self, x: f64, y: f64) -> Option<(K, f64, f64)> {
+        self.outputs
+            .iter()
+            .find(|o| o.contains(x, y))
+            .map(|o| (o.key.clone(), x - o.x, y - o.y))
+    }
+
+    fn to_local(&self, key: &K, x: f64, y: f64) -> Option<(f64, f64)> {
+        self.find(key).map(|o| (x - o.x, y - o.y))
+    }
+
+    fn to_panel(&self, key: &K, position: (f64, f64)) -> (f64, f64) {
+        match self.find(key) {
+            Some(o) => o.transform.to_panel(position, (o.width, o.height)),
+            None => position,
+        }
+    }
+}
+
+/// Tracks where each output sits in the global input coordinate space.
+///
+/// Outputs are placed left to right in the order they are added, top aligned.
+pub struct OutputLayout {
+    layout: Arc<RwLock<Layout<FlutterEngineWeakRef>>>,
+}
+
+impl Clone for OutputLayout {
+    fn clone(&self) -> Self {
+        Self {
+            layout: self.layout.clone(),
+        }
+    }
+}
+
+impl OutputLayout {
+    pub fn new() -> Self {
+        Self {
+            layout: Arc::new(RwLock::new(Layout::new())),
+        }
+    }
+
+    pub fn add(&self, engine: FlutterEngineWeakRef, width: u32, height: u32) {
+        self.layout.write().add(engine, width, height);
+    }
+
+    /// Sets how an output's content is oriented on its display, which decides how positions from
+    /// absolute input devices and for the cursor are converted.
+    pub(crate) fn set_transform(&self, engine: &FlutterEngineWeakRef, transform: Transform) {
+        self.layout.write().set_transform(engine, transform);
+    }
+
+    /// Updates the size of an output, shifting any outputs to its right.
+    pub(crate) fn resize(&self, engine: &FlutterEngineWeakRef, width: u32, height: u32) {
+        self.layout.write().resize(engine, width, height);
+    }
+
+    /// Removes an output, shifting any outputs to its right into the gap.
+    pub(crate) fn remove(&self, engine: &FlutterEngineWeakRef) {
+        self.layout.write().remove(engine);
+    }
+
+    /// Total size of the area covered by all outputs.
+    pub fn size(&self) -> (f64, f64) {
+        self.layout.read().size()
+    }
+
+    /// Restricts the given position to lie within an output.
+    pub fn clamp(&self, x: f64, y: f64) -> (f64, f64) {
+        self.layout.read().clamp(x, y)
+    }
+
+    /// The first output added, which receives input from absolute devices like touchscreens.
+    pub(crate) fn primary(&self) -> Option<(FlutterEngineWeakRef, f64, f64)> {
+        let layout = self.layout.read();
+        layout.primary().map(|o| (o.key.clone(), o.width, o.height))
+    }
+
+    /// Size of the primary output's display in pixels, before its transform. Absolute input
+    /// devices report positions in this space.
+    pub(crate) fn primary_panel_size(&self) -> Option<(u32, u32)> {
+        self.layout.read().primary_panel_size()
+    }
+
+    /// Converts a position on the primary output's display into one relative to its content.
+    pub(crate) fn primary_to_logical(&self, position: (f64, f64)) -> (f64, f64) {
+        self.layout.read().primary_to_logical(position)
+    }
+
+    /// Finds the output at the given global position, returning its engine and the position
+    /// relative to that output.
+    pub(crate) fn output_at(&self, x: f64, y: f64) -> Option<(FlutterEngineWeakRef, f64, f64)> {
+        self.layout.read().output_at(x, y)
+    }
+
+    /// Converts a global position into one relative to the output owning the given engine.
+    pub(crate) fn to_local(
+        &self,
+        engine: &FlutterEngineWeakRef,
+        x: f64,
+        y: f64,
+    ) -> Option<(f64, f64)> {
+        self.layout.read().to_local(engine, x, y)
+    }
+
+    /// Converts a position relative to an output's content into one on its display.
+    pub(crate) fn to_panel(
+        &self,
+        engine: &FlutterEngineWeakRef,
+        position: (f64, f64),
+    ) -> (f64, f64) {
+        self.layout.read().to_panel(engine, position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl OutputKey for u32 {
+        fn same_output(&self, other: &Self) -> bool {
+            self == other
+        }
+    }
+
+    /// An 800x600 output next to a taller 1024x768 one.
+    fn layout() -> Layout<u32> {
+        let mut layout = Layout::new();
+        layout.add(1, 800, 600);
+        layout.add(2, 1024, 768);
+        layout
+    }
+
+    #[test]
+    fn places_outputs_left_to_right() {
+        let layout = layout();
+        assert_eq!(layout.size(), (1824.0, 768.0));
+
+        assert_eq!(layout.output_at(10.0, 20.0), Some((1, 10.0, 20.0)));
+        assert_eq!(layout.output_at(799.5, 599.5), Some((1, 799.5, 599.5)));
+        assert_eq!(layout.output_at(800.0, 0.0), Some((2, 0.0, 0.0)));
+        assert_eq!(layout.output_at(900.0, 700.0), Some((2, 100.0, 700.0)));
+
+        // Below the shorter output, and outside of all outputs
+        assert_eq!(layout.output_at(100.0, 700.0), None);
+        assert_eq!(layout.output_at(-1.0, 0.0), None);
+        assert_eq!(layout.output_at(1824.0, 0.0), None);
+
+        assert_eq!(layout.to_local(&2, 900.0, 50.0), Some((100.0, 50.0)));
+        assert_eq!(layout.to_local(&1, 900.0, 50.0), Some((900.0, 50.0)));
+        assert_eq!(layout.to_local(&3, 900.0, 50.0), None);
+    }
+
+    #[test]
+    fn prefers_the_first_of_overlapping_outputs() {
+        let mut layout = layout();
+        layout.outputs[1].x = 400.0;
+
+        assert_eq!(layout.output_at(500.0, 10.0), Some((1, 500.0, 10.0)));
+        assert_eq!(layout.output_at(900.0, 10.0), Some((2, 500.0, 10.0)));
+        assert_eq!(layout.size(), (1424.0, 768.0));
+    }
+
+    #[test]
+    fn clamps_to_the_edges() {
+        let layout = layout();
+        assert_eq!(layout.clamp(-10.0, -10.0), (0.0, 0.0));
+        assert_eq!(layout.clamp(5000.0, 5000.0), (1823.0, 767.0));
+        assert_eq!(layout.clamp(400.0, 700.0), (400.0, 599.0));
+        assert_eq!(layout.clamp(900.0, 700.0), (900.0, 700.0));
+        assert_eq!(layout.clamp(799.9, 650.0), (799.9, 599.0));
+
+        assert_eq!(Layout::<u32>::new().clamp(10.0, 10.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn shifts_outputs_on_resize() {
+        let mut layout = layout();
+        layout.resize(&1, 1280, 720);
+
+        assert_eq!(layout.size(), (2304.0, 768.0));
+        assert_eq!(layout.output_at(1000.0, 700.0), Some((1, 1000.0, 700.0)));
+        assert_eq!(layout.output_at(1300.0, 10.0), Some((2, 20.0, 10.0)));
+        assert_eq!(layout.to_local(&2, 1300.0, 10.0), Some((20.0, 10.0)));
+        assert_eq!(layout.clamp(1000.0, 740.0), (1000.0, 719.0));
+    }
+
+    #[test]
+    fn closes_the_gap_of_removed_outputs() {
+        let mut layout = layout();
+        layout.remove(&1);
+
+        assert_eq!(layout.size(), (1024.0, 768.0));
+        assert_eq!(layout.output_at(10.0, 700.0), Some((2, 10.0, 700.0)));
+        assert_eq!(layout.to_local(&1, 10.0, 10.0), None);
+        assert_eq!(layout.primary().map(|o| o.key), Some(2));
+        assert_eq!(layout.clamp(2000.0, 10.0), (1023.0, 10.0));
+
+        layout.remove(&2);
+        assert_eq!(layout.output_at(10.0, 10.0), None);
+        assert_eq!(layout.size(), (0.0, 0.0));
+        assert_eq!(layout.primary_to_logical((5.0, 6.0)), (5.0, 6.0));
+    }
+
+    #[test]
+    fn converts_positions_on_the_primary_output() {
+        let mut layout = layout();
+        assert_eq!(layout.primary_to_logical((100.0, 50.0)), (100.0, 50.0));
+        assert_eq!(layout.primary_panel_size(), Some((800, 600)));
+
+        // The content of a portrait panel, rotated onto an 800x600 display
+        layout.resize(&1, 600, 800);
+        layout.set_transform(&1, Transform::Rotate90);
+        assert_eq!(layout.primary_panel_size(), Some((800, 600)));
+        assert_eq!(layout.primary_to_logical((100.0, 50.0)), (50.0, 700.0));
+        assert_eq!(layout.to_panel(&1, (50.0, 700.0)), (100.0, 50.0));
+
+        // Transforms only apply to their own output
+        assert_eq!(layout.to_panel(&2, (50.0, 700.0)), (50.0, 700.0));
+    }
+}
@@ -0,0 +1,57 @@
+use flutter_engine::channel::{BasicMessageChannel, ChannelRegistrar, MessageHandler};
+use flutter_engine::codec::{string_codec, Value};
+use flutter_engine::plugins::Plugin;
+use flutter_engine::FlutterEngine;
+use parking_lot::RwLock;
+use std::sync::{Arc, Weak};
+
+pub const PLUGIN_NAME: &str = module_path!();
+pub const CHANNEL_NAME: &str = "flutter/lifecycle";
+
+const STATE_DETACHED: &str = "AppLifecycleState.detached";
+
+/// Tells the app about changes to its lifecycle state.
+pub(crate) struct LifecyclePlugin {
+    channel: Weak<BasicMessageChannel>,
+    handler: Arc<RwLock<Handler>>,
+}
+
+struct Handler;
+
+impl LifecyclePlugin {
+    pub fn new() -> Self {
+        Self {
+            channel: Weak::new(),
+            handler: Arc::new(RwLock::new(Handler)),
+        }
+    }
+
+    /// Tells the app that its engine is about to shut down.
+    pub fn send_detached(&self) {
+        if let Some(channel) = self.channel.upgrade() {
+            channel.send(&Value::String(STATE_DETACHED.to_owned()));
+        }
+    }
+}
+
+impl Plugin for LifecyclePlugin {
+    fn plugin_name() -> &'static str {
+        PLUGIN_NAME
+    }
+
+    fn init_channels(&mut self, registrar: &mut ChannelRegistrar) {
+        let message_handler = Arc::downgrade(&self.handler);
+        self.channel = registrar.register_channel(BasicMessageChannel::new(
+            CHANNEL_NAME,
+            message_handler,
+            &string_codec::CODEC,
+        ));
+    }
+}
+
+impl MessageHandler for Handler {
+    fn on_message(&mut self, _message: Value, _engine: FlutterEngine) -> Value {
+        // Only the embedder sends on this channel
+        Value::Null
+    }
+}
@@ -1,18 +1,26 @@
 use crate::egl_util::{WrappedContext, WrappedDisplay};
-use crate::handler::{SmithayOpenGLHandler, SmithayPlatformTaskHandler, SmithayTextInputHandler};
-use crossbeam::sync::Parker;
+use crate::error::Error;
+use crate::handler::{
+    FrameReader, SharedOffscreen, SmithayOpenGLHandler, SmithayPlatformTaskHandler,
+    SmithaySoftwareHandler, SmithayTextInputHandler, SmithayVsyncHandler, SoftwareFrame,
+};
+use crate::vsync::FrameClock;
+use crossbeam::sync::{Parker, Unparker};
 use flutter_engine::FlutterEngine;
-use log::debug;
+use log::{debug, error, warn};
 use std::panic::AssertUnwindSafe;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc};
 
 use crate::input::keyboard::KeyboardManager;
+use crate::lifecycle::LifecyclePlugin;
+use crate::OutputLayout;
 use flutter_engine::builder::FlutterEngineBuilder;
 use flutter_plugins::keyevent::KeyEventPlugin;
 use flutter_plugins::textinput::TextInputPlugin;
 use parking_lot::Mutex;
 use std::path::PathBuf;
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 use std::{panic, thread};
 
@@ -22,96 +30,509 @@ pub trait FlutterOutputBackend {
     fn make_current(&self) -> Result<(), ()>;
 
     fn get_framebuffer_dimensions(&self) -> (u32, u32);
+
+    fn vsync_mode(&self) -> VsyncMode;
+
+    fn pixel_ratio(&self) -> f64;
+
+    /// Control over the display mode, for backends which drive the display directly.
+    fn mode_control(&self) -> Option<Arc<dyn ModeControl>>;
+
+    /// Lets the display hardware apply the transform. Returns false if it can't, in which case
+    /// the frame is transformed while rendering instead. Also called with `Transform::Normal`, to
+    /// undo any transform left behind by a previous user of the display.
+    fn apply_transform(&self, transform: Transform) -> bool;
 }
 
-pub struct FlutterOutput {
-    engine: FlutterEngine,
+/// A backend showing frames drawn by flutter's software renderer, for hardware without a GPU.
+pub trait SoftwareOutputBackend {
+    /// Shows a frame of premultiplied 32 bit pixels, laid out as `XRGB8888` in little endian
+    /// (blue in the first byte), with `row_bytes` between the start of each row.
+    fn present(&self, pixels: &[u8], row_bytes: usize, height: usize) -> Result<(), ()>;
+
+    fn get_framebuffer_dimensions(&self) -> (u32, u32);
+
+    fn vsync_mode(&self) -> VsyncMode;
+
+    fn pixel_ratio(&self) -> f64;
+
+    /// Control over the display mode, for backends which drive the display directly.
+    fn mode_control(&self) -> Option<Arc<dyn ModeControl>>;
+}
+
+/// Rotation and reflection of an output's content, for displays that are not mounted upright.
+///
+/// Rotations are clockwise. The flipped variants mirror the content horizontally before rotating.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Transform {
+    #[default]
+    Normal,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    Flipped,
+    Flipped90,
+    Flipped180,
+    Flipped270,
+}
+
+impl Transform {
+    /// Whether the content's width runs along the panel's height.
+    pub fn swaps_axes(self) -> bool {
+        matches!(
+            self,
+            Transform::Rotate90
+                | Transform::Rotate270
+                | Transform::Flipped90
+                | Transform::Flipped270
+        )
+    }
+
+    /// Converts between panel and content sizes, which works the same in both directions.
+    pub fn transform_size(self, (width, height): (u32, u32)) -> (u32, u32) {
+        if self.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// Converts a position in panel pixels, such as from a touchscreen, into content pixels. The
+    /// size is that of the content.
+    pub fn to_logical(self, (x, y): (f64, f64), (width, height): (f64, f64)) -> (f64, f64) {
+        match self {
+            Transform::Normal => (x, y),
+            Transform::Rotate90 => (y, height - x),
+            Transform::Rotate180 => (width - x, height - y),
+            Transform::Rotate270 => (width - y, x),
+            Transform::Flipped => (width - x, y),
+            Transform::Flipped90 => (width - y, height - x),
+            Transform::Flipped180 => (x, height - y),
+            Transform::Flipped270 => (y, x),
+        }
+    }
+
+    /// Converts a position in content pixels into panel pixels, the inverse of `to_logical`.
+    pub fn to_panel(self, (x, y): (f64, f64), (width, height): (f64, f64)) -> (f64, f64) {
+        match self {
+            Transform::Normal => (x, y),
+            Transform::Rotate90 => (height - y, x),
+            Transform::Rotate180 => (width - x, height - y),
+            Transform::Rotate270 => (y, width - x),
+            Transform::Flipped => (width - x, y),
+            Transform::Flipped90 => (height - y, width - x),
+            Transform::Flipped180 => (x, height - y),
+            Transform::Flipped270 => (y, x),
+        }
+    }
+
+    /// Texture coordinates of the content shown at the bottom left, bottom right, top left and
+    /// top right corners of the panel.
+    pub(crate) fn source_coords(self) -> [f32; 8] {
+        match self {
+            Transform::Normal => [0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0],
+            Transform::Rotate90 => [1.0, 0.0, 1.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+            Transform::Rotate180 => [1.0, 1.0, 0.0, 1.0, 1.0, 0.0, 0.0, 0.0],
+            Transform::Rotate270 => [0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0],
+            Transform::Flipped => [1.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 1.0],
+            Transform::Flipped90 => [0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 1.0, 1.0],
+            Transform::Flipped180 => [0.0, 1.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0],
+            Transform::Flipped270 => [1.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+        }
+    }
+}
+
+/// A display mode supported by an output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputMode {
+    pub(crate) id: usize,
+    pub(crate) size: (u32, u32),
+    pub(crate) refresh: u32,
+    pub(crate) preferred: bool,
+}
+
+impl OutputMode {
+    /// Resolution in pixels.
+    pub fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    /// Refresh rate in Hz.
+    pub fn refresh(&self) -> u32 {
+        self.refresh
+    }
+
+    /// Whether the display reports this as its native mode.
+    pub fn is_preferred(&self) -> bool {
+        self.preferred
+    }
+}
+
+pub trait ModeControl: Send + Sync {
+    fn modes(&self) -> Vec<OutputMode>;
+
+    fn current_mode(&self) -> OutputMode;
+
+    /// Queues a switch to the given mode, which the render thread applies before its next frame.
+    /// The receiver gets the new pixel ratio once the display has switched, or the error if it
+    /// couldn't.
+    fn set_mode(&self, mode: &OutputMode) -> Result<mpsc::Receiver<Result<f64, Error>>, Error>;
+
+    /// Drops the queued mode switch, returning false if the render thread already started on it.
+    fn cancel_mode(&self) -> bool;
+}
+
+/// Flutter lays out its logical pixels at roughly 96 per inch.
+const LOGICAL_DPI: f64 = 96.0;
+
+/// Derives the device pixel ratio from a display's resolution and physical size in millimetres,
+/// rounded to quarter steps. Displays of unknown size, and low density ones such as TVs, use 1.
+pub fn pixel_ratio_from_physical(size: (u32, u32), physical_mm: (u32, u32)) -> f64 {
+    if physical_mm.0 == 0 || physical_mm.1 == 0 {
+        return 1.0;
+    }
+
+    let diagonal_px = (size.0 as f64).hypot(size.1 as f64);
+    let diagonal_in = (physical_mm.0 as f64).hypot(physical_mm.1 as f64) / 25.4;
+    let ratio = diagonal_px / diagonal_in / LOGICAL_DPI;
+
+    ((ratio * 4.0).round() / 4.0).max(1.0)
+}
+
+/// How flutter's frames are paced.
+#[derive(Copy, Clone, Debug)]
+pub enum VsyncMode {
+    /// Frames start on vblank events, reported through `FlutterOutput::vblank`.
+    Vblank { interval: Duration },
+    /// The display gives no feedback, frames start on a timer instead.
+    Timer { interval: Duration },
+}
+
+impl VsyncMode {
+    /// Refresh interval for the given refresh rate in Hz, falling back to 60Hz when unknown.
+    pub fn interval_from_refresh(refresh: u32) -> Duration {
+        let refresh = if refresh == 0 { 60 } else { refresh };
+        Duration::from_nanos(1_000_000_000 / refresh as u64)
+    }
+}
+
+/// How long `FlutterOutput::capture_frame` waits for flutter to finish a frame it is drawing.
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long `FlutterOutput::set_mode` waits for the render thread to switch modes.
+const MODE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the app gets to handle the detached lifecycle state before its engine shuts down.
+const DETACH_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Decides whether to close an output's window, see `FlutterOutput::set_close_handler`.
+type CloseHandler = Box<dyn FnMut(&FlutterOutput) -> bool + Send>;
+
+/// A frame read back from an output, as flutter rendered it.
+#[derive(Clone, Debug)]
+pub struct CapturedFrame {
     width: u32,
     height: u32,
+    pixels: Vec<u8>,
+}
+
+impl CapturedFrame {
+    pub(crate) fn new(width: u32, height: u32, pixels: Vec<u8>) -> Self {
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Tightly packed RGBA pixels, top row first.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn into_pixels(self) -> Vec<u8> {
+        self.pixels
+    }
+
+    /// Encodes the frame as a PNG image.
+    #[cfg(feature = "png")]
+    pub fn to_png(&self) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut data, self.width, self.height);
+            encoder.set_color(png::ColorType::RGBA);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder
+                .write_header()
+                .map_err(|err| Error::Capture(err.to_string()))?;
+            writer
+                .write_image_data(&self.pixels)
+                .map_err(|err| Error::Capture(err.to_string()))?;
+        }
+        Ok(data)
+    }
+}
+
+struct WindowMetrics {
+    width: u32,
+    height: u32,
+    pixel_ratio: f64,
+    layout: Option<OutputLayout>,
+}
+
+pub struct FlutterOutput {
+    engine: FlutterEngine,
+    metrics: Arc<Mutex<WindowMetrics>>,
+    pixel_ratio_override: Option<f64>,
+    transform: Transform,
+    mode_control: Option<Arc<dyn ModeControl>>,
+    running: Arc<AtomicBool>,
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    close_handler: Arc<Mutex<Option<CloseHandler>>>,
+    keyboard: Arc<Mutex<KeyboardManager>>,
+    unparker: Unparker,
+    clock: Arc<Mutex<FrameClock>>,
+    frames: Arc<FrameReader>,
 }
 
 impl Clone for FlutterOutput {
     fn clone(&self) -> Self {
         Self {
             engine: self.engine.clone(),
-            width: self.width,
-            height: self.height,
+            metrics: self.metrics.clone(),
+            pixel_ratio_override: self.pixel_ratio_override,
+            transform: self.transform,
+            mode_control: self.mode_control.clone(),
+            running: self.running.clone(),
+            thread: self.thread.clone(),
+            close_handler: self.close_handler.clone(),
+            keyboard: self.keyboard.clone(),
+            unparker: self.unparker.clone(),
+            clock: self.clock.clone(),
+            frames: self.frames.clone(),
         }
     }
 }
 
-fn create_output<B>(
-    backend: B,
-    options: &mut FlutterEngineOptions,
-    keyboard: Arc<Mutex<KeyboardManager>>,
-) -> (Parker, FlutterOutput)
-where
-    B: FlutterOutputBackend + Send + 'static,
-{
-    let (width, height) = backend.get_framebuffer_dimensions();
+/// The renderer an output's engine uses, with the backend showing its frames.
+enum OutputRenderer {
+    OpenGL(Box<dyn FlutterOutputBackend + Send>),
+    Software(Box<dyn SoftwareOutputBackend + Send>),
+}
+
+impl OutputRenderer {
+    fn framebuffer_dimensions(&self) -> (u32, u32) {
+        match self {
+            OutputRenderer::OpenGL(backend) => backend.get_framebuffer_dimensions(),
+            OutputRenderer::Software(backend) => backend.get_framebuffer_dimensions(),
+        }
+    }
+
+    fn vsync_mode(&self) -> VsyncMode {
+        match self {
+            OutputRenderer::OpenGL(backend) => backend.vsync_mode(),
+            OutputRenderer::Software(backend) => backend.vsync_mode(),
+        }
+    }
 
-    let (resource_context, display) = unsafe {
-        backend.make_current().expect("Invalid backend context");
+    fn pixel_ratio(&self) -> f64 {
+        match self {
+            OutputRenderer::OpenGL(backend) => backend.pixel_ratio(),
+            OutputRenderer::Software(backend) => backend.pixel_ratio(),
+        }
+    }
 
-        let display = WrappedDisplay::get_current();
+    fn mode_control(&self) -> Option<Arc<dyn ModeControl>> {
+        match self {
+            OutputRenderer::OpenGL(backend) => backend.mode_control(),
+            OutputRenderer::Software(backend) => backend.mode_control(),
+        }
+    }
+}
+
+fn create_opengl_handler(
+    backend: Box<dyn FlutterOutputBackend + Send>,
+    options: &FlutterEngineOptions,
+    clock: Arc<Mutex<FrameClock>>,
+) -> Result<(SmithayOpenGLHandler, FrameReader), Error> {
+    // Prefer the display transforming the frame, which costs nothing
+    let render_transform = if backend.apply_transform(options.transform) {
+        Transform::Normal
+    } else {
+        options.transform
+    };
+    if render_transform != Transform::Normal {
+        debug!("Rendering {:?} offscreen", render_transform);
+    }
+    // Transforms which the display can't apply need a frame to transform
+    let render_offscreen = options.offscreen || render_transform != Transform::Normal;
+
+    let (resource_context, capture_context, display) = unsafe {
+        backend.make_current().map_err(|_| Error::BackendContext)?;
+
+        let display = WrappedDisplay::get_current()?;
         let resource_context = WrappedContext::create_context();
+        // Captures read the offscreen target on their own thread, through a context sharing it
+        let capture_context = if render_offscreen {
+            Some(WrappedContext::create_context())
+        } else {
+            None
+        };
         display.clear_current();
-        (resource_context, display)
+        (resource_context?, capture_context.transpose()?, display)
+    };
+
+    let offscreen = Arc::new(SharedOffscreen::default());
+    let frames = match capture_context {
+        Some(context) => FrameReader::OpenGL {
+            display: display.clone(),
+            context,
+            offscreen: offscreen.clone(),
+        },
+        None => FrameReader::Unavailable,
     };
+    let handler = SmithayOpenGLHandler::new(
+        backend,
+        display,
+        resource_context,
+        clock,
+        render_transform,
+        render_offscreen,
+        offscreen,
+    );
+    Ok((handler, frames))
+}
+
+fn create_output(
+    renderer: OutputRenderer,
+    options: &mut FlutterEngineOptions,
+    keyboard: Arc<Mutex<KeyboardManager>>,
+) -> Result<(Parker, FlutterOutput), Error> {
+    if let OutputRenderer::Software(_) = renderer {
+        if options.transform != Transform::Normal {
+            warn!(
+                "The software renderer can't transform frames, ignoring {:?}",
+                options.transform
+            );
+            options.transform = Transform::Normal;
+        }
+    }
+
+    let (width, height) = options
+        .transform
+        .transform_size(renderer.framebuffer_dimensions());
+    let pixel_ratio = options
+        .pixel_ratio
+        .unwrap_or_else(|| renderer.pixel_ratio());
+    let clock = Arc::new(Mutex::new(FrameClock::new(renderer.vsync_mode())));
+    let mode_control = renderer.mode_control();
 
     let parker = Parker::new();
     let unparker = parker.unparker().clone();
 
-    let platform_task_handler = Arc::new(SmithayPlatformTaskHandler::new(unparker));
+    let platform_task_handler = Arc::new(SmithayPlatformTaskHandler::new(unparker.clone()));
 
-    let opengl_handler = SmithayOpenGLHandler::new(Box::new(backend), display, resource_context);
+    let vsync_handler = Arc::new(Mutex::new(SmithayVsyncHandler::new(
+        clock.clone(),
+        unparker.clone(),
+    )));
 
-    let engine = FlutterEngineBuilder::new()
+    let builder = FlutterEngineBuilder::new()
         .with_platform_handler(platform_task_handler)
-        .with_opengl(opengl_handler)
+        .with_vsync_handler(vsync_handler);
+    let (builder, frames) = match renderer {
+        OutputRenderer::OpenGL(backend) => {
+            let (handler, frames) = create_opengl_handler(backend, options, clock.clone())?;
+            (builder.with_opengl(handler), frames)
+        }
+        OutputRenderer::Software(backend) => {
+            let last_frame = Arc::new(Mutex::new(SoftwareFrame::default()));
+            let handler = SmithaySoftwareHandler::new(backend, clock.clone(), last_frame.clone());
+            (
+                builder.with_software(handler),
+                FrameReader::Software(last_frame),
+            )
+        }
+    };
+
+    let engine = builder
         .with_asset_path(options.assets_path.clone())
         .with_args(options.arguments.clone())
         .build()
-        .expect("Failed to create engine");
+        .map_err(|err| Error::Engine(format!("{:?}", err)))?;
 
     engine.add_plugin(KeyEventPlugin::default());
+    engine.add_plugin(LifecyclePlugin::new());
     engine.add_plugin(TextInputPlugin::new(Arc::new(Mutex::new(
         SmithayTextInputHandler {
-            keyboard,
+            keyboard: keyboard.clone(),
             engine: engine.downgrade(),
         },
     ))));
 
+    for setup in options.setup_callbacks.drain(..) {
+        setup(&engine);
+    }
+
     if let Some(callback) = options.callback.take() {
         callback(&engine);
     }
 
-    (
+    engine
+        .run()
+        .map_err(|err| Error::Engine(format!("{:?}", err)))?;
+
+    Ok((
         parker,
         FlutterOutput {
             engine,
-            width,
-            height,
+            metrics: Arc::new(Mutex::new(WindowMetrics {
+                width,
+                height,
+                pixel_ratio,
+                layout: None,
+            })),
+            pixel_ratio_override: options.pixel_ratio,
+            transform: options.transform,
+            mode_control,
+            running: Arc::new(AtomicBool::new(true)),
+            thread: Arc::new(Mutex::new(None)),
+            close_handler: Arc::new(Mutex::new(None)),
+            keyboard,
+            unparker,
+            clock,
+            frames: Arc::new(frames),
         },
-    )
+    ))
 }
 
 fn run_output(parker: Parker, output: FlutterOutput) {
-    output.engine.run().expect("Failed to start engine");
-
-    //    let now = Instant::now();
-    //    output
-    //        .engine
-    //        .notify_vsync(now, now + Duration::from_millis(16));
+    {
+        let metrics = output.metrics.lock();
+        output.engine.send_window_metrics_event(
+            metrics.width as usize,
+            metrics.height as usize,
+            metrics.pixel_ratio,
+        );
+    }
 
-    output.engine.send_window_metrics_event(
-        output.width as usize,
-        output.height as usize,
-        output.height as f64 / 1080.0,
-    );
+    while output.running.load(Ordering::SeqCst) {
+        let frame_deadline = {
+            let mut clock = output.clock.lock();
+            if let Some((start, target)) = clock.poll(Instant::now()) {
+                output.engine.notify_vsync(start, target);
+            }
+            clock.deadline()
+        };
 
-    let running = Arc::new(AtomicBool::new(true));
-    while running.load(Ordering::SeqCst) {
         let duration = match output.engine.execute_platform_tasks() {
             None => Duration::from_millis(100), // Just in case, wake up every so often.
             Some(tgt) => {
@@ -122,8 +543,32 @@ fn run_output(parker: Parker, output: FlutterOutput) {
                 tgt - now
             }
         };
+        let duration = match frame_deadline {
+            Some(deadline) => duration.min(deadline.saturating_duration_since(Instant::now())),
+            None => duration,
+        };
         parker.park_timeout(duration);
     }
+
+    debug!("Shutting down flutter output");
+    output
+        .engine
+        .with_plugin(|lifecycle: &LifecyclePlugin| lifecycle.send_detached());
+
+    // The message is only delivered while platform tasks run, so they keep running for a moment
+    let deadline = Instant::now() + DETACH_TIMEOUT;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        let next = output
+            .engine
+            .execute_platform_tasks()
+            .map_or(deadline, |next| next.min(deadline));
+        parker.park_timeout(next.saturating_duration_since(now));
+    }
+    output.engine.shutdown();
 }
 
 impl FlutterOutput {
@@ -131,47 +576,306 @@ impl FlutterOutput {
         backend: B,
         options: FlutterEngineOptions,
         keyboard: Arc<Mutex<KeyboardManager>>,
-    ) -> Self
+    ) -> Result<Self, Error>
     where
         B: FlutterOutputBackend + Send + 'static,
     {
+        Self::spawn(OutputRenderer::OpenGL(Box::new(backend)), options, keyboard)
+    }
+
+    /// Creates an output using flutter's software renderer, for hardware without a GPU.
+    pub(crate) fn new_software<B>(
+        backend: B,
+        options: FlutterEngineOptions,
+        keyboard: Arc<Mutex<KeyboardManager>>,
+    ) -> Result<Self, Error>
+    where
+        B: SoftwareOutputBackend + Send + 'static,
+    {
+        Self::spawn(
+            OutputRenderer::Software(Box::new(backend)),
+            options,
+            keyboard,
+        )
+    }
+
+    fn spawn(
+        renderer: OutputRenderer,
+        options: FlutterEngineOptions,
+        keyboard: Arc<Mutex<KeyboardManager>>,
+    ) -> Result<Self, Error> {
         debug!("Creating new flutter output");
 
         let (send, recv) = mpsc::channel();
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
             let panic_sender = send.clone();
             let mut has_sent = false;
+            let sent = &mut has_sent;
             let result = panic::catch_unwind(AssertUnwindSafe(move || {
                 let mut options = options;
-                let (parker, output) = create_output(backend, &mut options, keyboard);
+                let (parker, output) = match create_output(renderer, &mut options, keyboard) {
+                    Ok(output) => output,
+                    Err(err) => {
+                        *sent = true;
+                        send.send(Err(err)).unwrap();
+                        return;
+                    }
+                };
                 send.send(Ok(output.clone())).unwrap();
-                has_sent = true;
+                *sent = true;
                 run_output(parker, output);
             }));
             if let Err(err) = result {
                 if has_sent {
                     panic::resume_unwind(err);
                 } else {
-                    panic_sender.send(Err(err)).unwrap();
+                    error!("Output thread panicked during setup");
+                    let _ = panic_sender.send(Err(Error::OutputThread));
                 }
             }
         });
 
-        match recv.recv().unwrap() {
-            Ok(output) => output,
-            Err(err) => panic::resume_unwind(err),
-        }
+        let output = recv.recv().unwrap_or(Err(Error::OutputThread))?;
+        *output.thread.lock() = Some(handle);
+        Ok(output)
     }
 
     pub fn engine(&self) -> FlutterEngine {
         self.engine.clone()
     }
+
+    pub fn size(&self) -> (u32, u32) {
+        let metrics = self.metrics.lock();
+        (metrics.width, metrics.height)
+    }
+
+    pub fn pixel_ratio(&self) -> f64 {
+        self.metrics.lock().pixel_ratio
+    }
+
+    pub fn transform(&self) -> Transform {
+        self.transform
+    }
+
+    /// Places the output in the layout, which then follows any size changes.
+    pub(crate) fn add_to_layout(&self, layout: &OutputLayout) {
+        let mut metrics = self.metrics.lock();
+        layout.add(self.engine.downgrade(), metrics.width, metrics.height);
+        layout.set_transform(&self.engine.downgrade(), self.transform);
+        metrics.layout = Some(layout.clone());
+    }
+
+    /// Display modes the output supports, empty if the mode can't be changed.
+    pub fn modes(&self) -> Vec<OutputMode> {
+        match self.mode_control.as_ref() {
+            Some(control) => control.modes(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn mode(&self) -> Option<OutputMode> {
+        self.mode_control
+            .as_ref()
+            .map(|control| control.current_mode())
+    }
+
+    /// Switches the display to one of the modes returned by `modes`, resizing the engine to match.
+    /// Blocks until the display has switched, so must not be called from the output's platform
+    /// thread, such as from a plugin.
+    pub fn set_mode(&self, mode: &OutputMode) -> Result<(), Error> {
+        let control = self
+            .mode_control
+            .as_ref()
+            .ok_or_else(|| Error::Mode("output does not support mode changes".to_string()))?;
+        if self.is_output_thread() {
+            return Err(Error::Mode(
+                "modes can't be changed from the output's own thread".to_string(),
+            ));
+        }
+
+        // The render thread switches before drawing, so flutter has to be made to draw a frame
+        let result = control.set_mode(mode)?;
+        self.request_frame();
+
+        let pixel_ratio = match result.recv_timeout(MODE_TIMEOUT) {
+            Ok(result) => result?,
+            Err(mpsc::RecvTimeoutError::Timeout) if !control.cancel_mode() => result
+                .recv()
+                .unwrap_or_else(|_| Err(Error::Mode("the output was closed".to_string())))?,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                return Err(Error::Mode("no frame was rendered".to_string()));
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(Error::Mode(format!("switch to {:?} was superseded", mode)));
+            }
+        };
+        self.clock
+            .lock()
+            .set_interval(VsyncMode::interval_from_refresh(mode.refresh));
+        self.update_metrics(mode.size.0, mode.size.1, pixel_ratio);
+        Ok(())
+    }
+
+    /// Updates the output's size and pixel ratio, sending the new metrics to flutter. The size is
+    /// that of the display, before the output's transform. The pixel ratio is ignored if one was
+    /// set in the `FlutterEngineOptions`.
+    pub(crate) fn update_metrics(&self, width: u32, height: u32, pixel_ratio: f64) {
+        let (width, height) = self.transform.transform_size((width, height));
+        let pixel_ratio = self.pixel_ratio_override.unwrap_or(pixel_ratio);
+        {
+            let mut metrics = self.metrics.lock();
+            metrics.width = width;
+            metrics.height = height;
+            metrics.pixel_ratio = pixel_ratio;
+
+            if let Some(layout) = metrics.layout.as_ref() {
+                layout.resize(&self.engine.downgrade(), width, height);
+            }
+        }
+
+        debug!(
+            "Output metrics changed to {}x{} at {}",
+            width, height, pixel_ratio
+        );
+        self.engine.run_on_platform_thread(move |engine| {
+            engine.send_window_metrics_event(width as usize, height as usize, pixel_ratio);
+        });
+    }
+
+    /// Makes flutter render a frame. It only renders when something changed, so the unchanged
+    /// metrics are sent again.
+    pub(crate) fn request_frame(&self) {
+        let (width, height, pixel_ratio) = {
+            let metrics = self.metrics.lock();
+            (metrics.width, metrics.height, metrics.pixel_ratio)
+        };
+        self.engine.run_on_platform_thread(move |engine| {
+            engine.send_window_metrics_event(width as usize, height as usize, pixel_ratio);
+        });
+    }
+
+    fn is_output_thread(&self) -> bool {
+        match self.thread.lock().as_ref() {
+            Some(handle) => handle.thread().id() == thread::current().id(),
+            None => false,
+        }
+    }
+
+    /// Reads back the last frame presented on the output, as flutter rendered it before the
+    /// output's transform.
+    ///
+    /// OpenGL outputs only keep their frames when rendering offscreen, see
+    /// `FlutterEngineOptions::set_offscreen`. Their frames are read through a context of their
+    /// own, which is made current on the calling thread for the duration of the call.
+    pub fn capture_frame(&self) -> Result<CapturedFrame, Error> {
+        self.frames.read(CAPTURE_TIMEOUT)
+    }
+
+    /// Gives the output keyboard focus, so that key events go to its engine.
+    pub fn focus(&self) {
+        self.keyboard.lock().set_focus(self.engine.downgrade());
+    }
+
+    pub fn has_focus(&self) -> bool {
+        match self.keyboard.lock().focus() {
+            Some(engine) => engine.ptr_equal(self.engine.downgrade()),
+            None => false,
+        }
+    }
+
+    /// Reports that the display started showing the last frame at the given time, letting the
+    /// next one start.
+    pub(crate) fn vblank(&self, time: Instant) {
+        self.clock.lock().vblank(time);
+        self.unparker.unpark();
+    }
+
+    /// Whether the output's engine is still running, rather than shut down or shutting down.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Asks the output's platform thread to shut the engine down. Returns immediately.
+    pub(crate) fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.unparker.unpark();
+    }
+
+    /// Sets what happens when the user asks to close the output's window. The handler returns
+    /// whether to close it, which shuts the output down. Without a handler, windows always close.
+    ///
+    /// To ask the app first, return `false` and call `shutdown` once it agrees.
+    pub fn set_close_handler<F>(&self, handler: F)
+    where
+        F: FnMut(&FlutterOutput) -> bool + Send + 'static,
+    {
+        *self.close_handler.lock() = Some(Box::new(handler));
+    }
+
+    /// Asks the close handler whether to close the window, stopping the output if it agrees.
+    /// Returns whether the output was stopped, in which case the caller still has to `shutdown`
+    /// it to wait for its thread.
+    pub(crate) fn request_close(&self) -> bool {
+        // Not locked while running, so the handler may replace itself
+        let mut handler = self.close_handler.lock().take();
+        let close = handler.as_mut().map_or(true, |handler| handler(self));
+        {
+            let mut current = self.close_handler.lock();
+            if current.is_none() {
+                *current = handler;
+            }
+        }
+
+        if close {
+            debug!("Closing output");
+            self.keyboard.lock().clear_focus(self.engine.downgrade());
+            self.stop();
+        }
+        close
+    }
+
+    /// Shuts the engine down, telling the app it is detached, and waits for the output's thread
+    /// to exit. Calling it again, or on a clone, returns immediately.
+    pub fn shutdown(&self) {
+        self.keyboard.lock().clear_focus(self.engine.downgrade());
+        self.stop();
+
+        let handle = self.thread.lock().take();
+        if let Some(handle) = handle {
+            if handle.thread().id() == thread::current().id() {
+                // Called from the engine's own thread, which exits once this returns
+                return;
+            }
+            if handle.join().is_err() {
+                error!("Output thread panicked during shutdown");
+            }
+        }
+    }
+
+    /// Shuts several outputs down together. Each app gets a moment to handle being detached,
+    /// which they then spend at the same time rather than one after another.
+    pub(crate) fn shutdown_all(outputs: &[FlutterOutput]) {
+        for output in outputs {
+            output
+                .keyboard
+                .lock()
+                .clear_focus(output.engine.downgrade());
+            output.stop();
+        }
+        for output in outputs {
+            output.shutdown();
+        }
+    }
 }
 
 pub struct FlutterEngineOptions {
     pub(crate) assets_path: PathBuf,
     pub(crate) arguments: Vec<String>,
     pub(crate) callback: Option<Box<dyn FnOnce(&FlutterEngine) + Send>>,
+    pub(crate) pixel_ratio: Option<f64>,
+    pub(crate) transform: Transform,
+    pub(crate) offscreen: bool,
+    pub(crate) setup_callbacks: Vec<Box<dyn FnOnce(&FlutterEngine) + Send>>,
 }
 
 impl FlutterEngineOptions {
@@ -180,6 +884,10 @@ impl FlutterEngineOptions {
             assets_path,
             arguments,
             callback: None,
+            pixel_ratio: None,
+            transform: Transform::Normal,
+            offscreen: false,
+            setup_callbacks: Vec::new(),
         }
     }
 
@@ -189,4 +897,189 @@ impl FlutterEngineOptions {
     {
         self.callback = Some(Box::new(callback));
     }
+
+    /// Overrides the device pixel ratio, which is otherwise derived from the display.
+    pub fn set_pixel_ratio(&mut self, pixel_ratio: f64) {
+        self.pixel_ratio = Some(pixel_ratio);
+    }
+
+    /// Rotates or flips the output, for displays that are not mounted upright. Flutter and input
+    /// coordinates follow the transformed orientation.
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = transform;
+    }
+
+    /// Renders flutter into an offscreen framebuffer sized to the output, which is then copied to
+    /// the display. This costs a copy per frame, but leaves the frame available for processing
+    /// before it is shown and for `FlutterOutput::capture_frame` after. Transforms the display
+    /// can't apply always render offscreen.
+    pub fn set_offscreen(&mut self, offscreen: bool) {
+        self.offscreen = offscreen;
+    }
+
+    /// Adds an embedder callback, run before the user callback while the engine is being set up.
+    pub(crate) fn add_setup_callback<F>(&mut self, callback: F)
+    where
+        F: FnOnce(&FlutterEngine) + 'static + Send,
+    {
+        self.setup_callbacks.push(Box::new(callback));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSFORMS: [Transform; 8] = [
+        Transform::Normal,
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+        Transform::Flipped,
+        Transform::Flipped90,
+        Transform::Flipped180,
+        Transform::Flipped270,
+    ];
+
+    const CONTENT: (f64, f64) = (1920.0, 1080.0);
+
+    fn panel_size(transform: Transform) -> (f64, f64) {
+        let (width, height) = transform.transform_size((1920, 1080));
+        (width as f64, height as f64)
+    }
+
+    #[test]
+    fn swaps_the_size_of_quarter_turns() {
+        for &transform in &TRANSFORMS {
+            let expected = if transform.swaps_axes() {
+                (1080, 1920)
+            } else {
+                (1920, 1080)
+            };
+            assert_eq!(transform.transform_size((1920, 1080)), expected);
+            assert_eq!(transform.transform_size(expected), (1920, 1080));
+        }
+    }
+
+    #[test]
+    fn maps_the_panel_origin() {
+        // Where the panel's top left corner lands in the content
+        let expected = [
+            (0.0, 0.0),
+            (0.0, 1080.0),
+            (1920.0, 1080.0),
+            (1920.0, 0.0),
+            (1920.0, 0.0),
+            (1920.0, 1080.0),
+            (0.0, 1080.0),
+            (0.0, 0.0),
+        ];
+        for (&transform, &expected) in TRANSFORMS.iter().zip(&expected) {
+            assert_eq!(
+                transform.to_logical((0.0, 0.0), CONTENT),
+                expected,
+                "{:?}",
+                transform
+            );
+        }
+    }
+
+    #[test]
+    fn maps_panel_corners_onto_content_corners() {
+        for &transform in &TRANSFORMS {
+            let (width, height) = panel_size(transform);
+            let corners = [(0.0, 0.0), (width, 0.0), (0.0, height), (width, height)];
+            let mut mapped: Vec<_> = corners
+                .iter()
+                .map(|&corner| transform.to_logical(corner, CONTENT))
+                .collect();
+            mapped.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(
+                mapped,
+                vec![(0.0, 0.0), (0.0, 1080.0), (1920.0, 0.0), (1920.0, 1080.0)],
+                "{:?}",
+                transform
+            );
+        }
+    }
+
+    #[test]
+    fn rotates_clockwise() {
+        // The content's top left corner is shown at the panel's top right corner
+        assert_eq!(
+            Transform::Rotate90.to_panel((0.0, 0.0), CONTENT),
+            (1080.0, 0.0)
+        );
+        assert_eq!(
+            Transform::Rotate270.to_panel((0.0, 0.0), CONTENT),
+            (0.0, 1920.0)
+        );
+        // Flipping first leaves the top right corner there instead
+        assert_eq!(
+            Transform::Flipped90.to_panel((1920.0, 0.0), CONTENT),
+            (1080.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn to_panel_inverts_to_logical() {
+        let points = [(0.0, 0.0), (10.0, 20.0), (333.0, 777.0), (1080.0, 1080.0)];
+        for &transform in &TRANSFORMS {
+            for &point in &points {
+                let logical = transform.to_logical(point, CONTENT);
+                assert_eq!(
+                    transform.to_panel(logical, CONTENT),
+                    point,
+                    "{:?}",
+                    transform
+                );
+                let panel = transform.to_panel(point, CONTENT);
+                assert_eq!(
+                    transform.to_logical(panel, CONTENT),
+                    point,
+                    "{:?}",
+                    transform
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn source_coords_match_to_logical() {
+        // Texture coordinates start at the bottom left, content coordinates at the top left
+        let corners = [(0.0, 1.0), (1.0, 1.0), (0.0, 0.0), (1.0, 0.0)];
+        for &transform in &TRANSFORMS {
+            let coords = transform.source_coords();
+            for (i, &corner) in corners.iter().enumerate() {
+                let (x, y) = transform.to_logical(corner, (1.0, 1.0));
+                let expected = [x as f32, 1.0 - y as f32];
+                assert_eq!(coords[i * 2..i * 2 + 2], expected, "{:?}", transform);
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_ratio_of_unknown_sizes_is_one() {
+        assert_eq!(pixel_ratio_from_physical((1920, 1080), (0, 0)), 1.0);
+        assert_eq!(pixel_ratio_from_physical((1920, 1080), (0, 300)), 1.0);
+        assert_eq!(pixel_ratio_from_physical((1920, 1080), (500, 0)), 1.0);
+    }
+
+    #[test]
+    fn pixel_ratio_is_at_least_one() {
+        // A 24" 1080p monitor at 0.96 and a 55" 4K TV at 0.84
+        assert_eq!(pixel_ratio_from_physical((1920, 1080), (531, 299)), 1.0);
+        assert_eq!(pixel_ratio_from_physical((3840, 2160), (1210, 680)), 1.0);
+    }
+
+    #[test]
+    fn pixel_ratio_rounds_to_quarter_steps() {
+        // 15.6" 1080p at 1.48, 13.3" 1080p at 1.73 and 27" 4K at 1.70
+        assert_eq!(pixel_ratio_from_physical((1920, 1080), (344, 194)), 1.5);
+        assert_eq!(pixel_ratio_from_physical((1920, 1080), (294, 165)), 1.75);
+        assert_eq!(pixel_ratio_from_physical((3840, 2160), (597, 336)), 1.75);
+
+        // 13.3" 2560x1600 at 2.37
+        assert_eq!(pixel_ratio_from_physical((2560, 1600), (286, 179)), 2.25);
+    }
 }
@@ -1,4 +1,10 @@
+use crate::cursor::{
+    CursorImage, CursorManager, CursorPlane, CursorTheme, MouseCursorPlugin, SoftwareCursor,
+    SoftwareCursorRenderer,
+};
 use crate::input::libinput::LibInputHandler;
+use smithay::backend::drm::atomic::AtomicDrmDevice;
+use smithay::backend::drm::common::fallback::FallbackDevice;
 use smithay::backend::drm::egl::{EglDevice, EglSurface};
 use smithay::backend::drm::gbm::{egl::Gbm as EglGbmBackend, GbmDevice, GbmSurface};
 use smithay::backend::drm::legacy::LegacyDrmDevice;
@@ -6,42 +12,67 @@ use smithay::backend::drm::{device_bind, Device, DeviceHandler, Surface};
 use smithay::backend::egl::EGLContext;
 use smithay::backend::libinput::{libinput_bind, LibinputInputBackend, LibinputSessionInterface};
 use smithay::backend::session::auto::{auto_session_bind, AutoId, AutoSession, BoundAutoSession};
-use smithay::backend::session::{notify_multiplexer, AsSessionObserver, Session, SessionNotifier};
+use smithay::backend::session::{
+    notify_multiplexer, AsSessionObserver, Session, SessionNotifier, SessionObserver,
+};
 use smithay::backend::udev::{udev_backend_bind, UdevBackend, UdevHandler};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::io::Error as IoError;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Instant;
 
 use smithay::reexports::calloop::{
     generic::{Generic, SourceFd},
+    mio::{PollOpt, Ready},
     LoopHandle, Source,
 };
 
 use smithay::reexports::{
+    drm::buffer::format::PixelFormat as DrmPixelFormat,
     drm::control::{
-        connector::{Info as ConnectorInfo, State as ConnectorState},
+        connector::{self, Info as ConnectorInfo, State as ConnectorState},
         crtc,
+        dumbbuffer::DumbBuffer,
         encoder::Info as EncoderInfo,
+        framebuffer, property, Device as ControlDevice, Event as DrmEvent, Mode, ModeTypeFlags,
+        PageFlipFlags, ResourceHandle,
     },
+    drm::{ClientCapability, Device as BasicDevice, DriverCapability},
     input::Libinput,
-    nix::{fcntl::OFlag, sys::stat::dev_t},
+    nix::{
+        fcntl::OFlag,
+        sys::stat::{dev_t, major, minor},
+        unistd::dup2,
+    },
 };
 
-use log::{error, info, trace};
+use log::{debug, error, info, trace, warn};
 
-use crate::egl_util::{WrappedContext, WrappedSurface};
+use crate::edid::Edid;
+use crate::egl_util::{WrappedContext, WrappedDisplay, WrappedPbuffer, WrappedSurface};
+use crate::error::Error;
+use crate::gl_util::read_framebuffer;
+use crate::gpu::{choose_primary, enumerate_gpus, GpuInfo};
 
-use crate::input::keyboard::KeyboardManager;
-use crate::output::{FlutterEngineOptions, FlutterOutput, FlutterOutputBackend};
-use crate::{EngineWeakCollection, FlutterDrmManager};
+use crate::input::keyboard::{KeyboardConfig, KeyboardDevice, KeyboardManager};
+use crate::input::pointer::PointerManager;
+use crate::input::touch::TouchManager;
+use crate::output::{
+    pixel_ratio_from_physical, FlutterEngineOptions, FlutterOutput, FlutterOutputBackend,
+    ModeControl, OutputMode, SoftwareOutputBackend, Transform, VsyncMode,
+};
+use crate::vsync::instant_from_monotonic;
+use crate::{EngineWeakCollection, FlutterDrmManager, OutputLayout};
 use parking_lot::Mutex;
 use smithay::backend::input::InputBackend;
 
+#[derive(Clone, Copy)]
 pub struct SessionFd(RawFd);
 
 impl AsRawFd for SessionFd {
@@ -50,314 +81,1785 @@ impl AsRawFd for SessionFd {
     }
 }
 
-type RenderDevice =
-    EglDevice<EglGbmBackend<LegacyDrmDevice<SessionFd>>, GbmDevice<LegacyDrmDevice<SessionFd>>>;
-type RenderSurface =
-    EglSurface<EglGbmBackend<LegacyDrmDevice<SessionFd>>, GbmDevice<LegacyDrmDevice<SessionFd>>>;
-type WrappedRenderSurface = WrappedSurface<GbmSurface<LegacyDrmDevice<SessionFd>>>;
+impl BasicDevice for SessionFd {}
+
+impl ControlDevice for SessionFd {}
+
+type DrmDevice = FallbackDevice<AtomicDrmDevice<SessionFd>, LegacyDrmDevice<SessionFd>>;
+type RenderDevice = EglDevice<EglGbmBackend<DrmDevice>, GbmDevice<DrmDevice>>;
+type RenderSurface = EglSurface<EglGbmBackend<DrmDevice>, GbmDevice<DrmDevice>>;
+type WrappedRenderSurface = WrappedSurface<GbmSurface<DrmDevice>>;
+
+// Bits of the plane rotation property
+const DRM_MODE_ROTATE_0: u64 = 1 << 0;
+const DRM_MODE_ROTATE_180: u64 = 1 << 2;
+const DRM_MODE_REFLECT_X: u64 = 1 << 4;
+const DRM_MODE_REFLECT_Y: u64 = 1 << 5;
+
+/// Value of the plane type property for primary planes.
+const DRM_PLANE_TYPE_PRIMARY: u64 = 1;
+
+/// Which kernel modesetting API to drive a GPU with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ModesettingApi {
+    /// Atomic modesetting if the driver supports it, legacy otherwise.
+    Auto,
+    Atomic,
+    Legacy,
+}
+
+/// How the outputs of a GPU render their frames.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RenderMode {
+    /// OpenGL if EGL can be set up on the device, software rendering otherwise.
+    Auto,
+    OpenGL,
+    /// Flutter's software renderer, shown through DRM dumb buffers. Works on devices without any
+    /// GPU, such as `simpledrm` or display controllers without a render node.
+    Software,
+}
+
+fn open_drm_device(fd: SessionFd, api: ModesettingApi) -> Option<DrmDevice> {
+    let atomic = || match AtomicDrmDevice::new(fd, None) {
+        Ok(device) => {
+            info!("Using atomic modesetting");
+            Some(FallbackDevice::Preference(device))
+        }
+        Err(err) => {
+            info!("Atomic modesetting unavailable: {:?}", err);
+            None
+        }
+    };
+    let legacy = || match LegacyDrmDevice::new(fd, None) {
+        Ok(device) => {
+            info!("Using legacy modesetting");
+            Some(FallbackDevice::Fallback(device))
+        }
+        Err(err) => {
+            error!("Legacy modesetting unavailable: {:?}", err);
+            None
+        }
+    };
+
+    match api {
+        ModesettingApi::Auto => atomic().or_else(legacy),
+        ModesettingApi::Atomic => atomic(),
+        ModesettingApi::Legacy => legacy(),
+    }
+}
+
+/// The modes of a connector, shared between the output and its render thread.
+struct DrmModeControl {
+    modes: Vec<Mode>,
+    physical_size: (u32, u32),
+    current: Mutex<usize>,
+    pending: Mutex<Option<PendingMode>>,
+}
+
+/// A mode switch waiting for the render thread, which reports the outcome to `result`.
+struct PendingMode {
+    id: usize,
+    result: mpsc::Sender<Result<f64, Error>>,
+}
+
+impl DrmModeControl {
+    fn new(modes: Vec<Mode>, physical_size: (u32, u32)) -> Self {
+        Self {
+            modes,
+            physical_size,
+            current: Mutex::new(0),
+            pending: Mutex::new(None),
+        }
+    }
+
+    fn output_mode(&self, id: usize) -> OutputMode {
+        let mode = &self.modes[id];
+        let (width, height) = mode.size();
+        OutputMode {
+            id,
+            size: (width as u32, height as u32),
+            refresh: mode.vrefresh(),
+            preferred: mode.mode_type().contains(ModeTypeFlags::PREFERRED),
+        }
+    }
+
+    fn check_mode(&self, mode: &OutputMode) -> Result<(), Error> {
+        if mode.id >= self.modes.len() || self.output_mode(mode.id) != *mode {
+            return Err(Error::Mode(format!("{:?} is not supported", mode)));
+        }
+        Ok(())
+    }
+
+    /// Takes the queued mode switch, to be applied by the render thread and then passed to
+    /// `finish_mode`.
+    fn take_pending(&self) -> Option<(Mode, PendingMode)> {
+        let pending = self.pending.lock().take()?;
+        Some((self.modes[pending.id], pending))
+    }
+
+    fn finish_mode(&self, pending: PendingMode, result: Result<(), Error>) {
+        let result = result.map(|()| {
+            *self.current.lock() = pending.id;
+            pixel_ratio_from_physical(self.output_mode(pending.id).size, self.physical_size)
+        });
+        if let Err(err) = result.as_ref() {
+            error!("{}", err);
+        }
+        let _ = pending.result.send(result);
+    }
+}
+
+impl ModeControl for DrmModeControl {
+    fn modes(&self) -> Vec<OutputMode> {
+        (0..self.modes.len())
+            .map(|id| self.output_mode(id))
+            .collect()
+    }
+
+    fn current_mode(&self) -> OutputMode {
+        self.output_mode(*self.current.lock())
+    }
+
+    fn set_mode(&self, mode: &OutputMode) -> Result<mpsc::Receiver<Result<f64, Error>>, Error> {
+        self.check_mode(mode)?;
+
+        // The render thread owns the surface, it switches mode before the next frame
+        let (send, recv) = mpsc::channel();
+        *self.pending.lock() = Some(PendingMode {
+            id: mode.id,
+            result: send,
+        });
+        Ok(recv)
+    }
+
+    fn cancel_mode(&self) -> bool {
+        self.pending.lock().take().is_some()
+    }
+}
 
 struct DrmOutputBackend {
     surface: WrappedRenderSurface,
+    cursor: Mutex<SoftwareCursorRenderer>,
+    modes: Arc<DrmModeControl>,
+    fd: SessionFd,
+    crtc: crtc::Handle,
 }
 
 impl FlutterOutputBackend for DrmOutputBackend {
     fn swap_buffers(&self) -> Result<(), ()> {
+        // The software cursor is drawn over flutter's frame, so it is only updated when flutter
+        // renders. Outputs with a hardware cursor plane never enable it.
+        unsafe {
+            self.cursor.lock().draw(self.get_framebuffer_dimensions());
+        }
+
         self.surface.swap_buffers().map_err(|_| ())
     }
 
     fn make_current(&self) -> Result<(), ()> {
+        if let Some((mode, pending)) = self.modes.take_pending() {
+            let result = self
+                .surface
+                .use_mode(Some(mode))
+                .map_err(|err| Error::Mode(format!("{:?}", err)));
+            self.modes.finish_mode(pending, result);
+        }
+
         unsafe { self.surface.make_current().map_err(|_| ()) }
     }
 
     fn get_framebuffer_dimensions(&self) -> (u32, u32) {
-        let (w, h) = self
+        match self.surface.pending_mode() {
+            Some(mode) => {
+                let (w, h) = mode.size();
+                (w as u32, h as u32)
+            }
+            None => self.modes.current_mode().size(),
+        }
+    }
+
+    fn vsync_mode(&self) -> VsyncMode {
+        let refresh = self
             .surface
             .pending_mode()
-            .map(|mode| mode.size())
-            .unwrap_or((1, 1));
-        (w as u32, h as u32)
+            .map(|mode| mode.vrefresh())
+            .unwrap_or(0);
+        VsyncMode::Vblank {
+            interval: VsyncMode::interval_from_refresh(refresh),
+        }
+    }
+
+    fn pixel_ratio(&self) -> f64 {
+        pixel_ratio_from_physical(self.get_framebuffer_dimensions(), self.modes.physical_size)
+    }
+
+    fn mode_control(&self) -> Option<Arc<dyn ModeControl>> {
+        Some(self.modes.clone())
+    }
+
+    fn apply_transform(&self, transform: Transform) -> bool {
+        // Scanning out rotated by 90 degrees needs a differently sized and tiled buffer, which
+        // few drivers support, so only rotations which keep the axes are tried
+        let rotation = match transform {
+            Transform::Normal => DRM_MODE_ROTATE_0,
+            Transform::Rotate180 => DRM_MODE_ROTATE_180,
+            Transform::Flipped => DRM_MODE_ROTATE_0 | DRM_MODE_REFLECT_X,
+            Transform::Flipped180 => DRM_MODE_ROTATE_0 | DRM_MODE_REFLECT_Y,
+            _ => return false,
+        };
+        set_primary_plane_rotation(&self.fd, self.crtc, rotation).is_ok()
+    }
+}
+
+/// Sets the rotation property of the primary plane of a CRTC, if the driver supports it.
+fn set_primary_plane_rotation(fd: &SessionFd, crtc: crtc::Handle, rotation: u64) -> Result<(), ()> {
+    // Primary planes are only listed once the client asks for them
+    fd.set_client_capability(ClientCapability::UniversalPlanes, true)
+        .map_err(|err| debug!("Universal planes unsupported: {:?}", err))?;
+    let resources = fd
+        .resource_handles()
+        .map_err(|err| error!("Failed to query resource handles: {:?}", err))?;
+    let planes = fd
+        .plane_handles()
+        .map_err(|err| error!("Failed to query planes: {:?}", err))?;
+
+    for plane in planes.planes() {
+        let info = match fd.get_plane(*plane) {
+            Ok(info) => info,
+            Err(_) => continue,
+        };
+        if !resources
+            .filter_crtcs(info.possible_crtcs())
+            .contains(&crtc)
+        {
+            continue;
+        }
+        match find_property(fd, *plane, "type") {
+            Some((_, DRM_PLANE_TYPE_PRIMARY)) => {}
+            _ => continue,
+        }
+
+        let (property, _) = find_property(fd, *plane, "rotation")
+            .ok_or_else(|| debug!("Primary plane of {:?} can't be rotated", crtc))?;
+        return fd
+            .set_property(*plane, property, rotation)
+            .map_err(|err| debug!("Failed to rotate primary plane: {:?}", err));
+    }
+
+    debug!("No primary plane found for {:?}", crtc);
+    Err(())
+}
+
+/// Number of dumb buffers per output, one shown while the next is written.
+const SCANOUT_BUFFERS: usize = 2;
+
+/// A dumb buffer and the framebuffer scanning it out.
+struct ScanoutBuffer {
+    buffer: DumbBuffer,
+    framebuffer: framebuffer::Handle,
+}
+
+struct DumbOutputState {
+    buffers: Vec<ScanoutBuffer>,
+    front: usize,
+    /// The `SoftwareSession::activation` the CRTC was last set in.
+    activation: usize,
+}
+
+/// Shows frames of flutter's software renderer on a CRTC, by copying them into dumb buffers.
+struct DumbOutputBackend {
+    fd: SessionFd,
+    crtc: crtc::Handle,
+    connector: connector::Handle,
+    modes: Arc<DrmModeControl>,
+    session: Arc<SoftwareSession>,
+    state: Mutex<DumbOutputState>,
+}
+
+impl DumbOutputBackend {
+    fn new(
+        fd: SessionFd,
+        crtc: crtc::Handle,
+        connector: connector::Handle,
+        modes: Arc<DrmModeControl>,
+        session: Arc<SoftwareSession>,
+        mode: Mode,
+    ) -> Result<Self, ()> {
+        let backend = Self {
+            fd,
+            crtc,
+            connector,
+            modes,
+            state: Mutex::new(DumbOutputState {
+                buffers: Vec::new(),
+                front: 0,
+                activation: session.activation.load(Ordering::SeqCst),
+            }),
+            session,
+        };
+        {
+            let mut state = backend.state.lock();
+            backend.set_mode(&mut state, mode)?;
+        }
+        Ok(backend)
+    }
+
+    /// Replaces the buffers with ones of the mode's size and shows the first, cleared to black.
+    fn set_mode(&self, state: &mut DumbOutputState, mode: Mode) -> Result<(), ()> {
+        self.destroy_buffers(state);
+
+        let (width, height) = mode.size();
+        for _ in 0..SCANOUT_BUFFERS {
+            let mut buffer = self
+                .fd
+                .create_dumb_buffer((width as u32, height as u32), DrmPixelFormat::XRGB8888, 32)
+                .map_err(|err| error!("Failed to create scanout buffer: {:?}", err))?;
+            if let Ok(mut mapping) = self.fd.map_dumb_buffer(&mut buffer) {
+                for byte in mapping.iter_mut() {
+                    *byte = 0;
+                }
+            }
+            let framebuffer = match self.fd.add_framebuffer(&buffer) {
+                Ok(framebuffer) => framebuffer,
+                Err(err) => {
+                    error!("Failed to create framebuffer: {:?}", err);
+                    let _ = self.fd.destroy_dumb_buffer(buffer);
+                    return Err(());
+                }
+            };
+            state.buffers.push(ScanoutBuffer {
+                buffer,
+                framebuffer,
+            });
+        }
+
+        state.front = 0;
+        self.fd
+            .set_crtc(
+                self.crtc,
+                Some(state.buffers[0].framebuffer),
+                (0, 0),
+                &[self.connector],
+                Some(mode),
+            )
+            .map_err(|err| error!("Failed to set mode: {:?}", err))
+    }
+
+    fn destroy_buffers(&self, state: &mut DumbOutputState) {
+        for scanout in state.buffers.drain(..) {
+            let _ = self.fd.destroy_framebuffer(scanout.framebuffer);
+            let _ = self.fd.destroy_dumb_buffer(scanout.buffer);
+        }
+    }
+}
+
+impl SoftwareOutputBackend for DumbOutputBackend {
+    fn present(&self, pixels: &[u8], row_bytes: usize, height: usize) -> Result<(), ()> {
+        // The frame is dropped while another session has the display, a new one is drawn once
+        // the session is back
+        if !self.session.active.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock();
+        if let Some((mode, pending)) = self.modes.take_pending() {
+            let result = self.set_mode(&mut state, mode);
+            if result.is_err() {
+                // Go back to the mode that worked, so that the output keeps showing frames
+                let current = self.modes.modes[*self.modes.current.lock()];
+                let _ = self.set_mode(&mut state, current);
+            }
+            let (width, height) = mode.size();
+            let result =
+                result.map_err(|()| Error::Mode(format!("{}x{} was rejected", width, height)));
+            self.modes.finish_mode(pending, result);
+        }
+        if state.buffers.is_empty() {
+            return Err(());
+        }
+
+        let back = (state.front + 1) % state.buffers.len();
+        let scanout = &mut state.buffers[back];
+        let pitch = scanout.buffer.pitch() as usize;
+        {
+            let mut mapping = self
+                .fd
+                .map_dumb_buffer(&mut scanout.buffer)
+                .map_err(|err| error!("Failed to map scanout buffer: {:?}", err))?;
+
+            // Flutter's pixels are already XRGB8888, only the row stride can differ. A frame
+            // rendered before a mode change may not match the buffer's size.
+            let width = row_bytes.min(pitch);
+            for (y, row) in pixels
+                .chunks(row_bytes)
+                .take(height.min(mapping.len() / pitch))
+                .enumerate()
+            {
+                let width = width.min(row.len());
+                mapping[y * pitch..y * pitch + width].copy_from_slice(&row[..width]);
+            }
+        }
+        let framebuffer = scanout.framebuffer;
+
+        // The CRTC is set again after the session was inactive, as whoever had the display in
+        // the meantime may have changed it
+        let activation = self.session.activation.load(Ordering::SeqCst);
+        if state.activation != activation {
+            let mode = self.modes.modes[*self.modes.current.lock()];
+            self.fd
+                .set_crtc(
+                    self.crtc,
+                    Some(framebuffer),
+                    (0, 0),
+                    &[self.connector],
+                    Some(mode),
+                )
+                .map_err(|err| error!("Failed to restore mode: {:?}", err))?;
+            state.activation = activation;
+        } else {
+            self.fd
+                .page_flip(
+                    self.crtc,
+                    framebuffer,
+                    &[PageFlipFlags::PageFlipEvent],
+                    None,
+                )
+                .map_err(|err| error!("Failed to flip page: {:?}", err))?;
+        }
+        state.front = back;
+        Ok(())
+    }
+
+    fn get_framebuffer_dimensions(&self) -> (u32, u32) {
+        self.modes.current_mode().size()
+    }
+
+    fn vsync_mode(&self) -> VsyncMode {
+        VsyncMode::Vblank {
+            interval: VsyncMode::interval_from_refresh(self.modes.current_mode().refresh()),
+        }
+    }
+
+    fn pixel_ratio(&self) -> f64 {
+        pixel_ratio_from_physical(self.get_framebuffer_dimensions(), self.modes.physical_size)
+    }
+
+    fn mode_control(&self) -> Option<Arc<dyn ModeControl>> {
+        Some(self.modes.clone())
+    }
+}
+
+impl Drop for DumbOutputBackend {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        self.destroy_buffers(&mut state);
+    }
+}
+
+/// Renders with OpenGL on another GPU than the one driving the display, and copies each frame
+/// into the display's dumb buffers through system memory.
+struct GpuCopyOutputBackend {
+    context: WrappedContext,
+    surface: Mutex<WrappedPbuffer>,
+    cursor: Mutex<SoftwareCursorRenderer>,
+    display: DumbOutputBackend,
+}
+
+impl GpuCopyOutputBackend {
+    fn new(
+        render: &WrappedDisplay,
+        display: DumbOutputBackend,
+        cursor: Arc<Mutex<SoftwareCursor>>,
+    ) -> Result<Self, Error> {
+        let context = unsafe { WrappedContext::create_pbuffer_context(render)? };
+        let surface = context.create_pbuffer(display.get_framebuffer_dimensions())?;
+
+        Ok(Self {
+            context,
+            surface: Mutex::new(surface),
+            cursor: Mutex::new(SoftwareCursorRenderer::new(cursor)),
+            display,
+        })
+    }
+}
+
+impl FlutterOutputBackend for GpuCopyOutputBackend {
+    fn swap_buffers(&self) -> Result<(), ()> {
+        let surface = self.surface.lock();
+        let (width, height) = surface.size();
+        let mut pixels = unsafe {
+            self.cursor.lock().draw((width, height));
+            read_framebuffer((width, height))
+                .map_err(|err| error!("Failed to read back frame: {}", err))?
+        };
+        surface.swap_buffers().map_err(|_| ())?;
+
+        // Dumb buffers are XRGB8888, which is BGRA in memory
+        for pixel in pixels.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+        self.display
+            .present(&pixels, width as usize * 4, height as usize)
+    }
+
+    fn make_current(&self) -> Result<(), ()> {
+        // The display changes modes once it presents, the pbuffer follows on the next frame
+        let mut surface = self.surface.lock();
+        let size = self.display.get_framebuffer_dimensions();
+        if surface.size() != size {
+            *surface = self
+                .context
+                .create_pbuffer(size)
+                .map_err(|err| error!("Failed to resize pbuffer: {}", err))?;
+        }
+
+        unsafe { surface.make_current().map_err(|_| ()) }
+    }
+
+    fn get_framebuffer_dimensions(&self) -> (u32, u32) {
+        self.display.get_framebuffer_dimensions()
+    }
+
+    fn vsync_mode(&self) -> VsyncMode {
+        self.display.vsync_mode()
+    }
+
+    fn pixel_ratio(&self) -> f64 {
+        self.display.pixel_ratio()
+    }
+
+    fn mode_control(&self) -> Option<Arc<dyn ModeControl>> {
+        self.display.mode_control()
+    }
+
+    fn apply_transform(&self, _transform: Transform) -> bool {
+        false
+    }
+}
+
+/// A DRM device showing frames through dumb buffers, delivering their page flip events. The
+/// frames are rendered in software, or with OpenGL on another GPU.
+struct SoftwareDevice {
+    fd: SessionFd,
+    session: Arc<SoftwareSession>,
+    /// Display of the GPU rendering the frames, if not flutter's software renderer.
+    render_display: Option<WrappedDisplay>,
+    outputs: DrmOutputs,
+}
+
+impl SoftwareDevice {
+    fn process_events(&mut self) {
+        let events = match self.fd.receive_events() {
+            Ok(events) => events,
+            Err(err) => {
+                error!("Failed to read DRM events: {:?}", err);
+                return;
+            }
+        };
+
+        for event in events {
+            if let DrmEvent::PageFlip(event) = event {
+                if let Some(output) = self.outputs.borrow().get(&event.crtc) {
+                    trace!("vblank");
+                    output.output.vblank(instant_from_monotonic(event.duration));
+                }
+            }
+        }
+    }
+}
+
+impl AsRawFd for SoftwareDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Whether a software device may present, shared with the render threads of its outputs.
+struct SoftwareSession {
+    active: AtomicBool,
+    /// Counts the times the session became active, so that outputs know to set their CRTC.
+    activation: AtomicUsize,
+}
+
+/// Pauses the outputs of a software device while the session is inactive, the way smithay's
+/// observers do for OpenGL devices.
+struct SoftwareDeviceObserver {
+    dev_id: dev_t,
+    fd: SessionFd,
+    session: Arc<SoftwareSession>,
+    outputs: DrmOutputs,
+}
+
+impl SoftwareDeviceObserver {
+    fn is_device(&self, major_id: u32, minor_id: u32) -> bool {
+        major(self.dev_id) == major_id as u64 && minor(self.dev_id) == minor_id as u64
+    }
+}
+
+impl SessionObserver for SoftwareDeviceObserver {
+    fn pause(&mut self, device: Option<(u32, u32)>) {
+        if let Some((major_id, minor_id)) = device {
+            if !self.is_device(major_id, minor_id) {
+                return;
+            }
+        }
+
+        self.session.active.store(false, Ordering::SeqCst);
+        if let Err(err) = self.fd.release_master_lock() {
+            debug!("Failed to release DRM master: {:?}", err);
+        }
+    }
+
+    fn activate(&mut self, device: Option<(u32, u32, Option<RawFd>)>) {
+        if let Some((major_id, minor_id, new_fd)) = device {
+            if !self.is_device(major_id, minor_id) {
+                return;
+            }
+            // The session may hand out a new fd for the device, in place of the paused one
+            if let Some(new_fd) = new_fd {
+                if let Err(err) = dup2(new_fd, self.fd.as_raw_fd()) {
+                    error!("Failed to replace the device's fd: {}", err);
+                }
+            }
+        }
+
+        if let Err(err) = self.fd.acquire_master_lock() {
+            debug!("Failed to acquire DRM master: {:?}", err);
+        }
+        self.session.activation.fetch_add(1, Ordering::SeqCst);
+        self.session.active.store(true, Ordering::SeqCst);
+
+        // Flutter may have nothing new to draw, so the outputs are asked for a frame to set
+        // their CRTC with
+        for output in self.outputs.borrow().values() {
+            output.output.request_frame();
+        }
+    }
+}
+
+/// Cursor shown on the DRM cursor plane of a CRTC.
+///
+/// The fd is not owned by the plane. It stays open until the device's `DrmBackend` is closed,
+/// which only happens after `remove_output` has removed the plane from the cursor manager.
+struct DrmCursorPlane {
+    fd: SessionFd,
+    crtc: crtc::Handle,
+    size: (u32, u32),
+    buffer: Option<DumbBuffer>,
+    hotspot: (u32, u32),
+}
+
+impl DrmCursorPlane {
+    fn new(fd: SessionFd, crtc: crtc::Handle) -> Self {
+        let width = fd
+            .get_driver_capability(DriverCapability::CursorWidth)
+            .unwrap_or(64);
+        let height = fd
+            .get_driver_capability(DriverCapability::CursorHeight)
+            .unwrap_or(64);
+
+        Self {
+            fd,
+            crtc,
+            size: (width as u32, height as u32),
+            buffer: None,
+            hotspot: (0, 0),
+        }
+    }
+
+    fn upload(&mut self, image: &CursorImage) -> Result<(), ()> {
+        if image.width() > self.size.0 || image.height() > self.size.1 {
+            debug!("Cursor image too large for hardware cursor plane");
+            return Err(());
+        }
+
+        let mut buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => self
+                .fd
+                .create_dumb_buffer(self.size, DrmPixelFormat::ARGB8888, 32)
+                .map_err(|err| error!("Failed to create cursor buffer: {:?}", err))?,
+        };
+
+        let pitch = buffer.pitch() as usize;
+        {
+            let mut mapping = self
+                .fd
+                .map_dumb_buffer(&mut buffer)
+                .map_err(|err| error!("Failed to map cursor buffer: {:?}", err))?;
+
+            // Clear the whole buffer, as the image may be smaller than the plane
+            for byte in mapping.iter_mut() {
+                *byte = 0;
+            }
+
+            let width = image.width() as usize;
+            for (y, row) in image.pixels().chunks(width).enumerate() {
+                for (x, pixel) in row.iter().enumerate() {
+                    let offset = y * pitch + x * 4;
+                    mapping[offset..offset + 4].copy_from_slice(&pixel.to_le_bytes());
+                }
+            }
+        }
+
+        let hotspot = image.hotspot();
+        let result = self.fd.set_cursor2(
+            self.crtc,
+            Some(&buffer),
+            (hotspot.0 as i32, hotspot.1 as i32),
+        );
+        self.buffer = Some(buffer);
+        self.hotspot = hotspot;
+
+        result.map_err(|err| debug!("Failed to set hardware cursor: {:?}", err))
+    }
+}
+
+impl CursorPlane for DrmCursorPlane {
+    fn set_image(&mut self, image: Option<&CursorImage>) -> Result<(), ()> {
+        match image {
+            Some(image) => self.upload(image),
+            None => self
+                .fd
+                .set_cursor2::<DumbBuffer>(self.crtc, None, (0, 0))
+                .map_err(|err| debug!("Failed to hide hardware cursor: {:?}", err)),
+        }
+    }
+
+    fn move_to(&mut self, x: i32, y: i32) -> Result<(), ()> {
+        // The plane is positioned by its top left corner
+        self.fd
+            .move_cursor(
+                self.crtc,
+                (x - self.hotspot.0 as i32, y - self.hotspot.1 as i32),
+            )
+            .map_err(|err| debug!("Failed to move hardware cursor: {:?}", err))
+    }
+}
+
+/// Describes a connected display, to decide what to show on it.
+#[derive(Clone, Debug)]
+pub struct ConnectorDescriptor {
+    /// Path of the GPU driving the connector.
+    pub gpu: PathBuf,
+    /// Kernel name of the connector type, such as `HDMI-A`, `eDP` or `DSI`.
+    pub interface: &'static str,
+    /// Index among connectors of the same type, starting at 1.
+    pub interface_id: u32,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// Physical size in millimetres, if known.
+    pub physical_size: Option<(u32, u32)>,
+    pub crtc: crtc::Handle,
+    /// The display's EDID, if it could be read.
+    pub edid: Option<Edid>,
+}
+
+impl ConnectorDescriptor {
+    /// Connector name as used by the kernel, such as `HDMI-A-1`.
+    pub fn name(&self) -> String {
+        format!("{}-{}", self.interface, self.interface_id)
+    }
+}
+
+fn interface_name(interface: connector::Interface) -> &'static str {
+    match interface {
+        connector::Interface::VGA => "VGA",
+        connector::Interface::DVII => "DVI-I",
+        connector::Interface::DVID => "DVI-D",
+        connector::Interface::DVIA => "DVI-A",
+        connector::Interface::Composite => "Composite",
+        connector::Interface::SVideo => "SVIDEO",
+        connector::Interface::LVDS => "LVDS",
+        connector::Interface::Component => "Component",
+        connector::Interface::NinePinDIN => "DIN",
+        connector::Interface::DisplayPort => "DP",
+        connector::Interface::HDMIA => "HDMI-A",
+        connector::Interface::HDMIB => "HDMI-B",
+        connector::Interface::TV => "TV",
+        connector::Interface::EmbeddedDisplayPort => "eDP",
+        connector::Interface::Virtual => "Virtual",
+        connector::Interface::DSI => "DSI",
+        connector::Interface::DPI => "DPI",
+        _ => "Unknown",
+    }
+}
+
+/// Looks up a property of a DRM object by name, returning its handle and current value.
+fn find_property<D: ControlDevice, H: ResourceHandle>(
+    device: &D,
+    handle: H,
+    name: &str,
+) -> Option<(property::Handle, property::RawValue)> {
+    let props = device.get_properties(handle).ok()?;
+    let (handles, values) = props.as_props_and_values();
+
+    handles
+        .iter()
+        .zip(values.iter())
+        .find(|(handle, _)| match device.get_property(**handle) {
+            Ok(info) => info.name().to_str() == Ok(name),
+            Err(_) => false,
+        })
+        .map(|(handle, value)| (*handle, *value))
+}
+
+/// Reads the raw EDID blob of a connector.
+fn read_edid<D: ControlDevice>(device: &D, connector: connector::Handle) -> Option<Vec<u8>> {
+    match find_property(device, connector, "EDID")? {
+        (_, 0) => None,
+        (_, blob) => device.get_property_blob(blob).ok(),
+    }
+}
+
+fn describe_connector<D: ControlDevice>(
+    device: &D,
+    gpu: &Path,
+    connector_info: &ConnectorInfo,
+    crtc: crtc::Handle,
+) -> ConnectorDescriptor {
+    let edid = read_edid(device, connector_info.handle()).and_then(|edid| Edid::parse(&edid));
+    let (make, model, serial) = match edid.as_ref() {
+        Some(edid) => {
+            let model = edid
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("0x{:04x}", edid.product_code));
+            let serial = edid.serial.clone().or_else(|| match edid.serial_number {
+                0 => None,
+                serial => Some(serial.to_string()),
+            });
+            (Some(edid.manufacturer.clone()), Some(model), serial)
+        }
+        None => (None, None, None),
+    };
+
+    ConnectorDescriptor {
+        gpu: gpu.to_path_buf(),
+        interface: interface_name(connector_info.interface()),
+        interface_id: connector_info.interface_id(),
+        make,
+        model,
+        serial,
+        physical_size: connector_info
+            .size()
+            .filter(|(w, h)| *w > 0 && *h > 0)
+            .or_else(|| edid.as_ref().and_then(|edid| edid.physical_size)),
+        crtc,
+        edid,
     }
 }
 
 pub trait UdevOutputManagerHandler {
-    fn should_use_gpu(&self, path: PathBuf) -> bool;
+    /// Decides whether to drive the displays of a GPU. Each GPU in use renders the frames of its
+    /// own displays, unless `render_gpu` picks another.
+    fn should_use_gpu(&self, gpu: &GpuInfo) -> bool;
+
+    /// Chooses the modesetting API for a GPU. Defaults to atomic modesetting where supported.
+    fn modesetting_api(&self, _path: &Path) -> ModesettingApi {
+        ModesettingApi::Auto
+    }
+
+    /// Chooses how the outputs of a GPU render. Defaults to OpenGL, falling back to software
+    /// rendering on devices where EGL can't be set up.
+    fn render_mode(&self, _path: &Path) -> RenderMode {
+        RenderMode::Auto
+    }
+
+    /// Chooses the GPU rendering the outputs of a GPU in use, out of all GPUs on the seat.
+    /// Defaults to `None`, rendering on the GPU driving the displays.
+    ///
+    /// Frames rendered on another GPU are copied to the displays through system memory, which
+    /// costs a copy per frame. Not asked for GPUs rendering in software.
+    fn render_gpu(&self, _display: &GpuInfo, _gpus: &[GpuInfo]) -> Option<PathBuf> {
+        None
+    }
 
-    fn configure_output(&self) -> Option<FlutterEngineOptions>;
+    /// Chooses what to run on a newly connected display, or `None` to leave it off.
+    fn configure_output(&self, connector: &ConnectorDescriptor) -> Option<FlutterEngineOptions>;
+
+    /// Picks the mode for a newly connected display, or `None` to leave it off. Defaults to the
+    /// display's preferred mode.
+    fn choose_mode(
+        &self,
+        _connector: &ConnectorDescriptor,
+        modes: &[OutputMode],
+    ) -> Option<OutputMode> {
+        modes
+            .iter()
+            .find(|mode| mode.is_preferred())
+            .or_else(|| modes.first())
+            .cloned()
+    }
+
+    /// Called once an output has been created for a newly connected display.
+    fn output_added(&self, _output: &FlutterOutput) {}
+
+    /// Called when a display is disconnected, before its engine is shut down.
+    fn output_removed(&self, _output: &FlutterOutput) {}
+
+    /// Called after a GPU has been unplugged and all of its outputs removed.
+    fn gpu_removed(&self, _gpu: &GpuInfo) {}
+
+    /// Called when the primary GPU was unplugged, with the GPU which became primary in its place,
+    /// if any is left. GPUs not in use are then offered to `should_use_gpu` again.
+    fn primary_gpu_removed(&self, _gpu: &GpuInfo, _replacement: Option<&GpuInfo>) {}
+
+    /// Chooses the layout of a newly used keyboard. Returning `None` uses the default config set
+    /// with `UdevOutputManager::set_keyboard_config`.
+    fn configure_keyboard(&self, _device: &KeyboardDevice) -> Option<KeyboardConfig> {
+        None
+    }
 }
 
 pub struct UdevOutputManager<S: SessionNotifier + 'static> {
     engines: EngineWeakCollection,
     keyboard: Arc<Mutex<KeyboardManager>>,
+    pointer: Arc<Mutex<PointerManager>>,
+    cursor: Arc<Mutex<CursorManager>>,
+    gpus: Arc<Mutex<Vec<GpuInfo>>>,
+    backends: DrmBackends<S>,
     session: AutoSession,
     udev_session_id: AutoId,
     seat: String,
     libinput_session_id: AutoId,
-    libinput_event_source: Source<Generic<SourceFd<LibinputInputBackend>>>,
-    session_event_source: BoundAutoSession,
-    udev_event_source: Source<Generic<SourceFd<UdevBackend<UdevHandlerImpl<S, ()>>>>>,
+    /// Taken when the manager is dropped.
+    libinput_event_source: Option<Source<Generic<SourceFd<LibinputInputBackend>>>>,
+    session_event_source: Option<BoundAutoSession>,
+    udev_event_source: Option<Source<Generic<SourceFd<UdevBackend<UdevHandlerImpl<S, ()>>>>>>,
 }
 
 pub fn new_udev(
     manager: &FlutterDrmManager,
     handler: Arc<dyn UdevOutputManagerHandler>,
-) -> UdevOutputManager<impl SessionNotifier + 'static> {
+) -> Result<UdevOutputManager<impl SessionNotifier + 'static>, Error> {
     let engines = EngineWeakCollection::new();
+    let layout = OutputLayout::new();
     let keyboard = Arc::new(Mutex::new(KeyboardManager::new(engines.clone())));
+    let pointer = Arc::new(Mutex::new(PointerManager::new(layout.clone())));
+    let touch = Arc::new(Mutex::new(TouchManager::new(layout.clone())));
+    let cursor = Arc::new(Mutex::new(CursorManager::new(CursorTheme::from_env())));
+    pointer.lock().set_cursor(cursor.clone());
+    pointer.lock().set_keyboard(keyboard.clone());
+    touch.lock().set_keyboard(keyboard.clone());
 
     // Init session
-    let (session, mut notifier) = AutoSession::new(None).ok_or(()).unwrap();
+    let (session, mut notifier) = AutoSession::new(None).ok_or(Error::NoSession)?;
     let (udev_observer, udev_notifier) = notify_multiplexer();
     let udev_session_id = notifier.register(udev_observer);
 
     // Initialize the udev backend
     let seat = session.seat();
 
-    // TODO: Find primary gpu
-    //    let primary_gpu = primary_gpu(&context, &seat).unwrap_or_default();
-    //    if let None = primary_gpu {
-    //        panic!("No primary gpu detected");
-    //    }
+    // List the GPUs before udev reports them, so the primary one is known from the start
+    let gpus = enumerate_gpus(&seat).map_err(Error::GpuEnumeration)?;
+    match gpus.iter().find(|gpu| gpu.primary) {
+        Some(gpu) => info!("Primary GPU: {:?}", gpu.path),
+        None => warn!("No GPU found on {}", seat),
+    }
+    let gpus = Arc::new(Mutex::new(gpus));
+    let backends = Rc::new(RefCell::new(HashMap::new()));
 
     let udev_backend = UdevBackend::new(
         UdevHandlerImpl {
             engines: engines.clone(),
+            layout,
             keyboard: keyboard.clone(),
-            handler,
+            cursor: cursor.clone(),
+            handler: handler.clone(),
+            gpus: gpus.clone(),
+            seat: seat.clone(),
             session: session.clone(),
-            backends: HashMap::new(),
+            backends: backends.clone(),
             loop_handle: manager.event_loop.handle(),
             notifier: udev_notifier,
         },
         seat.clone(),
         None,
     )
-    .map_err(|_| ())
-    .unwrap();
+    .map_err(Error::Udev)?;
 
     // Initialize libinput backend
     let mut libinput_context =
         Libinput::new_with_udev::<LibinputSessionInterface<AutoSession>>(session.clone().into());
     let libinput_session_id = notifier.register(libinput_context.observer());
-    libinput_context.udev_assign_seat(&seat).unwrap();
+    libinput_context
+        .udev_assign_seat(&seat)
+        .map_err(|_| Error::SeatAssignment(seat.clone()))?;
     let mut libinput_backend = LibinputInputBackend::new(libinput_context, None);
-    libinput_backend.set_handler(LibInputHandler::new(keyboard.clone(), session.clone()));
+    libinput_backend.set_handler(LibInputHandler::new(
+        keyboard.clone(),
+        pointer.clone(),
+        touch,
+        handler,
+        session.clone(),
+    ));
 
     // Bind all our objects that get driven by the event loop
     let libinput_event_source = libinput_bind(libinput_backend, manager.event_loop.handle())
-        .map_err(|e| -> IoError { e.into() })
-        .unwrap();
+        .map_err(|e| Error::EventSource(e.into()))?;
     let session_event_source = auto_session_bind(notifier, &manager.event_loop.handle())
-        .map_err(|(e, _)| e)
-        .unwrap();
+        .map_err(|(e, _)| Error::EventSource(e))?;
     let udev_event_source = udev_backend_bind(udev_backend, &manager.event_loop.handle())
-        .map_err(|e| -> IoError { e.into() })
-        .unwrap();
+        .map_err(|e| Error::EventSource(e.into()))?;
 
-    UdevOutputManager {
+    Ok(UdevOutputManager {
         engines,
         keyboard,
+        pointer,
+        cursor,
+        gpus,
+        backends,
         session,
         udev_session_id,
         seat,
         libinput_session_id,
-        libinput_event_source,
-        session_event_source,
-        udev_event_source,
-    }
+        libinput_event_source: Some(libinput_event_source),
+        session_event_source: Some(session_event_source),
+        udev_event_source: Some(udev_event_source),
+    })
 }
 
 impl<S: SessionNotifier + 'static> UdevOutputManager<S> {
+    /// Changes the keyboard layout and repeat settings, recompiling the keymap immediately.
+    pub fn set_keyboard_config(&self, config: KeyboardConfig) -> Result<(), Error> {
+        self.keyboard.lock().set_config(config)
+    }
+
+    /// Position of the pointer in the global layout, in logical pixels.
+    pub fn pointer_position(&self) -> (f64, f64) {
+        self.pointer.lock().position()
+    }
+
+    /// Moves the pointer to the given position in the global layout, such as to center it on
+    /// an output.
+    pub fn warp_pointer(&self, x: f64, y: f64) {
+        self.pointer.lock().motion_absolute(x, y);
+    }
+
+    /// Replaces the cursor image, or hides the cursor when `None`.
+    ///
+    /// The image is replaced again when flutter requests a different system cursor.
+    pub fn set_cursor_image(&self, image: Option<CursorImage>) {
+        self.cursor.lock().set_image(image);
+    }
+
+    /// Switches to one of flutter's system cursor kinds, such as `basic` or `text`.
+    pub fn set_system_cursor(&self, kind: &str) {
+        CursorManager::set_system_cursor(&self.cursor, kind);
+    }
+
+    /// Changes the cursor theme used for system cursors.
+    pub fn set_cursor_theme(&self, theme: CursorTheme) {
+        self.cursor.lock().set_theme(theme);
+        CursorManager::set_system_cursor(&self.cursor, "basic");
+    }
+
+    /// Lists the GPUs on the seat, whether in use or not.
+    pub fn gpus(&self) -> Vec<GpuInfo> {
+        self.gpus.lock().clone()
+    }
+
+    /// The GPU the system booted with, or whichever took its place once it was unplugged.
+    pub fn primary_gpu(&self) -> Option<GpuInfo> {
+        self.gpus.lock().iter().find(|gpu| gpu.primary).cloned()
+    }
+
+    /// Shuts every output's engine down and waits for its thread, then closes the GPUs and ends
+    /// the session, which releases DRM master and restores the VT. Dropping the manager does the
+    /// same.
     pub fn cleanup(self) {
-        let mut notifier = self.session_event_source.unbind();
-        notifier.unregister(self.libinput_session_id);
-        notifier.unregister(self.udev_session_id);
+        drop(self);
+    }
+}
+
+impl<S: SessionNotifier + 'static> Drop for UdevOutputManager<S> {
+    fn drop(&mut self) {
+        // Forget the GPUs first, so closing one doesn't offer the others in its place
+        self.gpus.lock().clear();
+
+        let backends: Vec<_> = self
+            .backends
+            .borrow_mut()
+            .drain()
+            .map(|(_, backend)| backend)
+            .collect();
+        let mut outputs = Vec::new();
+        for backend in &backends {
+            for (_, output) in backend.outputs.borrow_mut().drain() {
+                // The cursor plane uses the fd closed below
+                let engine = output.output.engine().downgrade();
+                self.cursor.lock().remove_output(&engine);
+                outputs.push(output.output);
+            }
+        }
+        FlutterOutput::shutdown_all(&outputs);
+
+        for backend in backends {
+            info!("Closing device: {:?}", backend.path);
+            // The udev session notifier goes away with the udev backend below
+            let _ = backend.close(&mut self.session);
+        }
+
+        if let Some(source) = self.session_event_source.take() {
+            let mut notifier = source.unbind();
+            notifier.unregister(self.libinput_session_id);
+            notifier.unregister(self.udev_session_id);
+        }
+        if let Some(source) = self.libinput_event_source.take() {
+            source.remove();
+        }
+        if let Some(source) = self.udev_event_source.take() {
+            source.remove();
+        }
+        // The session is restored once its last handle, this one, is dropped
+    }
+}
+
+/// An output driven by a connector and CRTC of a DRM device.
+struct DrmOutput {
+    connector: connector::Handle,
+    output: FlutterOutput,
+}
+
+type DrmOutputs = Rc<RefCell<HashMap<crtc::Handle, DrmOutput>>>;
 
-        self.libinput_event_source.remove();
-        self.udev_event_source.remove();
+/// Event source of a DRM device, which depends on how its outputs render.
+enum DeviceSource<S: SessionNotifier> {
+    /// An OpenGL device, observing the session to pause while it is inactive.
+    Render(Source<Generic<SourceFd<RenderDevice>>>, S::Id),
+    /// A device rendered to in software, whose observer stops presenting while the session is
+    /// inactive.
+    Software(Source<Generic<SourceFd<SoftwareDevice>>>, S::Id),
+}
+
+struct DrmBackend<S: SessionNotifier> {
+    path: PathBuf,
+    fd: SessionFd,
+    event_source: DeviceSource<S>,
+    outputs: DrmOutputs,
+}
+
+impl<S: SessionNotifier> DrmBackend<S> {
+    /// Closes the device once its outputs are gone, returning its session observer to
+    /// unregister.
+    fn close(self, session: &mut AutoSession) -> S::Id {
+        match self.event_source {
+            DeviceSource::Render(event_source, session_id) => {
+                // Dropping the event source closes the device
+                let _device = event_source.remove();
+                debug!("Dropping device");
+                session_id
+            }
+            DeviceSource::Software(event_source, session_id) => {
+                event_source.remove();
+                if let Err(err) = session.close(self.fd.as_raw_fd()) {
+                    error!("Failed to close device {:?}: {:?}", self.path, err);
+                }
+                session_id
+            }
+        }
     }
 }
 
+/// The opened DRM devices, shared with the output manager to close them on cleanup.
+type DrmBackends<S> = Rc<RefCell<HashMap<dev_t, DrmBackend<S>>>>;
+
 struct UdevHandlerImpl<S: SessionNotifier, Data: 'static> {
     engines: EngineWeakCollection,
+    layout: OutputLayout,
     keyboard: Arc<Mutex<KeyboardManager>>,
+    cursor: Arc<Mutex<CursorManager>>,
     handler: Arc<dyn UdevOutputManagerHandler>,
+    gpus: Arc<Mutex<Vec<GpuInfo>>>,
+    seat: String,
     session: AutoSession,
-    backends: HashMap<
-        dev_t,
-        (
-            S::Id,
-            Source<Generic<SourceFd<RenderDevice>>>,
-            Rc<RefCell<HashMap<crtc::Handle, FlutterOutput>>>,
-        ),
-    >,
+    backends: DrmBackends<S>,
     loop_handle: LoopHandle<Data>,
     notifier: S,
 }
 
 impl<S: SessionNotifier, Data: 'static> UdevHandlerImpl<S, Data> {
-    pub fn scan_connectors(
+    /// Brings the outputs in line with the connected connectors, creating outputs for newly
+    /// connected connectors and removing those whose connector was disconnected.
+    fn scan_connectors<F>(
         &self,
-        device: &mut RenderDevice,
-    ) -> HashMap<crtc::Handle, FlutterOutput> {
+        fd: SessionFd,
+        outputs: &mut HashMap<crtc::Handle, DrmOutput>,
+        mut create_output: F,
+    ) where
+        F: FnMut(&ConnectorInfo, crtc::Handle) -> Option<FlutterOutput>,
+    {
         // Get a set of all modesetting resource handles (excluding planes)
-        let res_handles = device.resource_handles().unwrap();
+        let res_handles = match fd.resource_handles() {
+            Ok(handles) => handles,
+            Err(err) => {
+                error!("Failed to query resource handles: {:?}", err);
+                return;
+            }
+        };
 
-        // Use first connected connector
         let connector_infos: Vec<ConnectorInfo> = res_handles
             .connectors()
             .iter()
-            .map(|conn| device.get_connector_info(*conn).unwrap())
+            .flat_map(|conn| fd.get_connector(*conn))
             .filter(|conn| conn.state() == ConnectorState::Connected)
             .inspect(|conn| info!("Connected: {:?}", conn.interface()))
             .collect();
 
-        let mut backends = HashMap::new();
+        // Tear down outputs whose connector is gone
+        let disconnected: Vec<crtc::Handle> = outputs
+            .iter()
+            .filter(|(_, output)| {
+                !connector_infos
+                    .iter()
+                    .any(|info| info.handle() == output.connector)
+            })
+            .map(|(crtc, _)| *crtc)
+            .collect();
+        let disconnected: Vec<FlutterOutput> = disconnected
+            .into_iter()
+            .filter_map(|crtc| outputs.remove(&crtc))
+            .map(|output| {
+                info!("Disconnected: {:?}", output.connector);
+                output.output
+            })
+            .collect();
+        self.remove_outputs(disconnected);
 
         // very naive way of finding good crtc/encoder/connector combinations.
         for connector_info in connector_infos {
+            if outputs
+                .values()
+                .any(|output| output.connector == connector_info.handle())
+            {
+                continue;
+            }
+
             let encoder_infos = connector_info
                 .encoders()
                 .iter()
                 .filter_map(|e| *e)
-                .flat_map(|encoder_handle| device.get_encoder_info(encoder_handle))
+                .flat_map(|encoder_handle| fd.get_encoder(encoder_handle))
                 .collect::<Vec<EncoderInfo>>();
-            for encoder_info in encoder_infos {
-                'inner: for crtc in res_handles.filter_crtcs(encoder_info.possible_crtcs()) {
-                    if !backends.contains_key(&crtc) {
-                        // device.get_crtc_info()
-                        // let info = device.resource_info::<crtc::Info>(crtc);
-                        // info!("CRTC Info: {:?}", info);
-
-                        let options = match self.handler.configure_output() {
-                            Some(opts) => opts,
-                            None => continue 'inner,
-                        };
-
-                        // Create new egl context for rendering
-                        let device_context = device.get_context();
-                        let raw_context = device_context.get_raw_context();
-                        let raw_display = device_context.get_raw_display();
-                        let render_context = unsafe {
-                            WrappedContext::create_context_dir(*raw_context, *raw_display)
-                        };
-
-                        // Create new egl surface to render to
-                        let surface = EGLContext::borrow_mut(&device_context)
-                            .create_surface(crtc)
-                            .unwrap();
-                        let surface = render_context.create_surface(surface);
-
-                        // Create output
-                        let backend = DrmOutputBackend { surface };
-                        let output = FlutterOutput::new(backend, options, self.keyboard.clone());
-                        let engine = output.engine();
-                        self.engines.add(engine.downgrade());
-
-                        backends.insert(crtc, output);
-                        break;
+            'outer: for encoder_info in encoder_infos {
+                for crtc in res_handles.filter_crtcs(encoder_info.possible_crtcs()) {
+                    if !outputs.contains_key(&crtc) {
+                        if let Some(output) = create_output(&connector_info, crtc) {
+                            outputs.insert(
+                                crtc,
+                                DrmOutput {
+                                    connector: connector_info.handle(),
+                                    output,
+                                },
+                            );
+                            break 'outer;
+                        }
                     }
                 }
             }
         }
+    }
 
-        backends
+    /// Asks the handler what to run on a connector and in which mode, returning the options and
+    /// modes of the new output.
+    fn configure_output(
+        &self,
+        fd: SessionFd,
+        path: &Path,
+        connector_info: &ConnectorInfo,
+        crtc: crtc::Handle,
+    ) -> Option<(FlutterEngineOptions, Arc<DrmModeControl>, Mode)> {
+        let descriptor = describe_connector(&fd, path, connector_info, crtc);
+        info!(
+            "Configuring output {} ({:?})",
+            descriptor.name(),
+            descriptor.model
+        );
+
+        let mut options = self.handler.configure_output(&descriptor)?;
+
+        // Let the application pick the resolution and refresh rate
+        let modes = Arc::new(DrmModeControl::new(
+            connector_info.modes().to_vec(),
+            connector_info.size().unwrap_or((0, 0)),
+        ));
+        let mode = match self.handler.choose_mode(&descriptor, &modes.modes()) {
+            Some(mode) => mode,
+            None => {
+                info!("No mode chosen for {}", descriptor.name());
+                return None;
+            }
+        };
+        // Applied directly by the new backend, the render thread doesn't exist yet
+        if let Err(err) = modes.check_mode(&mode) {
+            error!("{}", err);
+            return None;
+        }
+        *modes.current.lock() = mode.id;
+
+        let cursor = self.cursor.clone();
+        options.add_setup_callback(move |engine| {
+            engine.add_plugin(MouseCursorPlugin::new(cursor));
+        });
+
+        let mode = modes.modes[mode.id];
+        Some((options, modes, mode))
+    }
+
+    fn create_render_output(
+        &self,
+        device: &mut RenderDevice,
+        path: &Path,
+        connector_info: &ConnectorInfo,
+        crtc: crtc::Handle,
+    ) -> Option<FlutterOutput> {
+        let fd = SessionFd(device.as_raw_fd());
+        let (options, modes, mode) = self.configure_output(fd, path, connector_info, crtc)?;
+
+        // Create new egl context for rendering
+        let device_context = device.get_context();
+        let raw_context = device_context.get_raw_context();
+        let raw_display = device_context.get_raw_display();
+        let render_context =
+            match unsafe { WrappedContext::create_context_dir(*raw_context, *raw_display) } {
+                Ok(context) => context,
+                Err(err) => {
+                    error!("Failed to create render context: {}", err);
+                    return None;
+                }
+            };
+
+        // Create new egl surface to render to
+        let surface = match EGLContext::borrow_mut(&device_context).create_surface(crtc) {
+            Ok(surface) => surface,
+            Err(err) => {
+                error!("Failed to create surface: {:?}", err);
+                return None;
+            }
+        };
+        if let Err(err) = surface.use_mode(Some(mode)) {
+            error!("Failed to set mode: {:?}", err);
+            return None;
+        }
+        let surface = match render_context.create_surface(surface) {
+            Ok(surface) => surface,
+            Err(err) => {
+                error!("Failed to create surface: {}", err);
+                return None;
+            }
+        };
+
+        // Create output
+        let software_cursor = Arc::new(Mutex::new(SoftwareCursor::default()));
+        let backend = DrmOutputBackend {
+            surface,
+            cursor: Mutex::new(SoftwareCursorRenderer::new(software_cursor.clone())),
+            modes,
+            fd,
+            crtc,
+        };
+        let output = FlutterOutput::new(backend, options, self.keyboard.clone());
+        self.add_output(output, fd, crtc, software_cursor)
+    }
+
+    fn create_software_output(
+        &self,
+        fd: SessionFd,
+        session: &Arc<SoftwareSession>,
+        render_display: Option<&WrappedDisplay>,
+        path: &Path,
+        connector_info: &ConnectorInfo,
+        crtc: crtc::Handle,
+    ) -> Option<FlutterOutput> {
+        let (options, modes, mode) = self.configure_output(fd, path, connector_info, crtc)?;
+
+        let connector = connector_info.handle();
+        let backend =
+            DumbOutputBackend::new(fd, crtc, connector, modes, session.clone(), mode).ok()?;
+
+        // Nothing draws the software cursor into frames of the software renderer, which only show
+        // the cursor where the CRTC has a cursor plane
+        let software_cursor = Arc::new(Mutex::new(SoftwareCursor::default()));
+        let output = match render_display {
+            Some(render) => GpuCopyOutputBackend::new(render, backend, software_cursor.clone())
+                .and_then(|backend| FlutterOutput::new(backend, options, self.keyboard.clone())),
+            None => FlutterOutput::new_software(backend, options, self.keyboard.clone()),
+        };
+        self.add_output(output, fd, crtc, software_cursor)
+    }
+
+    /// Adds a newly created output to the layout and cursor, and reports it to the handler.
+    fn add_output(
+        &self,
+        output: Result<FlutterOutput, Error>,
+        fd: SessionFd,
+        crtc: crtc::Handle,
+        software_cursor: Arc<Mutex<SoftwareCursor>>,
+    ) -> Option<FlutterOutput> {
+        let output = match output {
+            Ok(output) => output,
+            Err(err) => {
+                error!("Failed to create output: {}", err);
+                return None;
+            }
+        };
+        let engine = output.engine();
+        self.engines.add(engine.downgrade());
+        output.add_to_layout(&self.layout);
+
+        let cursor_plane = DrmCursorPlane::new(fd, crtc);
+        self.cursor.lock().add_output(
+            engine.downgrade(),
+            software_cursor,
+            Some(Box::new(cursor_plane)),
+        );
+
+        self.handler.output_added(&output);
+        Some(output)
+    }
+
+    fn remove_outputs(&self, outputs: Vec<FlutterOutput>) {
+        for output in &outputs {
+            let engine = output.engine().downgrade();
+            self.layout.remove(&engine);
+            self.cursor.lock().remove_output(&engine);
+
+            self.handler.output_removed(output);
+        }
+
+        // The render threads must be done with the device before it is dropped
+        FlutterOutput::shutdown_all(&outputs);
+    }
+}
+
+impl<S: SessionNotifier, Data: 'static> UdevHandlerImpl<S, Data> {
+    /// Looks up a GPU reported by udev, listing it first if it was plugged in after startup.
+    fn find_gpu(&self, path: &Path) -> Option<GpuInfo> {
+        let mut gpus = self.gpus.lock();
+        if !gpus.iter().any(|gpu| gpu.path == path) {
+            match enumerate_gpus(&self.seat) {
+                Ok(found) => {
+                    // A GPU plugged in later only becomes primary if there is none
+                    let has_primary = gpus.iter().any(|gpu| gpu.primary);
+                    if let Some(mut gpu) = found.into_iter().find(|gpu| gpu.path == path) {
+                        gpu.primary = !has_primary;
+                        gpus.push(gpu);
+                    }
+                }
+                Err(err) => error!("Failed to list GPUs: {}", err),
+            }
+        }
+
+        gpus.iter().find(|gpu| gpu.path == path).cloned()
+    }
+
+    /// Makes another GPU primary after the primary one was unplugged, and offers the GPUs not in
+    /// use to the handler again.
+    fn replace_primary_gpu(&mut self, removed: &GpuInfo) {
+        let (replacement, unused) = {
+            let mut gpus = self.gpus.lock();
+            let replacement = choose_primary(&gpus).map(|index| {
+                gpus[index].primary = true;
+                gpus[index].clone()
+            });
+            let unused: Vec<(dev_t, PathBuf)> = gpus
+                .iter()
+                .filter(|gpu| !self.backends.borrow().contains_key(&gpu.device_id))
+                .map(|gpu| (gpu.device_id, gpu.path.clone()))
+                .collect();
+            (replacement, unused)
+        };
+
+        match replacement.as_ref() {
+            Some(gpu) => info!("Primary GPU is now {:?}", gpu.path),
+            None => warn!("No GPU left on {}", self.seat),
+        }
+        self.handler
+            .primary_gpu_removed(removed, replacement.as_ref());
+
+        for (device, path) in unused {
+            self.device_added(device, path);
+        }
+    }
+
+    /// Opens the GPU the handler chose to render the outputs of a GPU, if it chose another one.
+    fn open_render_gpu(&self, display: &GpuInfo) -> Option<WrappedDisplay> {
+        let gpus = self.gpus.lock().clone();
+        let path = self.handler.render_gpu(display, &gpus)?;
+        let path = path.canonicalize().unwrap_or(path);
+        if path == display.path {
+            return None;
+        }
+
+        match unsafe { WrappedDisplay::for_device(&path) } {
+            Ok(render_display) => {
+                info!("Rendering the outputs of {:?} on {:?}", display.path, path);
+                Some(render_display)
+            }
+            Err(err) => {
+                error!(
+                    "Failed to render on {:?}, using {:?}: {}",
+                    path, display.path, err
+                );
+                None
+            }
+        }
+    }
+
+    fn add_render_device(&mut self, path: PathBuf, mut device: RenderDevice) {
+        let fd = SessionFd(device.as_raw_fd());
+        let mut outputs = HashMap::new();
+        self.scan_connectors(fd, &mut outputs, |connector_info, crtc| {
+            self.create_render_output(&mut device, &path, connector_info, crtc)
+        });
+        let outputs = Rc::new(RefCell::new(outputs));
+
+        // Set the handler.
+        device.set_handler(DrmHandlerImpl {
+            outputs: outputs.clone(),
+        });
+
+        let device_session_id = self.notifier.register(device.observer());
+        let dev_id = device.device_id();
+        let event_source = match device_bind(&self.loop_handle, device) {
+            Ok(source) => source,
+            Err(err) => {
+                let err: IoError = err.into();
+                error!("Failed to bind device {:?}: {}", path, err);
+                let outputs = outputs
+                    .borrow_mut()
+                    .drain()
+                    .map(|(_, output)| output.output)
+                    .collect();
+                self.remove_outputs(outputs);
+                self.notifier.unregister(device_session_id);
+                return;
+            }
+        };
+
+        self.backends.borrow_mut().insert(
+            dev_id,
+            DrmBackend {
+                path,
+                fd,
+                event_source: DeviceSource::Render(event_source, device_session_id),
+                outputs,
+            },
+        );
+    }
+
+    fn add_software_device(
+        &mut self,
+        dev_id: dev_t,
+        path: PathBuf,
+        fd: SessionFd,
+        render_display: Option<WrappedDisplay>,
+    ) {
+        // Modesetting needs master, which a failed attempt at OpenGL may have dropped
+        if let Err(err) = fd.acquire_master_lock() {
+            debug!("Failed to acquire DRM master: {:?}", err);
+        }
+
+        // Devices are added while the session is active
+        let session = Arc::new(SoftwareSession {
+            active: AtomicBool::new(true),
+            activation: AtomicUsize::new(0),
+        });
+        let mut outputs = HashMap::new();
+        self.scan_connectors(fd, &mut outputs, |connector_info, crtc| {
+            let render = render_display.as_ref();
+            self.create_software_output(fd, &session, render, &path, connector_info, crtc)
+        });
+        let outputs = Rc::new(RefCell::new(outputs));
+
+        let device_session_id = self.notifier.register(SoftwareDeviceObserver {
+            dev_id,
+            fd,
+            session: session.clone(),
+            outputs: outputs.clone(),
+        });
+        let mut source = Generic::from_fd_source(SoftwareDevice {
+            fd,
+            session,
+            render_display,
+            outputs: outputs.clone(),
+        });
+        source.set_interest(Ready::readable());
+        source.set_pollopts(PollOpt::level());
+        let event_source = match self.loop_handle.insert_source(source, |event, _| {
+            event.source.borrow_mut().0.process_events()
+        }) {
+            Ok(source) => source,
+            Err(err) => {
+                let err: IoError = err.into();
+                error!("Failed to bind device {:?}: {}", path, err);
+                let outputs = outputs
+                    .borrow_mut()
+                    .drain()
+                    .map(|(_, output)| output.output)
+                    .collect();
+                self.remove_outputs(outputs);
+                self.notifier.unregister(device_session_id);
+                if let Err(err) = self.session.close(fd.as_raw_fd()) {
+                    error!("Failed to close device {:?}: {:?}", path, err);
+                }
+                return;
+            }
+        };
+
+        self.backends.borrow_mut().insert(
+            dev_id,
+            DrmBackend {
+                path,
+                fd,
+                event_source: DeviceSource::Software(event_source, device_session_id),
+                outputs,
+            },
+        );
     }
 }
 
 impl<S: SessionNotifier, Data: 'static> UdevHandler for UdevHandlerImpl<S, Data> {
-    fn device_added(&mut self, _device: dev_t, path: PathBuf) {
-        if !self.handler.should_use_gpu(path.canonicalize().unwrap()) {
+    fn device_added(&mut self, device: dev_t, path: PathBuf) {
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            Err(err) => {
+                error!("Failed to resolve device path {:?}: {}", path, err);
+                return;
+            }
+        };
+
+        let gpu = match self.find_gpu(&path) {
+            Some(gpu) => gpu,
+            None => {
+                error!("{:?} is not a GPU on {}", path, self.seat);
+                return;
+            }
+        };
+        if !self.handler.should_use_gpu(&gpu) {
             return;
         }
 
-        info!("Device added: {:?}", path.canonicalize().unwrap());
+        info!("Device added: {:?}", path);
 
         // Try to open the device
-        if let Some(mut device) = self
-            .session
-            .open(
-                &path,
-                OFlag::O_RDWR | OFlag::O_CLOEXEC | OFlag::O_NOCTTY | OFlag::O_NONBLOCK,
-            )
-            .ok()
-            .and_then(|fd| LegacyDrmDevice::new(SessionFd(fd), None).ok())
-            .and_then(|drm| GbmDevice::new(drm, None).ok())
-            .and_then(|gbm| EglDevice::new(gbm, None).ok())
-        {
-            let backends = Rc::new(RefCell::new(self.scan_connectors(&mut device)));
+        let fd = match self.session.open(
+            &path,
+            OFlag::O_RDWR | OFlag::O_CLOEXEC | OFlag::O_NOCTTY | OFlag::O_NONBLOCK,
+        ) {
+            Ok(fd) => SessionFd(fd),
+            Err(err) => {
+                error!("Failed to open device {:?}: {:?}", path, err);
+                return;
+            }
+        };
 
-            // Set the handler.
-            device.set_handler(DrmHandlerImpl {
-                backends: backends.clone(),
-            });
+        let render_mode = self.handler.render_mode(&path);
+        if render_mode != RenderMode::Software {
+            if let Some(render_display) = self.open_render_gpu(&gpu) {
+                return self.add_software_device(device, path, fd, Some(render_display));
+            }
 
-            let device_session_id = self.notifier.register(device.observer());
-            let dev_id = device.device_id();
-            let event_source = device_bind(&self.loop_handle, device)
-                .map_err(|e| -> IoError { e.into() })
-                .unwrap();
-
-            self.backends
-                .insert(dev_id, (device_session_id, event_source, backends));
-        }
-    }
-
-    fn device_changed(&mut self, _device: dev_t) {
-        //quick and dirty, just re-init all backends
-        //        if let Some((_, ref mut evt_source, ref backends)) = self.backends.get_mut(&device) {
-        //            let source = evt_source.clone_inner();
-        //            let mut evented = source.borrow_mut();
-        //            let mut backends = backends.borrow_mut();
-        //            let new_backends = UdevHandlerImpl::<S, Data>::scan_connectors(
-        //                &mut (*evented).0,
-        //                &self.logger,
-        //            );
-        //            *backends = new_backends;
-        //        }
-
-        error!("Device change not implemented");
-    }
-
-    fn device_removed(&mut self, _device: dev_t) {
-        error!("Device remove not implemented");
-
-        // drop the backends on this side
-        //        if let Some((id, evt_source, renderers)) = self.backends.remove(&device) {
-        //            // drop surfaces
-        //            renderers.borrow_mut().clear();
-        //            debug!("Surfaces dropped");
-        //
-        //            let device = Rc::try_unwrap(evt_source.remove().unwrap())
-        //                .map_err(|_| "This should not happend")
-        //                .unwrap()
-        //                .into_inner()
-        //                .0;
-        //
-        //            // TODO: Exit if primary gpu was disconnected
-        //            if device.dev_path().and_then(|path| path.canonicalize().ok()) == self.primary_gpu {
-        //                unimplemented!();
-        //            }
-        //
-        //            self.notifier.unregister(id);
-        //            debug!("Dropping device");
-        //        }
+            let render_device = open_drm_device(fd, self.handler.modesetting_api(&path))
+                .and_then(|drm| GbmDevice::new(drm, None).ok())
+                .and_then(|gbm| EglDevice::new(gbm, None).ok());
+            match render_device {
+                Some(render_device) => return self.add_render_device(path, render_device),
+                None if render_mode == RenderMode::Auto => {
+                    info!("OpenGL unavailable on {:?}, rendering in software", path);
+                }
+                None => {
+                    error!("Failed to set up OpenGL on {:?}", path);
+                    if let Err(err) = self.session.close(fd.as_raw_fd()) {
+                        error!("Failed to close device {:?}: {:?}", path, err);
+                    }
+                    return;
+                }
+            }
+        }
+
+        self.add_software_device(device, path, fd, None);
+    }
+
+    fn device_changed(&mut self, device: dev_t) {
+        let backends = self.backends.borrow();
+        let backend = match backends.get(&device) {
+            Some(backend) => backend,
+            None => return,
+        };
+
+        debug!("Device changed: {:?}", backend.path);
+
+        let mut outputs = backend.outputs.borrow_mut();
+        match &backend.event_source {
+            DeviceSource::Render(source, _) => {
+                let source = source.clone_inner();
+                let mut evented = source.borrow_mut();
+                self.scan_connectors(backend.fd, &mut outputs, |connector_info, crtc| {
+                    self.create_render_output(&mut evented.0, &backend.path, connector_info, crtc)
+                });
+            }
+            DeviceSource::Software(source, _) => {
+                let source = source.clone_inner();
+                let device = source.borrow();
+                let (session, render) = (&device.0.session, device.0.render_display.as_ref());
+                self.scan_connectors(backend.fd, &mut outputs, |connector_info, crtc| {
+                    let (fd, path) = (backend.fd, &backend.path);
+                    self.create_software_output(fd, session, render, path, connector_info, crtc)
+                });
+            }
+        }
+    }
+
+    fn device_removed(&mut self, device: dev_t) {
+        let gpu = {
+            let mut gpus = self.gpus.lock();
+            gpus.iter()
+                .position(|gpu| gpu.device_id == device)
+                .map(|index| gpus.remove(index))
+        };
+
+        let backend = self.backends.borrow_mut().remove(&device);
+        if let Some(backend) = backend {
+            info!("Device removed: {:?}", backend.path);
+
+            // Stop the outputs before the device goes away
+            let outputs = backend
+                .outputs
+                .borrow_mut()
+                .drain()
+                .map(|(_, output)| output.output)
+                .collect();
+            self.remove_outputs(outputs);
+
+            let session_id = backend.close(&mut self.session);
+            self.notifier.unregister(session_id);
+
+            if let Some(gpu) = gpu.as_ref() {
+                self.handler.gpu_removed(gpu);
+            }
+        }
+
+        if let Some(gpu) = gpu.filter(|gpu| gpu.primary) {
+            self.replace_primary_gpu(&gpu);
+        }
     }
 }
 
 pub struct DrmHandlerImpl {
-    backends: Rc<RefCell<HashMap<crtc::Handle, FlutterOutput>>>,
+    outputs: DrmOutputs,
 }
 
 impl DeviceHandler for DrmHandlerImpl {
     type Device = RenderDevice;
 
     fn vblank(&mut self, crtc: crtc::Handle) {
-        if let Some(_engine) = self.backends.borrow().get(&crtc) {
+        if let Some(output) = self.outputs.borrow().get(&crtc) {
             trace!("vblank");
-            //            let now = Instant::now();
-            //            _engine
-            //                .engine()
-            //                .notify_vsync(now + Duration::from_millis(16), now + Duration::from_millis(32));
+            // Smithay doesn't pass the page flip's timestamp on
+            output.output.vblank(Instant::now());
         }
     }
 
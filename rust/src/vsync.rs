@@ -0,0 +1,233 @@
+use crate::output::VsyncMode;
+use log::debug;
+use std::time::{Duration, Instant};
+
+/// Number of refresh intervals to wait for a page flip before assuming the vblank was missed.
+const MISSED_VBLANK_INTERVALS: u32 = 4;
+
+/// Decides when flutter may start each frame, answering its vsync requests.
+pub(crate) struct FrameClock {
+    mode: VsyncMode,
+    requested: bool,
+    presented: Option<Instant>,
+    last_vblank: Option<Instant>,
+}
+
+impl FrameClock {
+    pub fn new(mode: VsyncMode) -> Self {
+        Self {
+            mode,
+            requested: false,
+            presented: None,
+            last_vblank: None,
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        match self.mode {
+            VsyncMode::Vblank { interval } | VsyncMode::Timer { interval } => interval,
+        }
+    }
+
+    /// Changes the refresh interval, such as after a mode change.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.mode = match self.mode {
+            VsyncMode::Vblank { .. } => VsyncMode::Vblank { interval },
+            VsyncMode::Timer { .. } => VsyncMode::Timer { interval },
+        };
+    }
+
+    /// Flutter is waiting to start a frame.
+    pub fn request_frame(&mut self) {
+        self.requested = true;
+    }
+
+    /// A frame was handed to the display.
+    pub fn frame_presented(&mut self, now: Instant) {
+        if let VsyncMode::Vblank { .. } = self.mode {
+            self.presented = Some(now);
+        }
+    }
+
+    /// The display finished showing the last presented frame.
+    pub fn vblank(&mut self, now: Instant) {
+        self.presented = None;
+        self.last_vblank = Some(now);
+    }
+
+    /// Returns the start and target time of the requested frame, if it may start now.
+    pub fn poll(&mut self, now: Instant) -> Option<(Instant, Instant)> {
+        if !self.requested {
+            return None;
+        }
+
+        match self.mode {
+            VsyncMode::Vblank { .. } => {
+                // Wait for the previous frame to reach the screen
+                if let Some(presented) = self.presented {
+                    if now < presented + self.interval() * MISSED_VBLANK_INTERVALS {
+                        return None;
+                    }
+                    debug!("Missed vblank, starting frame anyway");
+                    self.presented = None;
+                }
+            }
+            VsyncMode::Timer { .. } => {
+                if let Some(deadline) = self.deadline() {
+                    if now < deadline {
+                        return None;
+                    }
+                }
+                self.last_vblank = Some(now);
+            }
+        }
+
+        self.requested = false;
+        Some((now, self.next_vblank(now)))
+    }
+
+    /// When `poll` should be called next, if it depends on time rather than a vblank event.
+    pub fn deadline(&self) -> Option<Instant> {
+        if !self.requested {
+            return None;
+        }
+
+        match self.mode {
+            VsyncMode::Vblank { .. } => self
+                .presented
+                .map(|presented| presented + self.interval() * MISSED_VBLANK_INTERVALS),
+            VsyncMode::Timer { .. } => self.last_vblank.map(|last| last + self.interval()),
+        }
+    }
+
+    /// Estimates the first vblank after the given time.
+    fn next_vblank(&self, now: Instant) -> Instant {
+        let interval = self.interval();
+        match self.last_vblank {
+            Some(last) if last <= now && interval > Duration::from_nanos(0) => {
+                let periods = (now - last).as_nanos() / interval.as_nanos() + 1;
+                last + Duration::from_nanos((interval.as_nanos() * periods) as u64)
+            }
+            _ => now + interval,
+        }
+    }
+}
+
+/// Converts a `CLOCK_MONOTONIC` timestamp, as reported by DRM page flip events, to an `Instant`.
+pub(crate) fn instant_from_monotonic(time: Duration) -> Instant {
+    let now = Instant::now();
+    let mut monotonic = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut monotonic) } != 0 {
+        return now;
+    }
+
+    let monotonic = Duration::new(monotonic.tv_sec as u64, monotonic.tv_nsec as u32);
+    let age = monotonic.checked_sub(time).unwrap_or_default();
+    now.checked_sub(age).unwrap_or(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(16);
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn waits_for_a_request() {
+        let start = Instant::now();
+        let mut clock = FrameClock::new(VsyncMode::Vblank { interval: INTERVAL });
+        assert_eq!(clock.poll(start), None);
+        assert_eq!(clock.deadline(), None);
+
+        clock.request_frame();
+        assert_eq!(clock.poll(start), Some((start, start + INTERVAL)));
+        assert_eq!(clock.poll(start), None);
+    }
+
+    #[test]
+    fn starts_frames_on_vblank() {
+        let start = Instant::now();
+        let mut clock = FrameClock::new(VsyncMode::Vblank { interval: INTERVAL });
+
+        clock.frame_presented(start);
+        clock.request_frame();
+        assert_eq!(clock.poll(start + ms(1)), None);
+        assert_eq!(
+            clock.deadline(),
+            Some(start + INTERVAL * MISSED_VBLANK_INTERVALS)
+        );
+
+        // The target is the vblank after the one the frame starts from
+        clock.vblank(start + ms(10));
+        assert_eq!(clock.deadline(), None);
+        assert_eq!(
+            clock.poll(start + ms(11)),
+            Some((start + ms(11), start + ms(26)))
+        );
+    }
+
+    #[test]
+    fn falls_back_after_missed_vblanks() {
+        let start = Instant::now();
+        let mut clock = FrameClock::new(VsyncMode::Vblank { interval: INTERVAL });
+        let missed = start + INTERVAL * MISSED_VBLANK_INTERVALS;
+
+        clock.frame_presented(start);
+        clock.request_frame();
+        assert_eq!(clock.poll(missed - Duration::from_nanos(1)), None);
+        assert_eq!(clock.poll(missed), Some((missed, missed + INTERVAL)));
+
+        // Without a pending frame, the next request starts right away
+        clock.request_frame();
+        assert_eq!(
+            clock.poll(missed + ms(1)).map(|(start, _)| start),
+            Some(missed + ms(1))
+        );
+    }
+
+    #[test]
+    fn paces_frames_with_the_timer() {
+        let start = Instant::now();
+        let mut clock = FrameClock::new(VsyncMode::Timer { interval: INTERVAL });
+
+        // Presenting doesn't hold back timer frames
+        clock.request_frame();
+        clock.frame_presented(start);
+        assert_eq!(clock.poll(start), Some((start, start + INTERVAL)));
+
+        clock.request_frame();
+        assert_eq!(clock.deadline(), Some(start + INTERVAL));
+        assert_eq!(clock.poll(start + ms(5)), None);
+        assert_eq!(
+            clock.poll(start + INTERVAL),
+            Some((start + INTERVAL, start + INTERVAL * 2))
+        );
+    }
+
+    #[test]
+    fn targets_the_next_vblank_after_late_starts() {
+        let start = Instant::now();
+        let mut clock = FrameClock::new(VsyncMode::Vblank { interval: INTERVAL });
+
+        clock.vblank(start);
+        clock.request_frame();
+        assert_eq!(
+            clock.poll(start + ms(40)),
+            Some((start + ms(40), start + ms(48)))
+        );
+
+        clock.set_interval(ms(10));
+        clock.request_frame();
+        assert_eq!(
+            clock.poll(start + ms(40)),
+            Some((start + ms(40), start + ms(50)))
+        );
+    }
+}
@@ -1,40 +1,201 @@
-use crate::egl_util::WrappedDisplay;
-use log::debug;
-use smithay::backend::graphics::gl::GLGraphicsBackend;
-use smithay::backend::winit;
-use std::sync::Arc;
-
-use crate::output::{FlutterEngineOptions, FlutterOutput, FlutterOutputBackend};
-use crate::{EngineWeakCollection, FlutterDrmManager};
-use smithay::backend::input::InputBackend;
-use smithay::backend::winit::{
-    WinitEventsHandler, WinitGraphicsBackend, WinitInputBackend, WinitInputError,
+use crate::egl_util::{NativePlatform, WrappedContext, WrappedDisplay, WrappedSurface};
+use crate::error::Error;
+use log::{debug, error};
+use smithay::backend::egl::{ffi, native};
+use smithay::backend::graphics::SwapBuffersError;
+use std::collections::HashMap;
+use std::os::raw::c_ulong;
+use std::sync::{mpsc, Arc};
+
+use crate::output::{
+    FlutterEngineOptions, FlutterOutput, FlutterOutputBackend, ModeControl, Transform, VsyncMode,
 };
+use crate::{EngineWeakCollection, OutputLayout};
+use ::winit::event::{Event, WindowEvent};
+use ::winit::event_loop::{ControlFlow, EventLoop, EventLoopProxy, EventLoopWindowTarget};
+use ::winit::platform::desktop::EventLoopExtDesktop;
+use ::winit::platform::unix::{EventLoopExtUnix, WindowExtUnix};
+use ::winit::window::{Window as WinitWindow, WindowId};
+use wayland_egl as wegl;
 
 pub use ::winit::{dpi::LogicalSize, dpi::PhysicalSize, window::WindowBuilder};
 
-use crate::input::keyboard::KeyboardManager;
+use crate::input::keyboard::{KeyboardConfig, KeyboardManager};
 use crate::input::winit::WinitInputHandler;
-use flutter_engine::FlutterEngine;
 use parking_lot::Mutex;
-use std::thread;
-use std::time::Duration;
+use std::thread::{self, JoinHandle};
+
+/// A `wl_egl_window` for EGL to render to a Wayland surface through.
+struct WaylandEglSurface(wegl::WlEglSurface);
+
+unsafe impl native::NativeSurface for WaylandEglSurface {
+    fn ptr(&self) -> ffi::NativeWindowType {
+        self.0.ptr() as *const _
+    }
+}
+
+/// An X11 window, which EGL renders to directly.
+struct XlibWindow(c_ulong);
+
+unsafe impl native::NativeSurface for XlibWindow {
+    fn ptr(&self) -> ffi::NativeWindowType {
+        self.0 as *const _
+    }
+}
+
+/// A window's EGL surface, for whichever display server it is on.
+enum NativeWindowSurface {
+    Wayland(WrappedSurface<WaylandEglSurface>),
+    X11(WrappedSurface<XlibWindow>),
+}
+
+/// A window and the EGL context and surface rendering to it.
+struct WindowSurface {
+    // Dropped in this order, as the surface renders to the window
+    surface: NativeWindowSurface,
+    context: Option<WrappedContext>,
+    window: WinitWindow,
+}
+
+impl WindowSurface {
+    fn new(window: WinitWindow) -> Result<Self, Error> {
+        unsafe {
+            if let (Some(display), Some(surface)) =
+                (window.wayland_display(), window.wayland_surface())
+            {
+                let display = WrappedDisplay::for_native(NativePlatform::Wayland, display)?;
+                let context = WrappedContext::create_window_context(&display)?;
+                let size = window.inner_size();
+                let native = wegl::WlEglSurface::new_from_raw(
+                    surface as *mut _,
+                    size.width as i32,
+                    size.height as i32,
+                );
+                let surface = context.create_surface(WaylandEglSurface(native))?;
+                Ok(Self::with_surface(
+                    NativeWindowSurface::Wayland(surface),
+                    context,
+                    window,
+                ))
+            } else if let (Some(display), Some(xlib_window)) =
+                (window.xlib_display(), window.xlib_window())
+            {
+                let display = WrappedDisplay::for_native(NativePlatform::X11, display)?;
+                let context = WrappedContext::create_window_context(&display)?;
+                let surface = context.create_surface(XlibWindow(xlib_window))?;
+                Ok(Self::with_surface(
+                    NativeWindowSurface::X11(surface),
+                    context,
+                    window,
+                ))
+            } else {
+                Err(Error::Window("unsupported display server".to_string()))
+            }
+        }
+    }
+
+    fn with_surface(
+        surface: NativeWindowSurface,
+        context: WrappedContext,
+        window: WinitWindow,
+    ) -> Self {
+        Self {
+            surface,
+            context: Some(context),
+            window,
+        }
+    }
+
+    fn window(&self) -> &WinitWindow {
+        &self.window
+    }
+
+    fn swap_buffers(&self) -> Result<(), SwapBuffersError> {
+        match &self.surface {
+            NativeWindowSurface::Wayland(surface) => surface.swap_buffers(),
+            NativeWindowSurface::X11(surface) => surface.swap_buffers(),
+        }
+    }
+
+    unsafe fn make_current(&self) -> Result<(), SwapBuffersError> {
+        match &self.surface {
+            NativeWindowSurface::Wayland(surface) => surface.make_current(),
+            NativeWindowSurface::X11(surface) => surface.make_current(),
+        }
+    }
+
+    /// Resizes the surface to the window's size, which X11 does by itself.
+    fn resize(&self, (width, height): (u32, u32)) {
+        if let NativeWindowSurface::Wayland(surface) = &self.surface {
+            surface.0.resize(width as i32, height as i32, 0, 0);
+        }
+    }
+}
+
+impl Drop for WindowSurface {
+    fn drop(&mut self) {
+        // The render thread has shut down by now, so nothing has the context current
+        if let Some(context) = self.context.take() {
+            unsafe { context.destroy() };
+        }
+    }
+}
 
 pub struct WinitOutputBackend {
-    graphics: WinitGraphicsBackend,
+    surface: WindowSurface,
+    /// Size of the window in pixels, as last reported by winit.
+    size: Arc<Mutex<(u32, u32)>>,
+    /// Size of the surface, which only the render thread may change.
+    surface_size: Mutex<(u32, u32)>,
 }
 
 impl FlutterOutputBackend for WinitOutputBackend {
     fn swap_buffers(&self) -> Result<(), ()> {
-        self.graphics.swap_buffers().map_err(|_| ())
+        self.surface.swap_buffers().map_err(|_| ())
     }
 
     fn make_current(&self) -> Result<(), ()> {
-        unsafe { self.graphics.make_current().map_err(|_| ()) }
+        let size = *self.size.lock();
+        let mut surface_size = self.surface_size.lock();
+        if *surface_size != size {
+            self.surface.resize(size);
+            *surface_size = size;
+        }
+
+        unsafe { self.surface.make_current().map_err(|_| ()) }
     }
 
     fn get_framebuffer_dimensions(&self) -> (u32, u32) {
-        self.graphics.get_framebuffer_dimensions()
+        *self.size.lock()
+    }
+
+    fn pixel_ratio(&self) -> f64 {
+        self.surface.window().scale_factor()
+    }
+
+    fn mode_control(&self) -> Option<Arc<dyn ModeControl>> {
+        // The window size is controlled by the window manager
+        None
+    }
+
+    fn vsync_mode(&self) -> VsyncMode {
+        // Winit doesn't tell which mode the monitor is in, so assume the fastest at its size
+        let monitor = self.surface.window().current_monitor();
+        let size = monitor.size();
+        let refresh = monitor
+            .video_modes()
+            .filter(|mode| mode.size() == size)
+            .map(|mode| mode.refresh_rate() as u32)
+            .max()
+            .unwrap_or(0);
+
+        VsyncMode::Timer {
+            interval: VsyncMode::interval_from_refresh(refresh),
+        }
+    }
+
+    fn apply_transform(&self, _transform: Transform) -> bool {
+        false
     }
 }
 
@@ -42,82 +203,302 @@ unsafe impl Sync for WinitOutputBackend {}
 
 unsafe impl Send for WinitOutputBackend {}
 
-struct InputWrapper(WinitInputBackend);
+/// Work for the event loop thread, which owns every window.
+enum WinitRequest {
+    CreateWindow(WindowRequest),
+    Exit,
+}
+
+struct WindowRequest {
+    builder: WindowBuilder,
+    options: FlutterEngineOptions,
+    reply: mpsc::Sender<Result<FlutterOutput, Error>>,
+}
 
-unsafe impl Send for InputWrapper {}
+// The builder is only used on the event loop thread
+unsafe impl Send for WindowRequest {}
 
+/// Runs flutter engines in windows of a Wayland or X11 desktop.
+///
+/// Winit's event loop runs on its own "winit-events" thread, so windows are handled without
+/// `FlutterDrmManager::run`. The thread closes every window and exits once the manager is dropped.
 pub struct WinitOutputManager {
     engines: EngineWeakCollection,
     keyboard: Arc<Mutex<KeyboardManager>>,
+    proxy: Mutex<EventLoopProxy<WinitRequest>>,
+    thread: Option<JoinHandle<()>>,
 }
 
 impl WinitOutputManager {
-    pub fn new(manager: &FlutterDrmManager) -> Self {
+    pub fn new() -> Result<Self, Error> {
         let engines = EngineWeakCollection::new();
+        let keyboard = Arc::new(Mutex::new(KeyboardManager::new(engines.clone())));
 
-        Self {
-            keyboard: Arc::new(Mutex::new(KeyboardManager::new(engines.clone()))),
+        // All windows share one event loop, as some platforms allow no more
+        let (send, recv) = mpsc::channel();
+        let router = WindowRouter {
+            windows: HashMap::new(),
+            keyboard: keyboard.clone(),
+            reaper: Reaper::new()?,
+        };
+        let thread = thread::Builder::new()
+            .name("winit-events".to_string())
+            .spawn(move || {
+                let mut event_loop = EventLoop::new_any_thread();
+                let _ = send.send(event_loop.create_proxy());
+                router.run(&mut event_loop);
+                debug!("Winit event loop stopped");
+            })
+            .map_err(|err| Error::EventLoop(err.to_string()))?;
+
+        let proxy = recv
+            .recv()
+            .map_err(|_| Error::EventLoop("the event loop thread panicked".to_string()))?;
+
+        Ok(Self {
             engines,
-        }
+            keyboard,
+            proxy: Mutex::new(proxy),
+            thread: Some(thread),
+        })
+    }
+
+    /// Changes the keyboard layout and repeat settings, recompiling the keymap immediately.
+    pub fn set_keyboard_config(&self, config: KeyboardConfig) -> Result<(), Error> {
+        self.keyboard.lock().set_config(config)
     }
 
     pub fn create_window(
         &self,
         builder: WindowBuilder,
         options: FlutterEngineOptions,
-    ) -> FlutterOutput {
+    ) -> Result<FlutterOutput, Error> {
         debug!("Creating window");
-        let (graphics, mut input) = winit::init_from_builder(builder, None).unwrap();
 
-        // Winit leaves the window bound
-        unsafe {
-            let display = WrappedDisplay::get_current();
-            display.clear_current();
+        let (reply, recv) = mpsc::channel();
+        self.proxy
+            .lock()
+            .send_event(WinitRequest::CreateWindow(WindowRequest {
+                builder,
+                options,
+                reply,
+            }))
+            .map_err(|_| Error::Window("event loop stopped".to_string()))?;
+
+        let output = recv.recv().unwrap_or(Err(Error::OutputThread))?;
+        self.engines.add(output.engine().downgrade());
+        Ok(output)
+    }
+}
+
+impl Drop for WinitOutputManager {
+    /// Shuts down every window's engine and closes the windows.
+    fn drop(&mut self) {
+        let _ = self.proxy.lock().send_event(WinitRequest::Exit);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Winit event thread panicked");
+            }
         }
+    }
+}
 
-        // Create output
-        let backend = WinitOutputBackend { graphics };
-        let output = FlutterOutput::new(backend, options, self.keyboard.clone());
-        let engine = output.engine();
-        self.engines.add(engine.downgrade());
-
-        // Configure input
-        input.set_events_handler(WinitOutputEventsHandler { engine });
-        input.set_handler(WinitInputHandler::new(self.keyboard.clone()));
-
-        let input = InputWrapper(input);
-        thread::spawn(move || {
-            let mut input = input.0;
-            let mut running = true;
-
-            while running {
-                match input.dispatch_new_events() {
-                    Ok(_) => {}
-                    Err(WinitInputError::WindowClosed) => {
-                        running = false;
-                        // TODO: Signal
+/// Waits for the outputs of closed windows to shut down. Shutting down takes a while, during
+/// which the event loop thread keeps handling the other windows.
+struct Reaper {
+    send: Option<mpsc::Sender<FlutterOutput>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reaper {
+    fn new() -> Result<Self, Error> {
+        let (send, recv) = mpsc::channel::<FlutterOutput>();
+        let thread = thread::Builder::new()
+            .name("winit-reaper".to_string())
+            .spawn(move || {
+                for output in recv {
+                    output.shutdown();
+                }
+            })
+            .map_err(|err| Error::EventLoop(err.to_string()))?;
+
+        Ok(Self {
+            send: Some(send),
+            thread: Some(thread),
+        })
+    }
+
+    fn reap(&self, output: FlutterOutput) {
+        output.stop();
+        let output = match self.send.as_ref() {
+            Some(send) => match send.send(output) {
+                Ok(()) => return,
+                Err(mpsc::SendError(output)) => output,
+            },
+            None => output,
+        };
+        // Without the reaper thread, the event loop thread has to wait itself
+        output.shutdown();
+    }
+
+    /// Waits for every output handed to `reap`.
+    fn finish(&mut self) {
+        self.send.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Winit reaper thread panicked");
+            }
+        }
+    }
+}
+
+/// Routes the events of the shared event loop to their windows.
+struct WindowRouter {
+    windows: HashMap<WindowId, WindowHandler>,
+    keyboard: Arc<Mutex<KeyboardManager>>,
+    reaper: Reaper,
+}
+
+impl WindowRouter {
+    fn run(mut self, event_loop: &mut EventLoop<WinitRequest>) {
+        event_loop.run_return(move |event, target, control_flow| {
+            *control_flow = ControlFlow::Wait;
+            match event {
+                Event::UserEvent(WinitRequest::CreateWindow(request)) => {
+                    let result = self.create_window(target, request.builder, request.options);
+                    let _ = request.reply.send(result);
+                }
+                Event::UserEvent(WinitRequest::Exit) => {
+                    for (_, handler) in self.windows.drain() {
+                        self.reaper.reap(handler.output);
                     }
+                    self.reaper.finish();
+                    *control_flow = ControlFlow::Exit;
                 }
-                thread::sleep(Duration::from_millis(16));
+                Event::WindowEvent { window_id, event } => self.window_event(window_id, &event),
+                _ => {}
             }
         });
+    }
 
-        output
+    fn create_window(
+        &mut self,
+        target: &EventLoopWindowTarget<WinitRequest>,
+        builder: WindowBuilder,
+        options: FlutterEngineOptions,
+    ) -> Result<FlutterOutput, Error> {
+        let handler = WindowHandler::new(target, builder, options, self.keyboard.clone())?;
+        let output = handler.output.clone();
+        self.windows.insert(handler.id, handler);
+        Ok(output)
+    }
+
+    fn window_event(&mut self, id: WindowId, event: &WindowEvent) {
+        let handler = match self.windows.get_mut(&id) {
+            Some(handler) => handler,
+            None => return,
+        };
+
+        // Keys go to the window the window manager focused
+        let engine = handler.output.engine().downgrade();
+        match event {
+            WindowEvent::Focused(true) => self.keyboard.lock().set_focus(engine.clone()),
+            WindowEvent::Focused(false) => self.keyboard.lock().clear_focus(engine.clone()),
+            _ => {}
+        }
+
+        if !handler.handle_event(event) {
+            debug!("Window closed");
+            self.keyboard.lock().clear_focus(engine);
+            if let Some(handler) = self.windows.remove(&id) {
+                self.reaper.reap(handler.output);
+            }
+        }
     }
 }
 
-struct WinitOutputEventsHandler {
-    engine: FlutterEngine,
+/// Handles the events of a window, on the event loop thread.
+struct WindowHandler {
+    id: WindowId,
+    output: FlutterOutput,
+    size: Arc<Mutex<(u32, u32)>>,
+    scale: f64,
+    input: WinitInputHandler,
 }
 
-impl WinitEventsHandler for WinitOutputEventsHandler {
-    fn resized(&mut self, size: (f64, f64), scale: f64) {
-        self.engine.run_on_platform_thread(move |engine| {
-            engine.send_window_metrics_event(size.0 as usize, size.1 as usize, 1.0);
-        });
+impl WindowHandler {
+    fn new(
+        target: &EventLoopWindowTarget<WinitRequest>,
+        builder: WindowBuilder,
+        options: FlutterEngineOptions,
+        keyboard: Arc<Mutex<KeyboardManager>>,
+    ) -> Result<Self, Error> {
+        let window = builder
+            .build(target)
+            .map_err(|err| Error::Window(err.to_string()))?;
+        let id = window.id();
+        let scale = window.scale_factor();
+        let size = window.inner_size();
+        let size = (size.width, size.height);
+
+        let surface = WindowSurface::new(window)?;
+
+        // Creating the context may leave it bound
+        unsafe {
+            if let Ok(display) = WrappedDisplay::get_current() {
+                display.clear_current();
+            }
+        }
+
+        // Create output
+        let shared_size = Arc::new(Mutex::new(size));
+        let backend = WinitOutputBackend {
+            surface,
+            size: shared_size.clone(),
+            surface_size: Mutex::new(size),
+        };
+        let output = FlutterOutput::new(backend, options, keyboard.clone())?;
+
+        // Each window has its own coordinate space
+        let layout = OutputLayout::new();
+        output.add_to_layout(&layout);
+
+        Ok(Self {
+            id,
+            output,
+            size: shared_size,
+            scale,
+            input: WinitInputHandler::new(keyboard, layout, scale),
+        })
     }
 
-    fn focus_changed(&mut self, focused: bool) {}
+    /// Handles a window event, returning `false` once the window is gone.
+    fn handle_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::Resized(size) => self.resized(*size),
+            WindowEvent::ScaleFactorChanged {
+                scale_factor,
+                new_inner_size,
+            } => {
+                self.scale = *scale_factor;
+                self.resized(**new_inner_size);
+            }
+            WindowEvent::CloseRequested => {
+                if self.output.request_close() {
+                    return false;
+                }
+            }
+            WindowEvent::Destroyed => return false,
+            _ => {}
+        }
 
-    fn refresh(&mut self) {}
+        self.input.handle_event(event);
+        self.output.is_running()
+    }
+
+    fn resized(&mut self, size: PhysicalSize<u32>) {
+        *self.size.lock() = (size.width, size.height);
+        self.output
+            .update_metrics(size.width, size.height, self.scale);
+    }
 }